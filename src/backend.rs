@@ -0,0 +1,653 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    BtrfsCommand, btrfs_allocation, btrfs_exclusive_bytes, btrfs_generations, btrfs_snapshots,
+    btrfs_subvolume_info, create_btrfs_snapshot, delete_btrfs_snapshots, ioctl,
+    sync_btrfs_deletions,
+};
+use jiff::{Span, Timestamp};
+#[cfg(test)]
+use std::{collections::BTreeMap, sync::Mutex};
+use std::{
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+/// What btrfs records about a subvolume.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubvolumeInfo {
+    pub path: PathBuf,
+    pub id: u64,
+    /// The last transaction that changed the subvolume.
+    pub generation: u64,
+    /// The transaction the subvolume was created in.
+    pub created_generation: u64,
+    pub uuid: Option<String>,
+    /// The UUID of the subvolume this is a snapshot of.
+    pub parent_uuid: Option<String>,
+    /// The UUID of the subvolume this was received from, if it was sent from another filesystem.
+    pub received_uuid: Option<String>,
+    pub created: Option<Timestamp>,
+}
+
+/// The btrfs operations snapshots are taken, pruned and replicated with, so everything around
+/// them can run against a filesystem held in memory in tests.
+pub trait SnapshotBackend: Send + Sync {
+    /// Snapshots the subvolume at `source` to `destination`, read-only if `readonly`.
+    fn create(&self, source: &Path, destination: &Path, readonly: bool) -> Result<(), String>;
+
+    /// Deletes every snapshot in `snapshot_paths` at once, returning the outcome for each in the
+    /// same order.
+    fn delete(&self, snapshot_paths: &[&Path]) -> Vec<Result<(), String>>;
+
+    /// Returns every subvolume directly in `dir`, skipping plain directories.
+    fn list(&self, dir: &Path) -> io::Result<Vec<SubvolumeInfo>>;
+
+    /// Whether `path` is the top directory of a subvolume.
+    fn is_subvolume(&self, path: &Path) -> Result<bool, String>;
+
+    /// Whether the subvolume at `path` is a snapshot rather than a plain subvolume.
+    fn is_snapshot(&self, path: &Path) -> Result<bool, String>;
+
+    /// Returns the generation of the subvolume at `path`, the last transaction that changed it,
+    /// and the generation it was created in.
+    fn generations(&self, path: &Path) -> Result<(u64, u64), String>;
+
+    /// Returns the exclusive bytes of the subvolume at `path`, the data no other subvolume shares.
+    fn exclusive_bytes(&self, path: &Path) -> Result<u64, String>;
+
+    /// Returns the unallocated bytes of the filesystem holding `path` and the free bytes in its
+    /// allocated metadata chunks.
+    fn allocation(&self, path: &Path) -> Result<(u64, u64), String>;
+
+    /// Waits up to `timeout` for the subvolumes deleted from the filesystem holding `dir` to be
+    /// cleaned up, returning whether they were in time.
+    fn sync(&self, dir: &Path, timeout: Span) -> Result<bool, String>;
+
+    /// Writes the send stream of the read-only snapshot at `snapshot_path` to `stream`,
+    /// incremental from `parent_path` if given. Extents stored compressed are sent without
+    /// decompressing them if `compressed_data`.
+    fn send(
+        &self,
+        snapshot_path: &Path,
+        parent_path: Option<&Path>,
+        compressed_data: bool,
+        stream: &mut dyn Write,
+    ) -> Result<(), String>;
+
+    /// Receives the send stream read from `stream` into `destination_dir`.
+    fn receive(&self, destination_dir: &Path, stream: &mut dyn Read) -> Result<(), String>;
+}
+
+impl SnapshotBackend for BtrfsCommand {
+    fn create(&self, source: &Path, destination: &Path, readonly: bool) -> Result<(), String> {
+        create_btrfs_snapshot(self, source, destination, readonly)
+    }
+
+    fn delete(&self, snapshot_paths: &[&Path]) -> Vec<Result<(), String>> {
+        delete_btrfs_snapshots(self, snapshot_paths)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<SubvolumeInfo>> {
+        btrfs_snapshots(self, dir)
+    }
+
+    fn is_subvolume(&self, path: &Path) -> Result<bool, String> {
+        if self.native() {
+            return match ioctl::is_subvolume(path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                x => x.map_err(|e| format!("Error reading {}. {}", path.to_string_lossy(), e)),
+            };
+        }
+
+        // Only a subvolume's top directory can be shown.
+        Ok(btrfs_subvolume_info(self, path).is_ok())
+    }
+
+    fn is_snapshot(&self, path: &Path) -> Result<bool, String> {
+        let info = btrfs_subvolume_info(self, path)?;
+
+        Ok(info.parent_uuid.is_some() || info.received_uuid.is_some())
+    }
+
+    fn generations(&self, path: &Path) -> Result<(u64, u64), String> {
+        btrfs_generations(self, path)
+    }
+
+    fn exclusive_bytes(&self, path: &Path) -> Result<u64, String> {
+        btrfs_exclusive_bytes(self, path)
+    }
+
+    fn allocation(&self, path: &Path) -> Result<(u64, u64), String> {
+        btrfs_allocation(self, path)
+    }
+
+    fn sync(&self, dir: &Path, timeout: Span) -> Result<bool, String> {
+        sync_btrfs_deletions(self, dir, timeout)
+    }
+
+    fn send(
+        &self,
+        snapshot_path: &Path,
+        parent_path: Option<&Path>,
+        compressed_data: bool,
+        stream: &mut dyn Write,
+    ) -> Result<(), String> {
+        let mut command = self.command();
+        command.arg("send").arg("-q");
+        if compressed_data {
+            command.arg("--compressed-data");
+        }
+        if let Some(x) = parent_path {
+            command.arg("-p").arg(x);
+        }
+        let mut send = command
+            .arg(snapshot_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Error running btrfs send. {}", e))?;
+
+        let copied = io::copy(
+            &mut send
+                .stdout
+                .take()
+                .expect("btrfs send stdout should be piped."),
+            stream,
+        );
+        let send = send
+            .wait_with_output()
+            .map_err(|e| format!("Error running btrfs send. {}", e))?;
+
+        if !send.status.success() {
+            return Err(format!(
+                "btrfs send failed. Output: {}",
+                String::from_utf8_lossy(&send.stderr).trim_end()
+            ));
+        }
+        copied
+            .map(|_| ())
+            .map_err(|e| format!("Error sending {}. {}", snapshot_path.to_string_lossy(), e))
+    }
+
+    fn receive(&self, destination_dir: &Path, stream: &mut dyn Read) -> Result<(), String> {
+        let mut receive = self
+            .command()
+            .arg("receive")
+            .arg(destination_dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Error running btrfs receive. {}", e))?;
+
+        // btrfs receive sees the end of the stream once its stdin is dropped.
+        let copied = io::copy(
+            stream,
+            &mut receive
+                .stdin
+                .take()
+                .expect("btrfs receive stdin should be piped."),
+        );
+        let receive = receive
+            .wait_with_output()
+            .map_err(|e| format!("Error running btrfs receive. {}", e))?;
+
+        if !receive.status.success() {
+            return Err(format!(
+                "btrfs receive failed. Output: {}",
+                String::from_utf8_lossy(&receive.stderr).trim_end()
+            ));
+        }
+        copied.map(|_| ()).map_err(|e| {
+            format!(
+                "Error receiving into {}. {}",
+                destination_dir.to_string_lossy(),
+                e
+            )
+        })
+    }
+}
+
+/// Subvolumes held in memory, for testing everything built on the backend without root or a
+/// btrfs filesystem.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockBackend {
+    filesystem: Mutex<MockFilesystem>,
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct MockFilesystem {
+    subvolumes: BTreeMap<PathBuf, MockSubvolume>,
+    /// The last transaction committed, each change to a subvolume committing the next.
+    generation: u64,
+    /// The unallocated and free metadata bytes, unlimited unless set.
+    allocation: Option<(u64, u64)>,
+}
+
+#[cfg(test)]
+#[derive(Clone, Copy)]
+struct MockSubvolume {
+    is_snapshot: bool,
+    generation: u64,
+    created_generation: u64,
+    exclusive_bytes: u64,
+}
+
+#[cfg(test)]
+impl MockFilesystem {
+    fn insert(&mut self, path: &Path, is_snapshot: bool) {
+        self.generation += 1;
+        self.subvolumes.insert(
+            path.to_path_buf(),
+            MockSubvolume {
+                is_snapshot,
+                generation: self.generation,
+                created_generation: self.generation,
+                exclusive_bytes: 0,
+            },
+        );
+    }
+
+    fn get(&self, path: &Path) -> Result<&MockSubvolume, String> {
+        self.subvolumes
+            .get(path)
+            .ok_or(format!("No subvolume at {}.", path.to_string_lossy()))
+    }
+}
+
+#[cfg(test)]
+impl MockBackend {
+    /// Adds a plain subvolume at `path`.
+    pub fn add_subvolume(&self, path: &Path) {
+        self.lock().insert(path, false);
+    }
+
+    /// Writes `bytes` to the subvolume at `path` that only it holds, committing a transaction.
+    pub fn write(&self, path: &Path, bytes: u64) {
+        let mut filesystem = self.lock();
+        filesystem.generation += 1;
+        let generation = filesystem.generation;
+        let subvolume = filesystem
+            .subvolumes
+            .get_mut(path)
+            .expect("Only subvolumes in the mock should be written to.");
+        subvolume.generation = generation;
+        subvolume.exclusive_bytes += bytes;
+    }
+
+    /// Sets the unallocated and free metadata bytes of the filesystem.
+    pub fn set_allocation(&self, unallocated: u64, metadata_free: u64) {
+        self.lock().allocation = Some((unallocated, metadata_free));
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockFilesystem> {
+        self.filesystem
+            .lock()
+            .expect("Mock filesystem should never be poisoned.")
+    }
+}
+
+/// A path in the temp directory unique to this test run and `name`, so tests running in parallel
+/// never share a file.
+#[cfg(test)]
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("snapshotter-{}-{}", std::process::id(), name))
+}
+
+#[cfg(test)]
+impl SnapshotBackend for MockBackend {
+    fn create(&self, source: &Path, destination: &Path, _readonly: bool) -> Result<(), String> {
+        let mut filesystem = self.lock();
+        filesystem.get(source)?;
+        if filesystem.subvolumes.contains_key(destination) {
+            return Err(format!("{} already exists.", destination.to_string_lossy()));
+        }
+        filesystem.insert(destination, true);
+
+        Ok(())
+    }
+
+    fn delete(&self, snapshot_paths: &[&Path]) -> Vec<Result<(), String>> {
+        let mut filesystem = self.lock();
+
+        snapshot_paths
+            .iter()
+            .map(|x| {
+                filesystem
+                    .subvolumes
+                    .remove(*x)
+                    .map(|_| ())
+                    .ok_or(format!("No subvolume at {}.", x.to_string_lossy()))
+            })
+            .collect()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<SubvolumeInfo>> {
+        Ok(self
+            .lock()
+            .subvolumes
+            .iter()
+            .filter(|(path, _)| path.parent() == Some(dir))
+            .map(|(path, x)| SubvolumeInfo {
+                path: path.clone(),
+                generation: x.generation,
+                created_generation: x.created_generation,
+                parent_uuid: x.is_snapshot.then(|| "mock".to_string()),
+                ..SubvolumeInfo::default()
+            })
+            .collect())
+    }
+
+    fn is_subvolume(&self, path: &Path) -> Result<bool, String> {
+        Ok(self.lock().subvolumes.contains_key(path))
+    }
+
+    fn is_snapshot(&self, path: &Path) -> Result<bool, String> {
+        self.lock().get(path).map(|x| x.is_snapshot)
+    }
+
+    fn generations(&self, path: &Path) -> Result<(u64, u64), String> {
+        self.lock()
+            .get(path)
+            .map(|x| (x.generation, x.created_generation))
+    }
+
+    fn exclusive_bytes(&self, path: &Path) -> Result<u64, String> {
+        self.lock().get(path).map(|x| x.exclusive_bytes)
+    }
+
+    fn allocation(&self, _path: &Path) -> Result<(u64, u64), String> {
+        Ok(self.lock().allocation.unwrap_or((u64::MAX, u64::MAX)))
+    }
+
+    fn sync(&self, _dir: &Path, _timeout: Span) -> Result<bool, String> {
+        Ok(true)
+    }
+
+    fn send(
+        &self,
+        snapshot_path: &Path,
+        _parent_path: Option<&Path>,
+        _compressed_data: bool,
+        stream: &mut dyn Write,
+    ) -> Result<(), String> {
+        self.is_snapshot(snapshot_path)?;
+
+        // The stream is only the name the snapshot is received under.
+        stream
+            .write_all(snapshot_path.as_os_str().as_encoded_bytes())
+            .map_err(|e| e.to_string())
+    }
+
+    fn receive(&self, destination_dir: &Path, stream: &mut dyn Read) -> Result<(), String> {
+        let mut sent = String::new();
+        stream
+            .read_to_string(&mut sent)
+            .map_err(|e| e.to_string())?;
+        let name = Path::new(&sent)
+            .file_name()
+            .ok_or("Empty send stream.".to_string())?;
+        self.lock().insert(&destination_dir.join(name), true);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Config, clones, metrics::RetentionMetrics, origin::Origin, prune_snapshots, replication,
+        retention::RetentionPolicy, state, take_snapshot, truncated_now,
+    };
+    use jiff::{ToSpan, Zoned};
+    use std::sync::Arc;
+
+    const SUBVOLUME: &str = "/mock/@";
+    const SNAPSHOTS: &str = "/mock/.snapshots";
+
+    /// The default config with its subvolume at SUBVOLUME, snapshotted into SNAPSHOTS on
+    /// `backend`, keeping its state in a file of the test `name`'s own.
+    fn config(backend: &Arc<MockBackend>, name: &str) -> Config {
+        let state_path = temp_path(&format!("{}-state.toml", name));
+        let _ = std::fs::remove_file(&state_path);
+        let mut config = Config {
+            state_path,
+            ..Config::default()
+        };
+        let subvolume = &mut config.subvolumes[0];
+        subvolume.path = PathBuf::from(SUBVOLUME);
+        subvolume.snapshot_path = PathBuf::from(SNAPSHOTS);
+        subvolume.backend = backend.clone();
+        backend.add_subvolume(&subvolume.path);
+
+        config
+    }
+
+    /// Takes a snapshot for each of `times`, as the schedule would.
+    fn take_snapshots(config: &Config, times: &[Zoned]) {
+        for time in times {
+            take_snapshot(config, &config.subvolumes[0], time)
+                .expect("Snapshotting the mock should succeed.");
+        }
+    }
+
+    fn paths(backend: &MockBackend, dir: &str) -> Vec<PathBuf> {
+        backend
+            .list(Path::new(dir))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|x| x.path)
+            .collect()
+    }
+
+    fn hours_ago(hours: i64) -> Vec<Zoned> {
+        let now = truncated_now();
+
+        (0..hours)
+            .rev()
+            .map(|x| now.saturating_sub(x.hours()))
+            .collect()
+    }
+
+    #[test]
+    fn prunes_down_to_the_retention_limits() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "prunes-down-to-the-retention-limits");
+        config.retention = RetentionPolicy {
+            hourly_limit: 12,
+            ..RetentionPolicy::default()
+        };
+        let times = hours_ago(30);
+        take_snapshots(&config, &times);
+        let before = paths(&backend, SNAPSHOTS);
+        assert_eq!(before.len(), 30);
+
+        prune_snapshots(
+            &config,
+            &config.subvolumes[0],
+            &mut RetentionMetrics::default(),
+        );
+
+        // Names sort by time, so the newest twelve are the last.
+        let after = paths(&backend, SNAPSHOTS);
+        assert_eq!(after, before[18..]);
+    }
+
+    #[test]
+    fn skips_snapshots_within_min_interval() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "skips-snapshots-within-min-interval");
+        config.min_interval = Some(1.hour());
+        let first = truncated_now().saturating_sub(2.hours());
+        take_snapshots(
+            &config,
+            &[
+                first.clone(),
+                first.saturating_add(30.minutes()),
+                first.saturating_add(1.hour()),
+            ],
+        );
+
+        assert_eq!(paths(&backend, SNAPSHOTS).len(), 2);
+    }
+
+    #[test]
+    fn never_prunes_plain_subvolumes() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "never-prunes-plain-subvolumes");
+        config.retention = RetentionPolicy {
+            hourly_limit: 1,
+            ..RetentionPolicy::default()
+        };
+        let times = hours_ago(3);
+        take_snapshots(&config, &times[1..]);
+        // Named just like a snapshot, but created by hand.
+        let impostor = crate::snapshot_path(&config, &config.subvolumes[0], &times[0]);
+        backend.add_subvolume(&impostor);
+
+        prune_snapshots(
+            &config,
+            &config.subvolumes[0],
+            &mut RetentionMetrics::default(),
+        );
+
+        let after = paths(&backend, SNAPSHOTS);
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[0], impostor);
+    }
+
+    #[test]
+    fn refuses_to_delete_immutable_snapshots() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "refuses-to-delete-immutable-snapshots");
+        config.retention.immutable_for = Some(90.minutes());
+        take_snapshots(&config, &hours_ago(3));
+        let snapshots = crate::matching_snapshots(&config, &config.subvolumes[0])
+            .expect("Listing the mock should succeed.");
+        let all: Vec<&crate::Snapshot> = snapshots.iter().collect();
+
+        let results = crate::delete_snapshots(&config, &*backend, &all);
+
+        assert!(results[0].is_ok());
+        assert!(results[1..].iter().all(Result::is_err));
+        assert_eq!(
+            paths(&backend, SNAPSHOTS),
+            [
+                snapshots[1].snapshot_path.clone(),
+                snapshots[2].snapshot_path.clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn spaces_on_demand_snapshots_by_min_interval() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "spaces-on-demand-snapshots-by-min-interval");
+        config.min_interval = Some(1.hour());
+
+        crate::take_origin_snapshot(&config, &config.subvolumes[0], Origin::Manual)
+            .expect("Snapshotting the mock should succeed.");
+        let second = crate::take_origin_snapshot(&config, &config.subvolumes[0], Origin::Manual);
+
+        assert!(second.is_err_and(|x| x.contains("less than")));
+        assert_eq!(paths(&backend, SNAPSHOTS).len(), 1);
+    }
+
+    #[test]
+    fn deletes_rollback_clones_after_clone_max_age() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "deletes-rollback-clones-after-clone-max-age");
+        config.clone_max_age = Some(1.day());
+        let (old, new) = (
+            Path::new("/mock/@.before-rollback"),
+            Path::new("/mock/.snapshots/@.rollback"),
+        );
+        let now = Zoned::now();
+        for (clone, time) in [(old, now.saturating_sub(2.days())), (new, now)] {
+            backend.add_subvolume(clone);
+            state::record_clone(&config, &config.subvolumes[0].name, clone, Some(&time));
+        }
+
+        clones::expire(&config, &config.subvolumes[0]);
+
+        assert_eq!(backend.is_subvolume(old), Ok(false));
+        assert_eq!(backend.is_subvolume(new), Ok(true));
+        let listed = clones::list(&config, &config.subvolumes[0])
+            .expect("Reading the clones should succeed.");
+        assert_eq!(listed.len(), 1);
+    }
+
+    #[test]
+    fn skips_unchanged_subvolumes() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "skips-unchanged-subvolumes");
+        config.skip_unchanged = true;
+        let times = hours_ago(3);
+        take_snapshots(&config, &times[..2]);
+        assert_eq!(paths(&backend, SNAPSHOTS).len(), 1);
+
+        backend.write(Path::new(SUBVOLUME), 4096);
+        take_snapshots(&config, &times[2..]);
+
+        assert_eq!(paths(&backend, SNAPSHOTS).len(), 2);
+    }
+
+    #[test]
+    fn refuses_to_snapshot_without_headroom() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "refuses-to-snapshot-without-headroom");
+        config.min_unallocated_bytes = Some(1 << 30);
+        backend.set_allocation(1 << 20, 1 << 20);
+
+        let result = take_snapshot(&config, &config.subvolumes[0], &truncated_now());
+
+        assert!(result.is_err());
+        assert!(paths(&backend, SNAPSHOTS).is_empty());
+    }
+
+    #[test]
+    fn prunes_down_to_the_space_budget() {
+        let backend = Arc::new(MockBackend::default());
+        let mut config = config(&backend, "prunes-down-to-the-space-budget");
+        config.retention = RetentionPolicy {
+            hourly_limit: 0,
+            space_budget_bytes: Some(150),
+            ..RetentionPolicy::default()
+        };
+        take_snapshots(&config, &hours_ago(3));
+        let before = paths(&backend, SNAPSHOTS);
+        for path in before.iter() {
+            backend.write(path, 100);
+        }
+
+        prune_snapshots(
+            &config,
+            &config.subvolumes[0],
+            &mut RetentionMetrics::default(),
+        );
+
+        assert_eq!(paths(&backend, SNAPSHOTS), before[2..]);
+    }
+
+    #[test]
+    fn sends_snapshots_to_another_directory() {
+        let backend = Arc::new(MockBackend::default());
+        let config = config(&backend, "sends-snapshots-to-another-directory");
+        take_snapshots(&config, &hours_ago(1));
+        let snapshot = paths(&backend, SNAPSHOTS);
+
+        replication::send_local(&*backend, &snapshot[0], Path::new("/backup"))
+            .expect("Sending within the mock should succeed.");
+
+        let received = paths(&backend, "/backup");
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].file_name(), snapshot[0].file_name());
+        assert_eq!(backend.is_snapshot(&received[0]), Ok(true));
+    }
+}
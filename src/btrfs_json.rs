@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{BtrfsCommand, backend::SubvolumeInfo, parse_creation_time};
+use jiff::Timestamp;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Runs btrfs with `--format json` ahead of `args` and `path`, returning the document it writes,
+/// or `None` if this btrfs can't write JSON for the command so its text output must be read.
+pub fn output(btrfs: &BtrfsCommand, args: &[&str], path: &Path) -> Result<Option<Value>, String> {
+    let mut command = btrfs.command();
+    command.args(["--format", "json"]).args(args).arg(path);
+
+    let output = btrfs.output(&mut command)?;
+
+    if output.status.success() {
+        return Ok(serde_json::from_slice(&output.stdout).ok());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // Older btrfs doesn't know --format, newer only supports JSON for some commands.
+    if stderr.contains("format") {
+        tracing::debug!(
+            "btrfs can't write JSON for {}. {}",
+            args.join(" "),
+            stderr.trim_end()
+        );
+        return Ok(None);
+    }
+
+    Err(stderr.trim_end().to_string())
+}
+
+/// Reads what `btrfs subvolume show` says about the subvolume at `path` from its JSON.
+pub fn subvolume_info(document: &Value, path: &Path) -> Option<SubvolumeInfo> {
+    let subvolume = find(document, "generation")?;
+    let uuid = |key: &str| {
+        string(subvolume, key)
+            .filter(|x| !x.is_empty() && *x != "-")
+            .map(str::to_string)
+    };
+
+    Some(SubvolumeInfo {
+        path: path.to_path_buf(),
+        id: number(subvolume, "subvolume_id").or(number(subvolume, "id"))?,
+        generation: number(subvolume, "generation")?,
+        created_generation: number(subvolume, "gen_at_creation")?,
+        uuid: uuid("uuid"),
+        parent_uuid: uuid("parent_uuid"),
+        received_uuid: uuid("received_uuid"),
+        created: string(subvolume, "otime").and_then(|x| {
+            parse_creation_time(x)
+                .map(|x| x.timestamp())
+                .or(x.parse::<Timestamp>().map_err(|e| e.to_string()))
+                .ok()
+        }),
+    })
+}
+
+/// Reads the path of every subvolume, relative to the filesystem's top level, from the JSON of
+/// `btrfs subvolume list`.
+pub fn subvolume_paths(document: &Value) -> Option<Vec<String>> {
+    entries(document)?
+        .iter()
+        .map(|x| {
+            x.as_object()
+                .and_then(|x| string(x, "path"))
+                .map(str::to_string)
+        })
+        .collect()
+}
+
+/// Reads the exclusive bytes of the subvolume's own qgroup from the JSON of `btrfs qgroup show`.
+pub fn exclusive_bytes(document: &Value) -> Option<u64> {
+    entries(document)?
+        .iter()
+        .filter_map(Value::as_object)
+        .find(|x| string(x, "qgroupid").is_some_and(|x| x.starts_with("0/")))
+        .and_then(|x| number(x, "exclusive"))
+}
+
+/// Reads the unallocated bytes and the free bytes in the allocated metadata chunks from the JSON
+/// of `btrfs filesystem usage`.
+pub fn allocation(document: &Value) -> Option<(u64, u64)> {
+    let unallocated = number(find(document, "device_unallocated")?, "device_unallocated")?;
+    let metadata = entries(document)?
+        .iter()
+        .filter_map(Value::as_object)
+        .find(|x| string(x, "type").is_some_and(|x| x.eq_ignore_ascii_case("metadata")))?;
+
+    Some((
+        unallocated,
+        number(metadata, "size")?.saturating_sub(number(metadata, "used")?),
+    ))
+}
+
+/// The first object in `value` holding `key`, searched for depth first.
+fn find<'a>(value: &'a Value, key: &str) -> Option<&'a Map<String, Value>> {
+    match value {
+        Value::Object(x) if x.contains_key(key) => Some(x),
+        Value::Object(x) => x.values().find_map(|x| find(x, key)),
+        Value::Array(x) => x.iter().find_map(|x| find(x, key)),
+        _ => None,
+    }
+}
+
+/// The first array in `value`, which holds one entry per row btrfs would print as text.
+fn entries(value: &Value) -> Option<&Vec<Value>> {
+    match value {
+        Value::Array(x) => Some(x),
+        Value::Object(x) => x.values().find_map(entries),
+        _ => None,
+    }
+}
+
+/// A number in `object`, which btrfs may write quoted.
+fn number(object: &Map<String, Value>, key: &str) -> Option<u64> {
+    match object.get(key)? {
+        Value::Number(x) => x.as_u64(),
+        Value::String(x) => x.parse().ok(),
+        _ => None,
+    }
+}
+
+fn string<'a>(object: &'a Map<String, Value>, key: &str) -> Option<&'a str> {
+    object.get(key)?.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_subvolume_show() {
+        let document = json!({
+            "__header": { "version": "1" },
+            "subvolume-show": {
+                "name": "@.2026-01-02",
+                "uuid": "4e2c7a44-0f6d-4d4e-9a4c-2f0b8b1f5a11",
+                "parent_uuid": "9b1d4c20-5c4e-4a6b-8d9f-3e2a1b0c9d88",
+                "received_uuid": "-",
+                "otime": "2026-01-02 03:04:05 +0000",
+                "subvolume_id": 257,
+                "generation": 120,
+                "gen_at_creation": "118"
+            }
+        });
+
+        let info = subvolume_info(&document, Path::new("/snapshots/@.2026-01-02"))
+            .expect("Complete subvolume JSON should be read.");
+
+        assert_eq!(info.id, 257);
+        assert_eq!((info.generation, info.created_generation), (120, 118));
+        assert!(info.parent_uuid.is_some());
+        assert_eq!(info.received_uuid, None);
+        assert_eq!(
+            info.created,
+            Some(
+                "2026-01-02T03:04:05Z"
+                    .parse()
+                    .expect("Should be a timestamp.")
+            )
+        );
+        assert_eq!(
+            subvolume_info(&json!({ "__header": {} }), Path::new("/")),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_subvolume_list_and_qgroups() {
+        let list = json!({
+            "__header": { "version": "1" },
+            "subvolume-list": [
+                { "id": 256, "gen": 7, "top_level": 5, "path": "@" },
+                { "id": 257, "gen": 9, "top_level": 5, "path": "@home" }
+            ]
+        });
+        let qgroups = json!({
+            "__header": { "version": "1" },
+            "qgroup-show": [
+                { "qgroupid": "1/100", "referenced": 8192, "exclusive": 8192 },
+                { "qgroupid": "0/257", "referenced": 16384, "exclusive": 4096 }
+            ]
+        });
+
+        assert_eq!(
+            subvolume_paths(&list),
+            Some(vec!["@".to_string(), "@home".to_string()])
+        );
+        assert_eq!(exclusive_bytes(&qgroups), Some(4096));
+    }
+}
@@ -0,0 +1,1456 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    BtrfsCommand, Config, FailurePolicy, Snapshot, apply_space_budget, btrfs_snapshots,
+    btrfs_subvolume_list, clones, control, delete_snapshots, delete_subvolume, enforce_min_keep,
+    glob_match, group, in_blackout, init, is_immutable, matching_snapshots, nearest_snapshot,
+    origin::Origin,
+    plan_retention, projected_expiry, read_only, replication,
+    retention::{self, RetentionPolicy},
+    state::{self, State},
+    subvolume_timing, timespec,
+};
+use jiff::{Zoned, tz::TimeZone};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+const USAGE: &str = "Usage: snapshotter [--read-only] [COMMAND]
+
+Runs the snapshot daemon when no command is given. The config file is
+/etc/btrfs-snapshotter/config.toml, or $XDG_CONFIG_HOME/btrfs-snapshotter/config.toml
+when not run as root.
+
+Options:
+  --read-only             Scan, plan, verify and report as usual, but never create
+                          or delete snapshots or write config files.
+
+Commands:
+  validate-config [PATH]  Parse and validate a config file without applying it.
+                          Defaults to the config file.
+  list [OPTIONS] [PATH]   List each subvolume's snapshots and when they will be
+                          pruned under the config file's policy.
+                          Defaults to the config file.
+      --since TIME        Only list snapshots taken at or after TIME.
+      --until TIME        Only list snapshots taken at or before TIME.
+      --groups            List each group's sets of snapshots taken together
+                          instead, and whether each is complete.
+  status [PATH]           Show the failure policy and whether each subvolume's
+                          snapshots are failing, backing off or stopped.
+                          Defaults to the config file.
+  resume [NAME]           Clear the failures of subvolume NAME, or of every
+                          subvolume, so snapshots are tried again next cycle.
+  snapshot now [NAME]     Ask the running daemon to snapshot subvolume NAME, or
+                          every subvolume, straight away. These are manual
+                          snapshots, kept apart from the timeline.
+  snapshot pre|post [NAME]
+                          Like snapshot now, but taken before and after a
+                          change such as a package upgrade. Each post snapshot
+                          pairs with the pre snapshot before it, and the pair
+                          is kept or pruned together.
+  next [--count N] [PATH]
+                          Print each subvolume's next N scheduled snapshot times,
+                          5 by default, and the snapshots projected to be pruned
+                          at each. Defaults to the config file.
+  nearest TIME [OPTIONS] [PATH]
+                          Print the newest snapshot of each subvolume taken at or
+                          before TIME, the set to restore to for that time.
+                          Defaults to the config file.
+      --subvolume NAME    Only print the snapshot of subvolume NAME.
+  rollback --group NAME TIME [PATH]
+                          Replace every subvolume of group NAME with a writable
+                          snapshot of its newest complete set at or before TIME,
+                          keeping the replaced subvolumes beside them as
+                          PATH.before-rollback. Nothing is replaced if any of
+                          them is mounted, leaving the snapshots staged to swap
+                          in by hand. Defaults to the config file.
+  clones list [PATH]      List the replaced subvolumes and staged snapshots
+                          rollbacks left, with when clone_max_age deletes each.
+                          Defaults to the config file.
+  clones delete CLONE [PATH]
+                          Delete CLONE, a subvolume clones list shows.
+                          Defaults to the config file.
+  verify-replica TARGET [PATH]
+                          Check that each snapshot on replication target TARGET
+                          was received in full from the local snapshot it is
+                          named after, reporting any missing or mismatched.
+                          Defaults to the config file.
+  prune --explain [PATH]  Print which retention rules keep each snapshot, or why
+                          it will be pruned, without deleting anything.
+                          Defaults to the config file.
+  prune --external DIR [--pattern GLOB] [--policy NAME] [--explain]
+                          Apply retention to the snapshots in DIR made by another
+                          tool, dated by their btrfs creation time.
+      --pattern           Only consider snapshot names matching GLOB.
+                          Defaults to *.
+      --policy            Use the config file's [policy.NAME] retention instead
+                          of the top level one.
+      --explain           Print why each snapshot is kept or pruned instead of
+                          deleting any.
+  config init [OPTIONS] [PATH]
+                          Write a commented default config file.
+                          Defaults to the config file.
+      --detect            Add an entry for each mounted btrfs subvolume.
+      --force             Overwrite an existing config file.
+  version [--json]        Print the version. --json adds the machine API version,
+                          features and backends for scripts to check.
+
+TIME is a date and time such as \"2026-03-01 12:00\", now, today or yesterday,
+a weekday such as \"friday 17:00\" or \"last friday\", or a span such as
+\"2 weeks ago\".";
+
+/// Bumped whenever the output of a command meant for scripts, such as `version --json`, changes
+/// incompatibly.
+const API_VERSION: u32 = 1;
+
+/// What this build supports, reported by `version --json`.
+const FEATURES: &[&str] = &[
+    "bandwidth_limit",
+    "blackout",
+    "calendar_schedule",
+    "catch_up",
+    "clone_cleanup",
+    "command_timeout",
+    "compression",
+    "defer_on_pressure",
+    "encryption",
+    "failure_policy",
+    "hooks",
+    "metrics",
+    "origin_policy",
+    "pre_post_pairs",
+    "replication",
+    "restore_drill",
+    "retention_policies",
+    "skip_on_battery",
+    "skip_unchanged",
+    "snapshot_groups",
+    "subvolume_schedules",
+    "trigger_paths",
+    "verify_replica",
+];
+
+/// The ways snapshots can be managed, reported by `version --json`.
+const BACKENDS: &[&str] = &["btrfs-progs", "ioctl"];
+
+/// The commented config shipped in the package, used as the template for `config init`.
+const DEFAULT_CONFIG: &str = include_str!("../pkg/common/config.toml");
+
+pub struct Cli {
+    pub command: CliCommand,
+    pub read_only: bool,
+}
+
+pub enum CliCommand {
+    Daemon,
+    ValidateConfig(PathBuf),
+    List {
+        path: PathBuf,
+        since: Option<Zoned>,
+        until: Option<Zoned>,
+        groups: bool,
+    },
+    Status(PathBuf),
+    Resume(Option<String>),
+    SnapshotNow(Origin, Option<String>),
+    Next {
+        path: PathBuf,
+        count: usize,
+    },
+    Nearest {
+        path: PathBuf,
+        time: Zoned,
+        subvolume: Option<String>,
+    },
+    Rollback {
+        path: PathBuf,
+        group: String,
+        time: Zoned,
+    },
+    ListClones(PathBuf),
+    DeleteClone {
+        path: PathBuf,
+        clone: PathBuf,
+    },
+    VerifyReplica {
+        path: PathBuf,
+        target: String,
+    },
+    PruneExternal {
+        config_path: PathBuf,
+        dir: PathBuf,
+        pattern: String,
+        policy: Option<String>,
+        explain: bool,
+    },
+    ExplainPruning(PathBuf),
+    InitConfig {
+        path: PathBuf,
+        detect: bool,
+        force: bool,
+    },
+    Version {
+        json: bool,
+    },
+}
+
+pub fn parse_args() -> Cli {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let read_only = args.iter().any(|x| x == "--read-only");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|x| *x != "--read-only")
+        .collect();
+
+    let command = match args.as_slice() {
+        [] => CliCommand::Daemon,
+        ["version"] => CliCommand::Version { json: false },
+        ["version", "--json"] => CliCommand::Version { json: true },
+        ["validate-config"] => CliCommand::ValidateConfig(init::config_file_path()),
+        ["validate-config", path] => CliCommand::ValidateConfig(PathBuf::from(path)),
+        ["list", options @ ..] => {
+            let mut path = init::config_file_path();
+            let mut since = None;
+            let mut until = None;
+            let mut groups = false;
+            let mut path_set = false;
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                match *option {
+                    "--since" => since = Some(time_argument(options.next())),
+                    "--until" => until = Some(time_argument(options.next())),
+                    "--groups" => groups = true,
+                    x if !x.starts_with('-') && !path_set => {
+                        path = PathBuf::from(x);
+                        path_set = true;
+                    }
+                    _ => usage_error(),
+                }
+            }
+
+            CliCommand::List {
+                path,
+                since,
+                until,
+                groups,
+            }
+        }
+        ["status"] => CliCommand::Status(init::config_file_path()),
+        ["status", path] => CliCommand::Status(PathBuf::from(path)),
+        ["resume"] => CliCommand::Resume(None),
+        ["resume", name] => CliCommand::Resume(Some(name.to_string())),
+        ["snapshot", "now"] => CliCommand::SnapshotNow(Origin::Manual, None),
+        ["snapshot", "now", name] => {
+            CliCommand::SnapshotNow(Origin::Manual, Some(name.to_string()))
+        }
+        ["snapshot", "pre"] => CliCommand::SnapshotNow(Origin::Pre, None),
+        ["snapshot", "pre", name] => CliCommand::SnapshotNow(Origin::Pre, Some(name.to_string())),
+        ["snapshot", "post"] => CliCommand::SnapshotNow(Origin::Post, None),
+        ["snapshot", "post", name] => CliCommand::SnapshotNow(Origin::Post, Some(name.to_string())),
+        ["next", options @ ..] => {
+            let mut path = init::config_file_path();
+            let mut count = 5;
+            let mut path_set = false;
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                match *option {
+                    "--count" => match options.next().and_then(|x| x.parse().ok()) {
+                        Some(x) if x > 0 => count = x,
+                        _ => usage_error(),
+                    },
+                    x if !x.starts_with('-') && !path_set => {
+                        path = PathBuf::from(x);
+                        path_set = true;
+                    }
+                    _ => usage_error(),
+                }
+            }
+
+            CliCommand::Next { path, count }
+        }
+        ["nearest", time, options @ ..] => {
+            let time = time_argument(Some(time));
+            let mut path = init::config_file_path();
+            let mut subvolume = None;
+            let mut path_set = false;
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                match *option {
+                    "--subvolume" => match options.next() {
+                        Some(x) => subvolume = Some(x.to_string()),
+                        None => usage_error(),
+                    },
+                    x if !x.starts_with('-') && !path_set => {
+                        path = PathBuf::from(x);
+                        path_set = true;
+                    }
+                    _ => usage_error(),
+                }
+            }
+
+            CliCommand::Nearest {
+                path,
+                time,
+                subvolume,
+            }
+        }
+        ["rollback", "--group", group, time, rest @ ..] if rest.len() <= 1 => {
+            CliCommand::Rollback {
+                path: rest
+                    .first()
+                    .map_or_else(init::config_file_path, PathBuf::from),
+                group: group.to_string(),
+                time: time_argument(Some(time)),
+            }
+        }
+        ["clones", "list"] => CliCommand::ListClones(init::config_file_path()),
+        ["clones", "list", path] => CliCommand::ListClones(PathBuf::from(path)),
+        ["clones", "delete", clone, rest @ ..] if rest.len() <= 1 => CliCommand::DeleteClone {
+            path: rest
+                .first()
+                .map_or_else(init::config_file_path, PathBuf::from),
+            clone: PathBuf::from(clone),
+        },
+        ["verify-replica", target] => CliCommand::VerifyReplica {
+            path: init::config_file_path(),
+            target: target.to_string(),
+        },
+        ["verify-replica", target, path] => CliCommand::VerifyReplica {
+            path: PathBuf::from(path),
+            target: target.to_string(),
+        },
+        ["prune", options @ ..] => {
+            let mut dir = None;
+            let mut pattern = None;
+            let mut policy = None;
+            let mut explain = false;
+            let mut path = None;
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                match (*option, options.len()) {
+                    ("--explain", _) => explain = true,
+                    ("--external", 1..) => dir = options.next().map(PathBuf::from),
+                    ("--pattern", 1..) => pattern = options.next().map(|x| x.to_string()),
+                    ("--policy", 1..) => policy = options.next().map(|x| x.to_string()),
+                    (x, _) if !x.starts_with('-') && path.is_none() => {
+                        path = Some(PathBuf::from(x));
+                    }
+                    _ => usage_error(),
+                }
+            }
+
+            match (dir, path) {
+                (Some(dir), None) => CliCommand::PruneExternal {
+                    config_path: init::config_file_path(),
+                    dir,
+                    pattern: pattern.unwrap_or("*".to_string()),
+                    policy,
+                    explain,
+                },
+                (None, path) if explain && pattern.is_none() && policy.is_none() => {
+                    CliCommand::ExplainPruning(path.unwrap_or_else(init::config_file_path))
+                }
+                _ => usage_error(),
+            }
+        }
+        ["config", "init", options @ ..] => {
+            let mut path = init::config_file_path();
+            let mut detect = false;
+            let mut force = false;
+            let mut path_set = false;
+
+            for option in options {
+                match *option {
+                    "--detect" => detect = true,
+                    "--force" => force = true,
+                    x if !x.starts_with('-') && !path_set => {
+                        path = PathBuf::from(x);
+                        path_set = true;
+                    }
+                    _ => usage_error(),
+                }
+            }
+
+            CliCommand::InitConfig {
+                path,
+                detect,
+                force,
+            }
+        }
+        ["-h"] | ["--help"] | ["help"] => {
+            println!("{}", USAGE);
+            exit(0);
+        }
+        _ => usage_error(),
+    };
+
+    Cli { command, read_only }
+}
+
+/// Resolves a TIME argument, exiting with the usage if it is missing or can't be resolved.
+fn time_argument(argument: Option<&&str>) -> Zoned {
+    let Some(argument) = argument else {
+        usage_error();
+    };
+
+    match timespec::parse_time(argument, &Zoned::now()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(2);
+        }
+    }
+}
+
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2);
+}
+
+pub fn version(json: bool) {
+    if !json {
+        println!("snapshotter {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+
+    // Every value is a fixed identifier, so nothing needs escaping.
+    let list = |x: &[&str]| {
+        x.iter()
+            .map(|y| format!("\"{}\"", y))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    println!(
+        "{{\"version\": \"{}\", \"api_version\": {}, \"features\": [{}], \"backends\": [{}]}}",
+        env!("CARGO_PKG_VERSION"),
+        API_VERSION,
+        list(FEATURES),
+        list(BACKENDS)
+    );
+}
+
+pub fn validate_config(config_file_path: PathBuf) {
+    match init::read_config(config_file_path.as_path()) {
+        Ok(_) => println!(
+            "Config file {} is valid.",
+            config_file_path.to_string_lossy()
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// How many future cycles `list` simulates when projecting expiry, enough for several years of
+/// hourly snapshots.
+const EXPIRY_HORIZON_CYCLES: usize = 100_000;
+
+pub fn list_snapshots(config_file_path: PathBuf, since: Option<Zoned>, until: Option<Zoned>) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let now = Zoned::now();
+    // Only used to show generations, so listing works without a readable state file.
+    let state = State::load(&config.state_path).unwrap_or_default();
+
+    for subvolume in config.subvolumes.iter() {
+        println!("{}:", subvolume.name);
+        let generations = state
+            .subvolumes
+            .get(&subvolume.name)
+            .map(|x| &x.generations);
+
+        let snapshots = match matching_snapshots(&config, subvolume) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Error reading snapshots in {}. {}",
+                    subvolume.snapshot_path.to_string_lossy(),
+                    e
+                );
+                continue;
+            }
+        };
+        let expiry = projected_expiry(&config, subvolume, &snapshots, &now, EXPIRY_HORIZON_CYCLES);
+
+        for (snapshot, expiry) in snapshots.iter().zip(expiry) {
+            if since.as_ref().is_some_and(|x| snapshot.time < *x)
+                || until.as_ref().is_some_and(|x| snapshot.time > *x)
+            {
+                continue;
+            }
+            let expiry = match expiry {
+                Some(x) if x <= now => "pruned next cycle".to_string(),
+                Some(x) => format!("pruned in {} ({})", approximate_duration(&now, &x), x),
+                None => "kept for the foreseeable future".to_string(),
+            };
+            let generation = match (
+                &snapshot.info,
+                generations.and_then(|x| x.get(snapshot.snapshot_path.to_string_lossy().as_ref())),
+            ) {
+                (Some(x), _) => format!("id {}  generation {}  ", x.id, x.generation),
+                (None, Some(x)) => format!("generation {}  ", x),
+                (None, None) => String::new(),
+            };
+            println!(
+                "  {}  {}{}",
+                snapshot
+                    .snapshot_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                generation,
+                expiry
+            );
+        }
+    }
+}
+
+/// Prints the sets of snapshots each group's members took together, marking the members whose
+/// snapshot is missing from a set.
+pub fn list_groups(config_file_path: PathBuf, since: Option<Zoned>, until: Option<Zoned>) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    if config.groups.is_empty() {
+        println!("No groups in config file.");
+        return;
+    }
+
+    for group in config.groups.iter() {
+        println!("{}:", group.name);
+
+        for (time, set) in group::group_snapshots(&state, group) {
+            if since.as_ref().is_some_and(|x| time < *x)
+                || until.as_ref().is_some_and(|x| time > *x)
+            {
+                continue;
+            }
+            println!(
+                "  {}  {}",
+                time,
+                if group::complete(group, &set) {
+                    "complete"
+                } else {
+                    "incomplete"
+                }
+            );
+            for member in group.subvolumes.iter() {
+                match set.snapshots.get(member) {
+                    Some(x) if Path::new(x).exists() => println!("    {}  {}", member, x),
+                    Some(x) => println!("    {}  {} (deleted)", member, x),
+                    None => println!("    {}  not taken", member),
+                }
+            }
+        }
+    }
+}
+
+/// Prints why the config file's retention keeps or prunes each of every subvolume's snapshots.
+pub fn explain_pruning(config_file_path: PathBuf) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    for subvolume in config.subvolumes.iter() {
+        println!("{}:", subvolume.name);
+        let mut snapshots = match matching_snapshots(&config, subvolume) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Error reading snapshots in {}. {}",
+                    subvolume.snapshot_path.to_string_lossy(),
+                    e
+                );
+                continue;
+            }
+        };
+        print_explanation(
+            &config,
+            &subvolume.btrfs,
+            &config.retention,
+            &subvolume.name,
+            &mut snapshots,
+        );
+    }
+}
+
+/// Plans retention for `snapshots` as pruning would, printing each one's fate and the rules
+/// behind it.
+fn print_explanation(
+    config: &Config,
+    btrfs: &BtrfsCommand,
+    policy: &RetentionPolicy,
+    series: &str,
+    snapshots: &mut [Snapshot],
+) {
+    let now = Zoned::now();
+    let times: Vec<Zoned> = snapshots.iter().map(|x| x.time.clone()).collect();
+    let reasons = retention::explain(policy, &times, &now);
+    plan_retention(policy, snapshots, &now);
+    apply_space_budget(btrfs, policy, snapshots, &now);
+    enforce_min_keep(config, series, snapshots);
+
+    for (snapshot, reasons) in snapshots.iter().zip(reasons) {
+        let explanation = match (snapshot.keep, reasons.is_empty()) {
+            (true, false) => format!("kept by {}", reasons.join(", ")),
+            (true, true) => format!("kept by min_keep {}", config.min_keep),
+            (false, false) => format!(
+                "pruned to fit space_budget_bytes, though kept by {}",
+                reasons.join(", ")
+            ),
+            (false, true)
+                if policy
+                    .max_age
+                    .is_some_and(|x| snapshot.time < now.saturating_sub(x)) =>
+            {
+                "pruned as older than max_age".to_string()
+            }
+            (false, true) => "pruned as no rule keeps it".to_string(),
+        };
+        println!(
+            "  {}  {}",
+            snapshot
+                .snapshot_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            explanation
+        );
+    }
+}
+
+/// Applies a retention policy from the config file to the snapshots in `dir` whose names match
+/// `pattern`, deleting the ones it does not keep. With `explain` nothing is deleted, and why each
+/// snapshot is kept or pruned is printed instead.
+pub fn prune_external(
+    config_path: PathBuf,
+    dir: &Path,
+    pattern: &str,
+    policy: Option<&str>,
+    explain: bool,
+) {
+    let config = match init::read_config(config_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let retention = match policy {
+        Some(x) => match config.policies.get(x) {
+            Some(x) => x,
+            None => {
+                eprintln!("No policy {} in config file.", x);
+                exit(1);
+            }
+        },
+        None => &config.retention,
+    };
+    // Run just as the daemon runs it, on the directory as the daemon sees it.
+    let btrfs = &config.btrfs;
+    let dir = init::host_path(&config, dir);
+    let dir = dir.as_path();
+
+    let subvolumes = match btrfs_snapshots(btrfs, dir) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!(
+                "Error reading snapshots in {}. {}",
+                dir.to_string_lossy(),
+                e
+            );
+            exit(1);
+        }
+    };
+    let mut snapshots = Vec::new();
+    for subvolume in subvolumes {
+        let name = subvolume
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if !glob_match(pattern, &name) {
+            continue;
+        }
+
+        match subvolume.created {
+            Some(x) => snapshots.push(Snapshot {
+                snapshot_path: subvolume.path.clone(),
+                time: x.to_zoned(TimeZone::system()),
+                keep: false,
+                info: Some(subvolume),
+            }),
+            None => eprintln!("Skipping {} as it has no creation time.", name),
+        }
+    }
+    snapshots.sort();
+
+    if explain {
+        print_explanation(
+            &config,
+            btrfs,
+            retention,
+            &dir.to_string_lossy(),
+            &mut snapshots,
+        );
+        return;
+    }
+    let now = Zoned::now();
+    plan_retention(retention, &mut snapshots, &now);
+    apply_space_budget(btrfs, retention, &mut snapshots, &now);
+    enforce_min_keep(&config, &dir.to_string_lossy(), &mut snapshots);
+
+    let mut doomed = Vec::new();
+    for snapshot in snapshots.iter() {
+        let name = snapshot
+            .snapshot_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        if snapshot.keep || is_immutable(&config, snapshot) {
+            println!("  {}  kept", name);
+            continue;
+        }
+        println!("  {}  pruned", name);
+        doomed.push(snapshot);
+    }
+
+    let mut failed = false;
+    for (snapshot, result) in doomed.iter().zip(delete_snapshots(&config, btrfs, &doomed)) {
+        if let Err(e) = result {
+            eprintln!(
+                "Error deleting {}. {}",
+                snapshot
+                    .snapshot_path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy(),
+                e
+            );
+            failed = true;
+        }
+    }
+
+    if failed && !read_only() {
+        exit(1);
+    }
+}
+
+pub fn status(config_file_path: PathBuf) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    println!(
+        "Failure policy: {}",
+        match config.failure_policy {
+            FailurePolicy::Retry => "retry every cycle".to_string(),
+            FailurePolicy::Backoff => format!(
+                "back off exponentially, up to {:#}",
+                config.failure_backoff_max
+            ),
+            FailurePolicy::Stop =>
+                format!("stop after {} consecutive failures", config.failure_limit),
+        }
+    );
+
+    for subvolume in config.subvolumes.iter() {
+        let Some(x) = state.subvolumes.get(&subvolume.name) else {
+            println!("{}: no snapshots recorded yet", subvolume.name);
+            continue;
+        };
+
+        let generation = match x.last_generation {
+            Some(x) => format!(" at generation {}", x),
+            None => String::new(),
+        };
+        if x.consecutive_failures == 0 {
+            println!(
+                "{}: ok, last snapshot {}{}",
+                subvolume.name,
+                x.last_success.as_deref().unwrap_or("unknown"),
+                generation
+            );
+        } else {
+            println!(
+                "{}: {} consecutive failures, last at {}",
+                subvolume.name,
+                x.consecutive_failures,
+                x.last_failure.as_deref().unwrap_or("unknown")
+            );
+            if let Some(e) = &x.last_error {
+                println!("  Error: {}", e);
+            }
+            if x.stopped {
+                println!(
+                    "  Stopped, run snapshotter resume {} once fixed.",
+                    subvolume.name
+                );
+            } else if let Some(retry_after) = &x.retry_after {
+                println!("  Retrying after {}.", retry_after);
+            }
+        }
+        if !x.deletion_queue.is_empty() {
+            println!(
+                "  {} pruned snapshots queued for deletion.",
+                x.deletion_queue.len()
+            );
+        }
+    }
+}
+
+/// Clears the recorded failures of `subvolume`, or of every subvolume, in the state file.
+pub fn resume(subvolume: Option<&str>) {
+    if read_only() {
+        eprintln!("Read-only mode, not writing the state file.");
+        exit(1);
+    }
+    let config = match init::read_config(init::config_file_path().as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let mut resumed = 0;
+    for (name, x) in state.subvolumes.iter_mut() {
+        if subvolume.is_none_or(|y| y == name) && x.consecutive_failures > 0 {
+            x.consecutive_failures = 0;
+            x.retry_after = None;
+            x.stopped = false;
+            println!("Resumed {}.", name);
+            resumed += 1;
+        }
+    }
+    if resumed == 0 {
+        println!("Nothing to resume.");
+        return;
+    }
+
+    if let Err(e) = state.save(&config.state_path) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+/// Asks the daemon to snapshot `subvolume`, or every subvolume, over the control socket so the
+/// snapshot goes through its state rather than racing it.
+pub fn snapshot_now(origin: Origin, subvolume: Option<&str>) {
+    let config = match init::read_config(init::config_file_path().as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let verb = match origin {
+        Origin::Manual => "snapshot",
+        x => x.tag(),
+    };
+    let request = match subvolume {
+        Some(x) => format!("{} {}", verb, x),
+        None => verb.to_string(),
+    };
+
+    match control::send_request(&config.control_socket, &request) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Prints the next `count` scheduled snapshot times of each subvolume with the snapshots projected
+/// to be pruned in each of those cycles.
+pub fn next(config_file_path: PathBuf, count: usize) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let now = Zoned::now();
+
+    for subvolume in config.subvolumes.iter() {
+        println!("{}:", subvolume.name);
+
+        let snapshots = match matching_snapshots(&config, subvolume) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Error reading snapshots in {}. {}",
+                    subvolume.snapshot_path.to_string_lossy(),
+                    e
+                );
+                Vec::new()
+            }
+        };
+        let expiry = projected_expiry(&config, subvolume, &snapshots, &now, count);
+        let timing = subvolume_timing(&config, subvolume);
+        let mut time = timing.first_at_or_after(&now);
+
+        for i in 0..count {
+            if in_blackout(&config, &time) {
+                println!("  {}  skipped, in a blackout window", time);
+            } else {
+                println!("  {}", time);
+            }
+            // Snapshots already due for pruning go in the first cycle.
+            for (snapshot, _) in snapshots.iter().zip(expiry.iter()).filter(|(_, x)| {
+                x.as_ref()
+                    .is_some_and(|x| *x == time || (i == 0 && *x <= time))
+            }) {
+                println!(
+                    "    prunes {}",
+                    snapshot
+                        .snapshot_path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                );
+            }
+            time = timing.next_after(&time);
+        }
+    }
+}
+
+/// Prints the snapshot of each subvolume, or just `subvolume`, nearest at or before `time`.
+pub fn nearest(config_file_path: PathBuf, time: &Zoned, subvolume: Option<&str>) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    if let Some(x) = subvolume
+        && !config.subvolumes.iter().any(|y| y.name == x)
+    {
+        eprintln!("No subvolume {} in config file.", x);
+        exit(1);
+    }
+
+    let mut found = false;
+    for x in config
+        .subvolumes
+        .iter()
+        .filter(|x| subvolume.is_none_or(|y| x.name == y))
+    {
+        let snapshots = match matching_snapshots(&config, x) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Error reading snapshots in {}. {}",
+                    x.snapshot_path.to_string_lossy(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        match nearest_snapshot(&snapshots, time) {
+            Some(snapshot) => {
+                found = true;
+                println!(
+                    "{}  {}  {}",
+                    x.name,
+                    snapshot.snapshot_path.to_string_lossy(),
+                    snapshot.time
+                );
+            }
+            None => eprintln!("{} has no snapshot at or before {}.", x.name, time),
+        }
+    }
+
+    if !found {
+        exit(1);
+    }
+}
+
+/// Rolls every subvolume of `group` back to the group's newest complete set of snapshots at or
+/// before `time`. Writable snapshots of the set are staged beside each snapshot first, then
+/// swapped in for the subvolumes by renaming, so the group is either rolled back as a whole or
+/// left as it was. Mounted subvolumes can't be renamed, so if any member is mounted the staged
+/// snapshots are left for the user to swap in instead.
+pub fn rollback_group(config_file_path: PathBuf, group: &str, time: &Zoned) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let Some(group) = config.groups.iter().find(|x| x.name == group) else {
+        eprintln!("No group {} in config file.", group);
+        exit(1);
+    };
+    let state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    let sets: Vec<_> = group::group_snapshots(&state, group)
+        .into_iter()
+        .filter(|(x, _)| x <= time)
+        .collect();
+    let Some((set_time, set)) = sets.iter().rev().find(|(_, x)| group::complete(group, x)) else {
+        eprintln!(
+            "Group {} has no complete set of snapshots at or before {}.",
+            group.name, time
+        );
+        exit(1);
+    };
+    if sets.last().is_some_and(|(x, _)| x != set_time) {
+        eprintln!(
+            "Skipping the newer sets of group {} missing a member's snapshot.",
+            group.name
+        );
+    }
+
+    // Each member with its snapshot, where a writable copy is staged and where the subvolume it
+    // replaces is kept.
+    let mut members = Vec::with_capacity(group.subvolumes.len());
+    for name in group.subvolumes.iter() {
+        let subvolume = config
+            .subvolumes
+            .iter()
+            .find(|x| x.name == *name)
+            .expect("Group members should be validated as subvolumes.");
+        let snapshot = PathBuf::from(&set.snapshots[name]);
+        let mut staged = snapshot.clone().into_os_string();
+        staged.push(".rollback");
+        let mut replaced = subvolume.path.clone().into_os_string();
+        replaced.push(".before-rollback");
+        let (staged, replaced) = (PathBuf::from(staged), PathBuf::from(replaced));
+
+        if let Some(x) = [&staged, &replaced].into_iter().find(|x| x.exists()) {
+            eprintln!(
+                "{} already exists, delete it or move it aside to roll back.",
+                x.to_string_lossy()
+            );
+            exit(1);
+        }
+        members.push((subvolume, snapshot, staged, replaced));
+    }
+    let mount_points = match mount_points() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error reading mount points. {}", e);
+            exit(1);
+        }
+    };
+    let mounted: Vec<&Path> = members
+        .iter()
+        .map(|(x, _, _, _)| x.path.as_path())
+        .filter(|x| fs::canonicalize(x).is_ok_and(|x| mount_points.contains(&x)))
+        .collect();
+
+    println!("Rolling group {} back to {}:", group.name, set_time);
+    for (subvolume, snapshot, _, replaced) in members.iter() {
+        println!(
+            "  {}  {} from {}, keeping the current one as {}",
+            subvolume.name,
+            subvolume.path.to_string_lossy(),
+            snapshot.to_string_lossy(),
+            replaced.to_string_lossy()
+        );
+    }
+    if read_only() {
+        println!("Read-only mode, nothing was rolled back.");
+        return;
+    }
+
+    for (i, (subvolume, snapshot, staged, _)) in members.iter().enumerate() {
+        if let Err(e) = subvolume.backend.create(snapshot, staged, false) {
+            eprintln!("{}", e);
+            for (subvolume, _, staged, _) in members[..i].iter() {
+                if let Err(e) = delete_subvolume(subvolume.backend.as_ref(), staged) {
+                    eprintln!("{}", e);
+                }
+            }
+            exit(1);
+        }
+    }
+    let now = Zoned::now();
+    if !mounted.is_empty() {
+        for (subvolume, _, staged, _) in members.iter() {
+            state::record_clone(&config, &subvolume.name, staged, Some(&now));
+        }
+        println!(
+            "Mounted at {}, so nothing was replaced. Swap each staged snapshot in for its subvolume while none of them are mounted, e.g. from a rescue system:",
+            mounted
+                .iter()
+                .map(|x| x.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        for (subvolume, _, staged, _) in members.iter() {
+            println!(
+                "  {}  {}",
+                subvolume.path.to_string_lossy(),
+                staged.to_string_lossy()
+            );
+        }
+        println!("Any left unswapped are listed by snapshotter clones list.");
+        return;
+    }
+
+    for (i, (subvolume, _, staged, replaced)) in members.iter().enumerate() {
+        let result = fs::rename(&subvolume.path, replaced).and_then(|()| {
+            fs::rename(staged, &subvolume.path).inspect_err(|_| {
+                let _ = fs::rename(replaced, &subvolume.path);
+            })
+        });
+
+        if let Err(e) = result {
+            eprintln!(
+                "Error swapping {} in for {}, putting the group back as it was. {}",
+                staged.to_string_lossy(),
+                subvolume.path.to_string_lossy(),
+                e
+            );
+            for (subvolume, _, staged, replaced) in members[..i].iter().rev() {
+                if let Err(e) = fs::rename(&subvolume.path, staged)
+                    .and_then(|()| fs::rename(replaced, &subvolume.path))
+                {
+                    eprintln!(
+                        "Error putting back {}, it is at {}. {}",
+                        subvolume.path.to_string_lossy(),
+                        replaced.to_string_lossy(),
+                        e
+                    );
+                }
+            }
+            exit(1);
+        }
+    }
+    for (subvolume, _, _, replaced) in members.iter() {
+        state::record_clone(&config, &subvolume.name, replaced, Some(&now));
+    }
+    println!(
+        "Rolled back. Move any subvolumes nested in the replaced ones across, then delete them with snapshotter clones delete once satisfied, or leave them to clone_max_age."
+    );
+}
+
+/// Lists the writable subvolumes rollbacks left of each subvolume, with when each was made and
+/// when clone_max_age deletes it.
+pub fn list_clones(config_file_path: PathBuf) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+
+    for subvolume in config.subvolumes.iter() {
+        let clones = match clones::list(&config, subvolume) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        };
+        if clones.is_empty() {
+            continue;
+        }
+        println!("{}:", subvolume.name);
+        for (path, made) in clones {
+            match config.clone_max_age {
+                Some(x) => println!(
+                    "  {}  made {}, deleted after {}",
+                    path.to_string_lossy(),
+                    made,
+                    made.saturating_add(x)
+                ),
+                None => println!("  {}  made {}", path.to_string_lossy(), made),
+            }
+        }
+    }
+}
+
+/// Deletes `clone`, a writable subvolume a rollback left.
+pub fn delete_clone(config_file_path: PathBuf, clone: &Path) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let found = config
+        .subvolumes
+        .iter()
+        .find(|x| clones::list(&config, x).is_ok_and(|y| y.iter().any(|(path, _)| path == clone)));
+    let Some(subvolume) = found else {
+        eprintln!(
+            "{} isn't a clone a rollback left, see snapshotter clones list.",
+            clone.to_string_lossy()
+        );
+        exit(1);
+    };
+
+    if let Err(e) = clones::delete(&config, subvolume, clone) {
+        eprintln!("{}", e);
+        if !read_only() {
+            exit(1);
+        }
+        return;
+    }
+    println!("Deleted {}.", clone.to_string_lossy());
+}
+
+/// Checks the snapshots of every subvolume on replication target `target` against the local ones,
+/// exiting with an error if any are missing or mismatched.
+pub fn verify_replica(config_file_path: PathBuf, target: &str) {
+    let config = match init::read_config(config_file_path.as_path()) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    let Some(replication) = config.replication.iter().find(|x| x.name == target) else {
+        eprintln!("No replication target {} in config file.", target);
+        exit(1);
+    };
+
+    let mut failed = false;
+    for subvolume in config.subvolumes.iter().filter(|x| x.readonly) {
+        let check = match replication::verify_replica(&config, replication, subvolume) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!(
+                    "Error verifying {} on {}. {}",
+                    subvolume.name, replication.name, e
+                );
+                failed = true;
+                continue;
+            }
+        };
+
+        println!(
+            "{}  {} verified, {} missing or mismatched, {} not sent yet",
+            subvolume.name,
+            check.verified,
+            check.problems.len(),
+            check.unsent.len()
+        );
+        for problem in check.problems.iter() {
+            println!("  {}", problem);
+        }
+        failed |= !check.problems.is_empty();
+    }
+
+    if failed {
+        exit(1);
+    }
+}
+
+fn approximate_duration(from: &Zoned, to: &Zoned) -> String {
+    let seconds = to.duration_since(from).as_secs();
+
+    match seconds {
+        x if x >= 2 * 86400 => format!("~{} days", x / 86400),
+        x if x >= 2 * 3600 => format!("~{} hours", x / 3600),
+        x => format!("~{} minutes", x.max(60) / 60),
+    }
+}
+
+pub fn init_config(config_file_path: PathBuf, detect: bool, force: bool) {
+    if read_only() {
+        eprintln!(
+            "Read-only mode, not writing config file {}.",
+            config_file_path.to_string_lossy()
+        );
+        exit(1);
+    }
+
+    let mut config = DEFAULT_CONFIG.to_string();
+
+    if detect {
+        match detect_subvolumes() {
+            Ok(x) if x.is_empty() => eprintln!("No mounted btrfs subvolumes found."),
+            Ok(x) => config = with_subvolumes(&config, &x),
+            Err(e) => {
+                eprintln!("Error detecting subvolumes. {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    if let Err(e) = write_new_file(config_file_path.as_path(), &config, force) {
+        eprintln!(
+            "Error writing config file: {} | Error: {}",
+            config_file_path.to_string_lossy(),
+            e
+        );
+        exit(1);
+    }
+    println!("Wrote config file {}.", config_file_path.to_string_lossy());
+}
+
+fn write_new_file(path: &Path, contents: &str, overwrite: bool) -> io::Result<()> {
+    if let Some(x) = path.parent() {
+        fs::create_dir_all(x)?;
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(!overwrite)
+        .create(true)
+        .truncate(true)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+/// Returns the mount point and a snapshot name for every subvolume listed by btrfs that is
+/// mounted, as only mounted subvolumes have a path to snapshot from.
+fn detect_subvolumes() -> Result<Vec<(String, String)>, String> {
+    let mount_info = fs::read_to_string("/proc/self/mountinfo").map_err(|e| e.to_string())?;
+    let mut btrfs_mounts: Vec<(String, String)> = Vec::new();
+
+    // Fields are described in proc(5); the filesystem type follows the " - " separator.
+    for line in mount_info.lines() {
+        let Some((mount_fields, filesystem_fields)) = line.split_once(" - ") else {
+            continue;
+        };
+        let mount_fields: Vec<&str> = mount_fields.split(' ').collect();
+
+        if filesystem_fields.starts_with("btrfs ") && mount_fields.len() >= 5 {
+            let subvolume = unescape_mount_field(mount_fields[3]);
+            let mount_point = unescape_mount_field(mount_fields[4]);
+
+            if !btrfs_mounts.iter().any(|(x, _)| x == &subvolume) {
+                btrfs_mounts.push((subvolume, mount_point));
+            }
+        }
+    }
+
+    let mut subvolumes = Vec::new();
+    for subvolume in btrfs_subvolume_list(&BtrfsCommand::default(), Path::new("/"))? {
+        if let Some((_, mount_point)) = btrfs_mounts
+            .iter()
+            .find(|(x, _)| x.trim_start_matches('/') == subvolume)
+        {
+            subvolumes.push((mount_point.clone(), subvolume.replace('/', "-")));
+        }
+    }
+
+    Ok(subvolumes)
+}
+
+/// Returns every mount point in the mount namespace.
+fn mount_points() -> Result<Vec<PathBuf>, String> {
+    let mount_info = fs::read_to_string("/proc/self/mountinfo").map_err(|e| e.to_string())?;
+
+    Ok(mount_info
+        .lines()
+        .filter_map(|x| x.split(' ').nth(4))
+        .map(|x| PathBuf::from(unescape_mount_field(x)))
+        .collect())
+}
+
+fn unescape_mount_field(field: &str) -> String {
+    field
+        .replace("\\040", " ")
+        .replace("\\011", "\t")
+        .replace("\\012", "\n")
+        .replace("\\134", "\\")
+}
+
+/// Replaces the template's example subvolume with the detected ones, keeping its comments.
+fn with_subvolumes(config: &str, subvolumes: &[(String, String)]) -> String {
+    let mut output = String::with_capacity(config.len());
+    let mut in_subvolume_block = false;
+
+    for line in config.lines() {
+        if line == "[[subvolume]]" {
+            in_subvolume_block = true;
+        } else if in_subvolume_block && line.is_empty() {
+            in_subvolume_block = false;
+
+            for (path, name) in subvolumes.iter().skip(1) {
+                output.push_str(&format!(
+                    "\n[[subvolume]]\npath = {}\nname = {}\n",
+                    toml::Value::from(path.as_str()),
+                    toml::Value::from(name.as_str())
+                ));
+            }
+        } else if in_subvolume_block && line.starts_with("path = ") {
+            output.push_str(&format!(
+                "path = {}\n",
+                toml::Value::from(subvolumes[0].0.as_str())
+            ));
+            continue;
+        } else if in_subvolume_block && line.starts_with("name = ") {
+            output.push_str(&format!(
+                "name = {}\n",
+                toml::Value::from(subvolumes[0].1.as_str())
+            ));
+            continue;
+        }
+
+        output.push_str(line);
+        output.push('\n');
+    }
+
+    output
+}
@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, SubvolumeConfig, delete_subvolume, read_only,
+    state::{self, State},
+};
+use jiff::Zoned;
+use std::path::{Path, PathBuf};
+
+/// Returns the writable subvolumes rollbacks left of `subvolume` that are still subvolumes, with
+/// when each was made, oldest first. Any since deleted, or moved aside by hand and replaced, are
+/// left out.
+pub fn list(config: &Config, subvolume: &SubvolumeConfig) -> Result<Vec<(PathBuf, Zoned)>, String> {
+    let state = State::load(&config.state_path)?;
+    let mut clones: Vec<(PathBuf, Zoned)> = state
+        .subvolumes
+        .get(&subvolume.name)
+        .map(|x| {
+            x.clones
+                .iter()
+                .filter(|(path, _)| {
+                    subvolume
+                        .backend
+                        .is_subvolume(Path::new(path))
+                        .is_ok_and(|x| x)
+                })
+                .filter_map(|(path, time)| Some((PathBuf::from(path), time.parse().ok()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    clones.sort_by(|(_, x), (_, y)| x.cmp(y));
+
+    Ok(clones)
+}
+
+/// Deletes the clone of `subvolume` at `path`, refusing anything a rollback didn't leave.
+pub fn delete(config: &Config, subvolume: &SubvolumeConfig, path: &Path) -> Result<(), String> {
+    if !list(config, subvolume)?.iter().any(|(x, _)| x == path) {
+        return Err(format!(
+            "{} isn't a clone a rollback of {} left.",
+            path.to_string_lossy(),
+            subvolume.name
+        ));
+    }
+    if read_only() {
+        return Err(format!(
+            "Read-only mode, not deleting clone {}.",
+            path.to_string_lossy()
+        ));
+    }
+
+    delete_subvolume(&*subvolume.backend, path)?;
+    tracing::info!("Deleted clone {}.", path.to_string_lossy());
+    state::record_clone(config, &subvolume.name, path, None);
+
+    Ok(())
+}
+
+/// Deletes the clones of `subvolume` older than clone_max_age.
+pub fn expire(config: &Config, subvolume: &SubvolumeConfig) {
+    let Some(max_age) = config.clone_max_age else {
+        return;
+    };
+    let clones = match list(config, subvolume) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let cutoff = Zoned::now().saturating_sub(max_age);
+
+    for (path, _) in clones.iter().filter(|(_, x)| *x <= cutoff) {
+        if let Err(e) = delete(config, subvolume, path) {
+            tracing::error!(
+                "Error deleting clone {} after clone_max_age. {}",
+                path.to_string_lossy(),
+                e
+            );
+        }
+    }
+}
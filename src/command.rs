@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use jiff::{Span, Zoned};
+use std::{
+    io::Read,
+    process::{Command, Output, Stdio},
+    thread::{self, JoinHandle, sleep},
+    time::Duration,
+};
+
+/// Runs `command` and collects its output like `Command::output`, but kills it once it has run
+/// for `timeout`, so a command stuck on a sick filesystem fails instead of hanging the daemon.
+pub fn output_with_timeout(command: &mut Command, timeout: Option<Span>) -> Result<Output, String> {
+    let Some(timeout) = timeout else {
+        return command.output().map_err(|e| e.to_string());
+    };
+    let deadline = Zoned::now().saturating_add(timeout);
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    // Read as it runs so a full pipe can't block the command from exiting.
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                return Ok(Output {
+                    status,
+                    stdout: stdout.join().unwrap_or_default(),
+                    stderr: stderr.join().unwrap_or_default(),
+                });
+            }
+            Ok(None) if Zoned::now() >= deadline => {
+                // The output threads are left behind, as anything the command started may
+                // still hold its pipes open.
+                let _ = child.kill().and_then(|()| child.wait());
+                tracing::error!(
+                    "Killed {:?} after it ran for longer than command_timeout {:#}.",
+                    command,
+                    timeout
+                );
+                return Err(format!("{:?} timed out after {:#}.", command, timeout));
+            }
+            Ok(None) => sleep(Duration::from_millis(50)),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn read_in_background<R: Read + Send + 'static>(reader: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut x) = reader {
+            let _ = x.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, Event, events::EventSender, init, matching_snapshots, nearest_snapshot, origin::Origin,
+    take_origin_snapshot, timespec,
+};
+use jiff::{Timestamp, Zoned, tz::TimeZone};
+use std::{
+    fs::{self, Permissions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// How long a client has to send its request before the connection is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request sent to the daemon over the control socket, one line of text such as
+/// `snapshot @home`, `pre @home`, `nearest @home 2 days ago` or
+/// `validate-config /etc/btrfs-snapshotter/config.toml`. The
+/// daemon answers with lines starting with `ok` or `error`, one per subvolume for snapshots.
+pub enum ControlRequest {
+    /// Snapshot the named subvolume now, or every subvolume, as a manual, pre or post snapshot.
+    Snapshot(Origin, Option<String>),
+    /// Find the newest snapshot of the named subvolume taken at or before the time, as `nearest`
+    /// does.
+    Nearest(String, Timestamp),
+    /// Parse and validate the config file at the path, as `validate-config` does.
+    ValidateConfig(PathBuf),
+}
+
+impl ControlRequest {
+    fn parse(line: &str) -> Result<Self, String> {
+        let (verb, rest) = line
+            .split_once(' ')
+            .map_or((line, ""), |(x, y)| (x, y.trim()));
+        let name = (!rest.is_empty()).then(|| rest.to_string());
+
+        match verb {
+            "snapshot" | "pre" | "post" if !rest.contains(char::is_whitespace) => {
+                let origin = match verb {
+                    "pre" => Origin::Pre,
+                    "post" => Origin::Post,
+                    _ => Origin::Manual,
+                };
+                Ok(Self::Snapshot(origin, name))
+            }
+            "nearest" => match rest.split_once(' ') {
+                Some((subvolume, time)) => Ok(Self::Nearest(
+                    subvolume.to_string(),
+                    timespec::parse_time(time, &Zoned::now())?.timestamp(),
+                )),
+                None => Err(format!("Unknown request: {}", line)),
+            },
+            // The rest of the line, as paths may hold spaces.
+            "validate-config" if !rest.is_empty() => Ok(Self::ValidateConfig(PathBuf::from(rest))),
+            _ => Err(format!("Unknown request: {}", line)),
+        }
+    }
+}
+
+/// Listens on the control socket at `path`, sending each request to the main loop with the
+/// connection to answer it on. Each connection is read on a thread of its own, so a client slow
+/// to send its request doesn't hold up any other. Only root, or the user running the daemon, may
+/// connect.
+pub fn listen(path: &Path, sender: EventSender) -> io::Result<()> {
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another daemon is already listening on it",
+        ));
+    }
+    // Left behind by a daemon that didn't exit cleanly.
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    if let Some(x) = path.parent() {
+        fs::create_dir_all(x)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::error!("Error accepting control connection. {}", e);
+                    continue;
+                }
+            };
+            let sender = sender.clone();
+            thread::spawn(move || handle_connection(stream, &sender));
+        }
+    });
+
+    Ok(())
+}
+
+/// Reads the request on `stream` and sends it to the main loop to answer.
+fn handle_connection(mut stream: UnixStream, sender: &EventSender) {
+    let request = read_request(&stream).and_then(|x| {
+        ControlRequest::parse(&x).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    });
+
+    match request {
+        // Only fails once the main loop has exited.
+        Ok(x) => {
+            let _ = sender.send(Event::Control(x, stream));
+        }
+        Err(e) => {
+            let _ = writeln!(stream, "error {}", e);
+        }
+    }
+}
+
+fn read_request(stream: &UnixStream) -> io::Result<String> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+
+    Ok(line.trim().to_string())
+}
+
+/// Carries out `request` for the main loop, answering on `stream`.
+pub fn respond(config: &Config, request: ControlRequest, mut stream: UnixStream) {
+    match request {
+        ControlRequest::Snapshot(origin, name) => {
+            let subvolumes: Vec<_> = config
+                .subvolumes
+                .iter()
+                .filter(|x| name.as_ref().is_none_or(|y| *y == x.name))
+                .collect();
+            if subvolumes.is_empty() {
+                let _ = writeln!(stream, "error No subvolume {}.", name.unwrap_or_default());
+                return;
+            }
+
+            for subvolume in subvolumes {
+                tracing::info!(
+                    "Taking an on-demand {} snapshot of {}.",
+                    origin.tag(),
+                    subvolume.name
+                );
+                // The client may have gone away, the snapshots are still taken.
+                let _ = match take_origin_snapshot(config, subvolume, origin) {
+                    Ok(x) => writeln!(stream, "ok {} {}", subvolume.name, x.to_string_lossy()),
+                    Err(e) => writeln!(stream, "error {} {}", subvolume.name, e.trim_end()),
+                };
+            }
+        }
+        ControlRequest::Nearest(name, time) => {
+            let time = time.to_zoned(TimeZone::system());
+            let Some(subvolume) = config.subvolumes.iter().find(|x| x.name == name) else {
+                let _ = writeln!(stream, "error No subvolume {}.", name);
+                return;
+            };
+
+            let _ = match matching_snapshots(config, subvolume) {
+                Ok(x) => match nearest_snapshot(&x, &time) {
+                    Some(x) => writeln!(
+                        stream,
+                        "ok {} {} {}",
+                        subvolume.name,
+                        x.snapshot_path.to_string_lossy(),
+                        x.time
+                    ),
+                    None => writeln!(
+                        stream,
+                        "error {} has no snapshot at or before {}.",
+                        subvolume.name, time
+                    ),
+                },
+                Err(e) => writeln!(
+                    stream,
+                    "error Error reading snapshots in {}. {}",
+                    subvolume.snapshot_path.to_string_lossy(),
+                    e
+                ),
+            };
+        }
+        ControlRequest::ValidateConfig(path) => {
+            let _ = match init::read_config(&path) {
+                Ok(_) => writeln!(
+                    stream,
+                    "ok Config file {} is valid.",
+                    path.to_string_lossy()
+                ),
+                // Such as the suggestion after an unknown key.
+                Err(e) => e.lines().try_for_each(|x| writeln!(stream, "error {}", x)),
+            };
+        }
+    }
+}
+
+/// Sends `request` to the daemon listening on `path`, printing its answers. Returns whether every
+/// answer was ok.
+pub fn send_request(path: &Path, request: &str) -> Result<bool, String> {
+    let mut stream = UnixStream::connect(path).map_err(|e| {
+        format!(
+            "Error connecting to the daemon at {}, is it running? {}",
+            path.to_string_lossy(),
+            e
+        )
+    })?;
+    writeln!(stream, "{}", request).map_err(|e| e.to_string())?;
+
+    let mut all_ok = true;
+    for line in BufReader::new(stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        match line.split_once(' ') {
+            Some(("ok", x)) => println!("{}", x),
+            Some(("error", x)) => {
+                eprintln!("{}", x);
+                all_ok = false;
+            }
+            _ => println!("{}", line),
+        }
+    }
+
+    Ok(all_ok)
+}
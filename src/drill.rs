@@ -0,0 +1,188 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{Config, RestoreDrillConfig, delete_subvolume, matching_snapshots, read_only};
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+/// Restores a random recent snapshot into the scratch subvolume and checks a sample of its files
+/// against the snapshot, proving the snapshot can actually be restored from.
+pub fn restore_drill(config: &Config, drill_config: &RestoreDrillConfig) -> Result<(), String> {
+    let span = tracing::info_span!("restore_drill");
+    let _span_guard = span.entered();
+
+    let subvolume = &config.subvolumes[random_index(config.subvolumes.len())];
+    let snapshots = matching_snapshots(config, subvolume).map_err(|e| e.to_string())?;
+    let recent_snapshots = &snapshots[snapshots
+        .len()
+        .saturating_sub(drill_config.recent_snapshots)..];
+    if recent_snapshots.is_empty() {
+        return Err("No snapshots available to restore.".to_string());
+    }
+    let snapshot = &recent_snapshots[random_index(recent_snapshots.len())];
+    let scratch_path = drill_config.scratch_path.as_path();
+
+    if read_only() {
+        tracing::info!(
+            "Read-only mode, verifying snapshot {} in place instead of restoring it.",
+            snapshot.snapshot_path.to_string_lossy()
+        );
+        // Without a restored copy to compare against, reading the sample back proves the files
+        // are at least readable.
+        let verified = verify_sample(
+            snapshot.snapshot_path.as_path(),
+            snapshot.snapshot_path.as_path(),
+            drill_config.sample_files,
+        )?;
+        tracing::info!(
+            "Verified {} files readable in {}.",
+            verified,
+            snapshot.snapshot_path.to_string_lossy()
+        );
+        return Ok(());
+    }
+
+    tracing::info!(
+        "Restoring snapshot {} into {}.",
+        snapshot.snapshot_path.to_string_lossy(),
+        scratch_path.to_string_lossy()
+    );
+
+    if subvolume.backend.is_subvolume(scratch_path)? {
+        tracing::info!("Removing leftover scratch subvolume.");
+        delete_subvolume(&*subvolume.backend, scratch_path)?;
+    }
+    subvolume
+        .backend
+        .create(&snapshot.snapshot_path, scratch_path, false)?;
+
+    let result = verify_sample(
+        snapshot.snapshot_path.as_path(),
+        scratch_path,
+        drill_config.sample_files,
+    );
+
+    if let Err(e) = delete_subvolume(&*subvolume.backend, scratch_path) {
+        tracing::error!("Error removing scratch subvolume. {}", e);
+    }
+
+    let verified = result?;
+    tracing::info!(
+        "Verified {} files restored from {}.",
+        verified,
+        snapshot.snapshot_path.to_string_lossy()
+    );
+
+    Ok(())
+}
+
+/// Compares a random sample of the regular files in `restored` against the same files in
+/// `original`, returning the number of files verified.
+fn verify_sample(original: &Path, restored: &Path, sample_files: usize) -> Result<usize, String> {
+    let mut sample = Vec::with_capacity(sample_files);
+    let mut seen = 0;
+
+    sample_files_in(restored, sample_files, &mut sample, &mut seen)
+        .map_err(|e| format!("Error walking restored subvolume. {}", e))?;
+
+    for restored_file in sample.iter() {
+        let relative_path = restored_file
+            .strip_prefix(restored)
+            .expect("Sampled file should be inside the restored subvolume.");
+        let original_file = original.join(relative_path);
+
+        match files_match(original_file.as_path(), restored_file.as_path()) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(format!(
+                    "Restored file {} does not match the snapshot.",
+                    relative_path.to_string_lossy()
+                ));
+            }
+            Err(e) => {
+                return Err(format!(
+                    "Error comparing restored file {}. {}",
+                    relative_path.to_string_lossy(),
+                    e
+                ));
+            }
+        }
+    }
+
+    Ok(sample.len())
+}
+
+/// Reservoir samples up to `sample_files` regular files below `dir` into `sample`.
+fn sample_files_in(
+    dir: &Path,
+    sample_files: usize,
+    sample: &mut Vec<PathBuf>,
+    seen: &mut usize,
+) -> io::Result<()> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            sample_files_in(entry.path().as_path(), sample_files, sample, seen)?;
+        } else if file_type.is_file() {
+            *seen += 1;
+            if sample.len() < sample_files {
+                sample.push(entry.path());
+            } else {
+                let index = random_index(*seen);
+                if index < sample_files {
+                    sample[index] = entry.path();
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn files_match(original: &Path, restored: &Path) -> io::Result<bool> {
+    let mut original_file = File::open(original)?;
+    let mut restored_file = File::open(restored)?;
+
+    if original_file.metadata()?.len() != restored_file.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut original_buffer = vec![0; 64 * 1024];
+    let mut restored_buffer = vec![0; 64 * 1024];
+    loop {
+        let original_read = read_chunk(&mut original_file, &mut original_buffer)?;
+        let restored_read = read_chunk(&mut restored_file, &mut restored_buffer)?;
+
+        if original_buffer[..original_read] != restored_buffer[..restored_read] {
+            return Ok(false);
+        }
+        if original_read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fills as much of `buffer` as possible, only returning less than its length at end of file.
+fn read_chunk(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            x => filled += x,
+        }
+    }
+
+    Ok(filled)
+}
+
+fn random_index(len: usize) -> usize {
+    RandomState::new().build_hasher().finish() as usize % len
+}
@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::Event;
+use jiff::Zoned;
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, SendError, Sender},
+    },
+};
+
+/// Sends events to the main loop, waking it through an eventfd so it can block in epoll on the
+/// schedule and every event source at once.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: Sender<Event>,
+    eventfd: Arc<OwnedFd>,
+}
+
+/// The main loop's end of the event channel, with the timer it waits for the schedule on.
+pub struct EventReceiver {
+    receiver: Receiver<Event>,
+    eventfd: Arc<OwnedFd>,
+    timerfd: OwnedFd,
+    epoll: OwnedFd,
+}
+
+pub fn channel() -> io::Result<(EventSender, EventReceiver)> {
+    let (sender, receiver) = mpsc::channel();
+    // SAFETY: eventfd, timerfd_create and epoll_create1 have no preconditions, and each returned
+    // descriptor is checked before being owned.
+    let eventfd = Arc::new(unsafe { owned_fd(libc::eventfd(0, libc::EFD_CLOEXEC))? });
+    let timerfd = unsafe {
+        owned_fd(libc::timerfd_create(
+            libc::CLOCK_REALTIME,
+            libc::TFD_CLOEXEC,
+        ))?
+    };
+    let epoll = unsafe { owned_fd(libc::epoll_create1(libc::EPOLL_CLOEXEC))? };
+
+    for fd in [eventfd.as_raw_fd(), timerfd.as_raw_fd()] {
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: fd as u64,
+        };
+        // SAFETY: Both descriptors are open and event points to a valid epoll_event.
+        if unsafe { libc::epoll_ctl(epoll.as_raw_fd(), libc::EPOLL_CTL_ADD, fd, &mut event) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok((
+        EventSender {
+            sender,
+            eventfd: eventfd.clone(),
+        },
+        EventReceiver {
+            receiver,
+            eventfd,
+            timerfd,
+            epoll,
+        },
+    ))
+}
+
+impl EventSender {
+    pub fn send(&self, event: Event) -> Result<(), SendError<Event>> {
+        self.sender.send(event)?;
+
+        // SAFETY: The eventfd is open for as long as self and the buffer is the 8 bytes eventfd
+        // expects. A failed write only means the counter is already saturated, so it still wakes.
+        unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                (&1u64 as *const u64).cast(),
+                size_of::<u64>(),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl EventReceiver {
+    /// Waits until the wall clock reaches `next_time`, returning early with any event received
+    /// in the meantime. The timer is absolute on the realtime clock, so it still fires on time
+    /// after a suspend, and is cancelled and re-armed whenever the clock is set.
+    pub fn wait_until(&self, next_time: &Zoned) -> Option<Event> {
+        if let Ok(x) = self.receiver.try_recv() {
+            return Some(x);
+        }
+        if &Zoned::now() >= next_time {
+            return None;
+        }
+        tracing::info!(
+            "Sleeping for {} seconds until {}.",
+            Zoned::now().duration_until(next_time).as_secs_f64(),
+            next_time
+        );
+
+        loop {
+            if let Err(e) = self.arm_timer(next_time) {
+                tracing::error!("Error arming timer, sleeping until {}. {}", next_time, e);
+                std::thread::sleep(Zoned::now().duration_until(next_time).unsigned_abs());
+                return None;
+            }
+
+            let mut ready = [libc::epoll_event { events: 0, u64: 0 }; 2];
+            // SAFETY: The epoll descriptor is open and ready has room for the 2 events passed.
+            let count =
+                unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), ready.as_mut_ptr(), 2, -1) };
+            if count < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    tracing::error!("Error waiting for events. {}", e);
+                }
+                continue;
+            }
+
+            for event in ready[..count as usize].iter() {
+                if event.u64 == self.eventfd.as_raw_fd() as u64 {
+                    // Only resets the counter, the channel holds the events themselves.
+                    let _ = read_counter(&self.eventfd);
+                    if let Ok(x) = self.receiver.try_recv() {
+                        return Some(x);
+                    }
+                } else if read_counter(&self.timerfd).is_err() {
+                    // ECANCELED, the clock was set while the timer was armed.
+                    tracing::warn!(
+                        "Clock changed while sleeping, rescheduling for {}.",
+                        next_time
+                    );
+                }
+            }
+
+            if &Zoned::now() >= next_time {
+                return None;
+            }
+        }
+    }
+
+    fn arm_timer(&self, next_time: &Zoned) -> io::Result<()> {
+        let timestamp = next_time.timestamp();
+        let timer = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: timestamp.as_second(),
+                tv_nsec: timestamp.subsec_nanosecond().into(),
+            },
+        };
+
+        // SAFETY: The timer descriptor is open and timer points to a valid itimerspec.
+        let result = unsafe {
+            libc::timerfd_settime(
+                self.timerfd.as_raw_fd(),
+                libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET,
+                &timer,
+                std::ptr::null_mut(),
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+/// Takes ownership of `fd`, or returns the error a negative result stands for.
+///
+/// # Safety
+///
+/// `fd` must be a descriptor nothing else owns, or negative.
+unsafe fn owned_fd(fd: libc::c_int) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        // SAFETY: Guaranteed by the caller.
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// Reads and resets the counter of an eventfd or timerfd.
+fn read_counter(fd: &OwnedFd) -> io::Result<()> {
+    let mut counter = 0u64;
+    // SAFETY: The descriptor is open and counter is the 8 bytes both descriptor types expect.
+    let result = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            (&mut counter as *mut u64).cast(),
+            size_of::<u64>(),
+        )
+    };
+
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, SubvolumeConfig,
+    state::{GroupSnapshot, State},
+};
+use jiff::Zoned;
+use std::path::Path;
+
+/// Subvolumes snapshotted back-to-back under the same time each cycle, such as / and /home, so
+/// their snapshots make one restore point for `list --groups` and `rollback --group`.
+pub struct SnapshotGroup {
+    pub name: String,
+    /// The names of the member subvolumes, in the order they are snapshotted.
+    pub subvolumes: Vec<String>,
+}
+
+/// Returns the index and group `subvolume` is a member of, if any.
+pub fn group_of<'a>(
+    config: &'a Config,
+    subvolume: &SubvolumeConfig,
+) -> Option<(usize, &'a SnapshotGroup)> {
+    config
+        .groups
+        .iter()
+        .enumerate()
+        .find(|(_, x)| x.subvolumes.contains(&subvolume.name))
+}
+
+/// Whether every member's snapshot in `set` still exists, so it can be restored as a whole.
+pub fn complete(group: &SnapshotGroup, set: &GroupSnapshot) -> bool {
+    group
+        .subvolumes
+        .iter()
+        .all(|x| set.snapshots.get(x).is_some_and(|x| Path::new(x).exists()))
+}
+
+/// Returns the recorded sets of `group`, oldest first, leaving out any with an unreadable time.
+pub fn group_snapshots(state: &State, group: &SnapshotGroup) -> Vec<(Zoned, GroupSnapshot)> {
+    state
+        .groups
+        .get(&group.name)
+        .into_iter()
+        .flatten()
+        .filter_map(|x| Some((x.time.parse::<Zoned>().ok()?, x.clone())))
+        .collect()
+}
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{HooksConfig, SubvolumeConfig, read_only};
+use std::process::Command;
+use tracing::info_span;
+
+/// The environment every hook for `subvolume` is run with.
+pub fn subvolume_env(subvolume: &SubvolumeConfig) -> Vec<(&'static str, String)> {
+    vec![
+        ("SNAPSHOTTER_SUBVOLUME", subvolume.name.clone()),
+        (
+            "SNAPSHOTTER_SUBVOLUME_PATH",
+            subvolume.path.to_string_lossy().to_string(),
+        ),
+    ]
+}
+
+/// Runs the `hook` command through `sh -c` with `env` added to its environment, returning an
+/// error if it can't be run or exits unsuccessfully. Does nothing if the hook isn't configured.
+pub fn run_hook(hook: &str, command: Option<&str>, env: &[(&str, String)]) -> Result<(), String> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+    let span = info_span!("run_hook", hook);
+    let _span_guard = span.entered();
+
+    // Hooks are free to change anything, so they can't be trusted to respect read-only mode.
+    if read_only() {
+        tracing::info!("Read-only mode, not running {} hook.", hook);
+        return Ok(());
+    }
+    tracing::info!("Running {} hook.", hook);
+
+    let output = match Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("SNAPSHOTTER_HOOK", hook)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .output()
+    {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error running {} hook. {}", hook, e)),
+    };
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The {} hook failed with {}. Output: {}",
+            hook,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))
+    }
+}
+
+/// Logs `error` and runs the on_error hook with it in `SNAPSHOTTER_ERROR`.
+pub fn report_error(hooks: &HooksConfig, env: &[(&str, String)], error: &str) {
+    tracing::error!("{}", error);
+
+    let mut env = env.to_vec();
+    env.push(("SNAPSHOTTER_ERROR", error.to_string()));
+    if let Err(e) = run_hook("on_error", hooks.on_error.as_deref(), &env) {
+        tracing::error!("{}", e);
+    }
+}
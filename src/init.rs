@@ -1,7 +1,39 @@
-use crate::Config;
-use jiff::Zoned;
-use serde::Deserialize;
-use std::{path::PathBuf, process::exit};
+use crate::{
+    BtrfsCommand, Compression, Config, Encryption, Event, FailurePolicy, HooksConfig,
+    ReplicationConfig, ReplicationStore, RestoreDrillConfig, SubvolumeConfig, control,
+    events::{self, EventReceiver, EventSender},
+    group::SnapshotGroup,
+    ioctl, nested,
+    origin::Origin,
+    retention::{RetentionPolicy, RetentionTier, TierPeriod},
+    schedule::{CalendarSchedule, DstPolicy, Timing},
+    schema::{self, KeyType},
+    trigger,
+};
+use inotify::{Inotify, WatchMask};
+use jiff::{Span, ToSpan, Zoned, civil::Weekday, tz::TimeZone};
+use serde::{
+    Deserialize, Deserializer,
+    de::{
+        MapAccess, SeqAccess, Visitor,
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+    },
+};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    thread,
+};
+use toml::{Table, Value};
 use tracing_appender::{non_blocking::WorkerGuard, rolling::Rotation};
 use tracing_subscriber::{
     filter,
@@ -17,43 +49,258 @@ impl FormatTime for JiffLocal {
     }
 }
 
+const CONFIG_FILE_PATH: &str = "/etc/btrfs-snapshotter/config.toml";
+const DROP_IN_DIR_NAME: &str = "conf.d";
+const ENV_PREFIX: &str = "BTRFS_SNAPSHOTTER_";
+
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct TempConfig {
     minutes: Option<i8>,
+    interval: Option<String>,
+    schedule: Option<String>,
+    dst_policy: Option<String>,
+    stagger_window: Option<String>,
+    stagger_jitter: Option<String>,
+    stagger_spacing: Option<String>,
+    catch_up: Option<bool>,
+    skip_unchanged: Option<bool>,
+    skip_on_battery: Option<bool>,
+    defer_io_pressure: Option<f64>,
+    defer_load_average: Option<f64>,
+    defer_max: Option<String>,
+    failure_policy: Option<String>,
+    failure_limit: Option<u32>,
+    failure_backoff_max: Option<String>,
+    state_path: Option<PathBuf>,
+    control_socket: Option<PathBuf>,
+    blackout: Option<Vec<String>>,
+    snapshot_on_stop: Option<bool>,
+    stop_timeout: Option<String>,
+    min_interval: Option<String>,
+    name_template: Option<String>,
+    timestamp_timezone: Option<String>,
+    ignore_patterns: Option<Vec<String>>,
+    trigger_paths: Option<Vec<PathBuf>>,
+    trigger_limit: Option<usize>,
+    trigger_keep_last: Option<usize>,
+    trigger_snapshot_path: Option<PathBuf>,
     subvolume_path: Option<PathBuf>,
     subvolume_name: Option<String>,
     snapshot_path: Option<PathBuf>,
+    subvolume: Option<Vec<TempSubvolumeConfig>>,
+    group: Option<Vec<TempGroupConfig>>,
+    frequent_limit: Option<usize>,
     hourly_limit: Option<usize>,
+    daily_limit: Option<usize>,
+    weekly_limit: Option<usize>,
+    week_start: Option<String>,
+    monthly_limit: Option<usize>,
+    yearly_limit: Option<usize>,
+    keep_last: Option<usize>,
+    keep_within: Option<String>,
+    immutable_for: Option<String>,
+    max_age: Option<String>,
+    space_budget_bytes: Option<u64>,
+    tiers: Option<Vec<TempRetentionTier>>,
+    policy: Option<BTreeMap<String, TempRetentionPolicy>>,
+    origin_policy: Option<BTreeMap<String, String>>,
+    min_keep: Option<usize>,
+    trash_grace: Option<String>,
+    clone_max_age: Option<String>,
+    min_free_bytes: Option<u64>,
+    min_unallocated_bytes: Option<u64>,
+    min_metadata_headroom_bytes: Option<u64>,
+    min_free_percent: Option<u8>,
+    fill_horizon: Option<String>,
+    max_deletions_per_cycle: Option<usize>,
+    deletion_sync_timeout: Option<String>,
+    metrics_path: Option<PathBuf>,
+    restore_drill: Option<TempRestoreDrillConfig>,
+    hooks: Option<TempHooksConfig>,
+    replication: Option<TempReplicationTargets>,
+    btrfs_path: Option<PathBuf>,
+    command_wrapper: Option<Vec<String>>,
+    command_timeout: Option<String>,
+    host_prefix: Option<PathBuf>,
+    log_dir: Option<PathBuf>,
+    log_max_age: Option<String>,
+    file_log_level: Option<String>,
+    stdout_log_level: Option<String>,
 }
 
-pub fn init_logging() -> WorkerGuard {
-    let rolling_appender = match tracing_appender::rolling::RollingFileAppender::builder()
-        .rotation(Rotation::NEVER)
-        .filename_prefix("btrfs-snapshotter")
-        .filename_suffix("log")
-        .build("/var/log")
-    {
-        Ok(x) => x,
-        Err(e) => {
-            eprintln!("Error initialising logger. tracing message: {}", e);
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempRetentionPolicy {
+    frequent_limit: Option<usize>,
+    hourly_limit: Option<usize>,
+    daily_limit: Option<usize>,
+    weekly_limit: Option<usize>,
+    week_start: Option<String>,
+    monthly_limit: Option<usize>,
+    yearly_limit: Option<usize>,
+    keep_last: Option<usize>,
+    keep_within: Option<String>,
+    immutable_for: Option<String>,
+    max_age: Option<String>,
+    space_budget_bytes: Option<u64>,
+    tiers: Option<Vec<TempRetentionTier>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempRetentionTier {
+    period: String,
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempSubvolumeConfig {
+    path: PathBuf,
+    name: String,
+    enabled: Option<bool>,
+    snapshot_path: Option<PathBuf>,
+    trigger_snapshot_path: Option<PathBuf>,
+    minutes: Option<i8>,
+    interval: Option<String>,
+    schedule: Option<String>,
+    stagger: Option<String>,
+    readonly: Option<bool>,
+    include_nested: Option<bool>,
+    btrfs_path: Option<PathBuf>,
+    command_wrapper: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempGroupConfig {
+    name: String,
+    subvolumes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempHooksConfig {
+    pre_snapshot: Option<String>,
+    post_snapshot: Option<String>,
+    pre_prune: Option<String>,
+    post_prune: Option<String>,
+    on_error: Option<String>,
+}
+
+/// A single `[replication]` target, or several as `[[replication]]`.
+enum TempReplicationTargets {
+    One(Box<TempReplicationConfig>),
+    Many(Vec<TempReplicationConfig>),
+}
+
+// By hand rather than untagged, so errors inside a target are reported as they are for any other
+// table.
+impl<'de> Deserialize<'de> for TempReplicationTargets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TargetsVisitor;
+
+        impl<'de> Visitor<'de> for TargetsVisitor {
+            type Value = TempReplicationTargets;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a [replication] table or [[replication]] tables")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                TempReplicationConfig::deserialize(MapAccessDeserializer::new(map))
+                    .map(|x| TempReplicationTargets::One(Box::new(x)))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(TempReplicationTargets::Many)
+            }
+        }
+
+        deserializer.deserialize_any(TargetsVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempReplicationConfig {
+    name: Option<String>,
+    host: String,
+    path: PathBuf,
+    ssh_options: Option<Vec<String>>,
+    interval: Option<String>,
+    keep_last: Option<usize>,
+    policy: Option<String>,
+    compression: Option<String>,
+    store: Option<String>,
+    age_recipients: Option<Vec<String>>,
+    gpg_recipients: Option<Vec<String>>,
+    full_send_interval: Option<String>,
+    bandwidth_limit_bytes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TempRestoreDrillConfig {
+    interval: Option<String>,
+    scratch_path: Option<PathBuf>,
+    recent_snapshots: Option<usize>,
+    sample_files: Option<usize>,
+}
+
+/// Logs to stdout and, unless its level is off, a file in the configured log directory. The
+/// returned guard must be kept alive for the file to be written.
+pub fn init_logging(config: &Config) -> Option<WorkerGuard> {
+    let (logfile_layer, guard) = if config.file_log_level == filter::LevelFilter::OFF {
+        (None, None)
+    } else {
+        if let Err(e) = fs::create_dir_all(&config.log_dir) {
+            eprintln!(
+                "Error creating log directory: {} | Error: {}",
+                config.log_dir.to_string_lossy(),
+                e
+            );
             exit(1);
         }
-    };
+        let mut builder = tracing_appender::rolling::RollingFileAppender::builder()
+            .rotation(Rotation::NEVER)
+            .filename_prefix("btrfs-snapshotter")
+            .filename_suffix("log");
+        // Rotating daily and keeping a file per day bounds the log to log_max_age.
+        if let Some(x) = config.log_max_age {
+            let seconds = x
+                .to_duration(&Zoned::now())
+                .map_or(i64::MAX, |x| x.as_secs());
+            builder = builder
+                .rotation(Rotation::DAILY)
+                .max_log_files((seconds / 86400 + 1).try_into().unwrap_or(usize::MAX));
+        }
+        let rolling_appender = match builder.build(config.log_dir.as_path()) {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Error initialising logger. tracing message: {}", e);
+                exit(1);
+            }
+        };
 
-    let (file_writer, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
-        .lossy(false)
-        .finish(rolling_appender);
-    let logfile_layer = fmt::Layer::default()
-        .with_ansi(false)
-        .with_writer(file_writer)
-        .with_timer(JiffLocal)
-        .with_filter(filter::LevelFilter::INFO);
+        let (file_writer, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
+            .lossy(false)
+            .finish(rolling_appender);
+        let logfile_layer = fmt::Layer::default()
+            .with_ansi(false)
+            .with_writer(file_writer)
+            .with_timer(JiffLocal)
+            .with_filter(config.file_log_level);
+
+        (Some(logfile_layer), Some(guard))
+    };
     let stdout_layer = fmt::Layer::default()
         .with_writer(std::io::stdout)
         .with_ansi(true)
         .event_format(format().compact())
         .with_timer(JiffLocal)
-        .with_filter(filter::LevelFilter::INFO);
+        .with_filter(config.stdout_log_level);
     let subscriber = tracing_subscriber::Registry::default()
         .with(logfile_layer)
         .with(stdout_layer);
@@ -66,43 +313,1209 @@ pub fn init_logging() -> WorkerGuard {
     guard
 }
 
-pub fn load_config() -> Config {
-    let config_file_path = "/etc/btrfs-snapshotter/config.toml";
-    let config_file = match std::fs::read(config_file_path) {
+/// Forwards signals handled by the daemon, changes to the config file and `config`'s trigger
+/// paths, and control socket requests as events on the returned channel. Trigger paths and the
+/// control socket are only read here, so changing them needs a restart.
+pub fn init_events(config: &Config) -> EventReceiver {
+    let (sender, receiver) = match events::channel() {
         Ok(x) => x,
         Err(e) => {
-            eprintln!(
-                "Error loading config file: {} | Error: {}",
-                config_file_path, e
-            );
-            std::process::exit(1);
+            eprintln!("Error creating event loop. {}", e);
+            exit(1);
         }
     };
 
-    let temp_config: TempConfig = match toml::from_slice(config_file.as_slice()) {
+    if let Err(e) = watch_config_file(sender.clone()) {
+        tracing::error!(
+            "Error watching config file, reload with SIGHUP instead. {}",
+            e
+        );
+    }
+    if let Err(e) = trigger::watch_trigger_paths(&config.trigger_paths, sender.clone()) {
+        tracing::error!("Error watching trigger paths. {}", e);
+    }
+    if let Err(e) = control::listen(&config.control_socket, sender.clone()) {
+        tracing::error!(
+            "Error listening on control socket {}. {}",
+            config.control_socket.to_string_lossy(),
+            e
+        );
+    }
+    let mut signals = match Signals::new([SIGHUP, SIGINT, SIGTERM]) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Error registering signal handlers. {}", e);
+            exit(1);
+        }
+    };
+
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = match signal {
+                SIGHUP => Event::ReloadConfig,
+                SIGINT | SIGTERM => Event::Stop,
+                _ => continue,
+            };
+
+            if sender.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// Watches the config file's directory so editors that replace the file are also noticed, along
+/// with the drop-in directory if it exists.
+fn watch_config_file(sender: EventSender) -> io::Result<()> {
+    let config_file_path = config_file_path();
+    let config_dir = config_file_path
+        .parent()
+        .expect("Config file path should have a parent directory.");
+    let config_file_name = config_file_path
+        .file_name()
+        .expect("Config file path should have a file name.")
+        .to_owned();
+    let mut inotify = Inotify::init()?;
+
+    let config_dir_watch = inotify
+        .watches()
+        .add(config_dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+    let drop_in_dir = config_dir.join(DROP_IN_DIR_NAME);
+    let drop_in_dir_watch = if drop_in_dir.is_dir() {
+        Some(inotify.watches().add(
+            drop_in_dir,
+            WatchMask::CLOSE_WRITE
+                | WatchMask::MOVED_TO
+                | WatchMask::MOVED_FROM
+                | WatchMask::DELETE,
+        )?)
+    } else {
+        None
+    };
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+
+        loop {
+            let mut events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::error!("Error reading config file changes. {}", e);
+                    break;
+                }
+            };
+
+            if events.any(|x| {
+                (x.wd == config_dir_watch && x.name == Some(config_file_name.as_os_str()))
+                    || (Some(&x.wd) == drop_in_dir_watch.as_ref()
+                        && x.name.is_some_and(is_drop_in_file))
+            }) {
+                tracing::info!("Config file changed.");
+                if sender.send(Event::ReloadConfig).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The config file the daemon reads, under XDG_CONFIG_HOME when not run as root.
+pub fn config_file_path() -> PathBuf {
+    match user_dir("XDG_CONFIG_HOME", ".config") {
+        Some(x) => x.join("btrfs-snapshotter").join("config.toml"),
+        None => PathBuf::from(CONFIG_FILE_PATH),
+    }
+}
+
+/// The default log directory, under XDG_STATE_HOME when not run as root.
+pub fn default_log_dir() -> PathBuf {
+    match user_dir("XDG_STATE_HOME", ".local/state") {
+        Some(x) => x.join("btrfs-snapshotter"),
+        None => PathBuf::from("/var/log"),
+    }
+}
+
+/// The default state file, under XDG_STATE_HOME when not run as root.
+pub fn default_state_path() -> PathBuf {
+    match user_dir("XDG_STATE_HOME", ".local/state") {
+        Some(x) => x.join("btrfs-snapshotter").join("state.toml"),
+        None => PathBuf::from("/var/lib/btrfs-snapshotter/state.toml"),
+    }
+}
+
+/// The default control socket, under XDG_RUNTIME_DIR when not run as root.
+pub fn default_control_socket() -> PathBuf {
+    match user_dir("XDG_RUNTIME_DIR", ".local/state") {
+        Some(x) => x.join("btrfs-snapshotter").join("control.sock"),
+        None => PathBuf::from("/run/btrfs-snapshotter/control.sock"),
+    }
+}
+
+/// Returns the XDG base directory in `variable`, or `fallback` in the home directory if it is
+/// unset, when not running as root.
+fn user_dir(variable: &str, fallback: &str) -> Option<PathBuf> {
+    // SAFETY: geteuid is always successful and has no preconditions.
+    if unsafe { libc::geteuid() } == 0 {
+        return None;
+    }
+
+    // The XDG base directory spec says relative paths are invalid and should be ignored.
+    match std::env::var_os(variable) {
+        Some(x) if Path::new(&x).is_absolute() => Some(PathBuf::from(x)),
+        _ => std::env::var_os("HOME").map(|x| PathBuf::from(x).join(fallback)),
+    }
+}
+
+pub fn load_config() -> Config {
+    match read_config(config_file_path().as_path()) {
         Ok(x) => x,
         Err(e) => {
             eprintln!("{}", e);
             std::process::exit(1);
         }
+    }
+}
+
+/// Reads, parses and validates the config file at `config_file_path`.
+pub fn read_config(config_file_path: &Path) -> Result<Config, String> {
+    let mut env_overrides: Vec<(String, String)> = Vec::new();
+    for (key, value) in std::env::vars_os() {
+        // Unrelated variables may hold anything.
+        let Some(key) = key.to_str().filter(|x| x.starts_with(ENV_PREFIX)) else {
+            continue;
+        };
+        let Some(value) = value.to_str() else {
+            return Err(format!("Env override {} isn't valid UTF-8.", key));
+        };
+        env_overrides.push((key.to_string(), value.to_string()));
+    }
+    let mut config_table: Table = match std::fs::read(config_file_path) {
+        Ok(x) => toml::from_slice(x.as_slice()).map_err(|e| e.to_string())?,
+        // Containers may be configured entirely through the environment.
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !env_overrides.is_empty() => Table::new(),
+        Err(e) => {
+            return Err(format!(
+                "Error loading config file: {} | Error: {}",
+                config_file_path.to_string_lossy(),
+                e
+            ));
+        }
     };
 
+    if let Some(x) = config_file_path.parent() {
+        for drop_in in drop_in_files(&x.join(DROP_IN_DIR_NAME))? {
+            let drop_in_file = std::fs::read(&drop_in).map_err(|e| {
+                format!(
+                    "Error loading config drop-in: {} | Error: {}",
+                    drop_in.to_string_lossy(),
+                    e
+                )
+            })?;
+            let drop_in_table: Table = toml::from_slice(drop_in_file.as_slice())
+                .map_err(|e| format!("{}: {}", drop_in.to_string_lossy(), e))?;
+
+            merge_tables(&mut config_table, drop_in_table);
+        }
+    }
+    for (key, value) in env_overrides.iter() {
+        apply_env_override(&mut config_table, key, value)?;
+    }
+    let temp_config: TempConfig =
+        Value::Table(config_table)
+            .try_into()
+            .map_err(|e: toml::de::Error| match suggest_key(e.message()) {
+                Some(x) => format!("{}\nDid you mean `{}`?", e.to_string().trim_end(), x),
+                None => e.to_string(),
+            })?;
+
     let mut config = Config::default();
     if let Some(x) = temp_config.minutes {
-        config.minutes = x;
+        config.timing.minutes = x;
+    }
+    if let Some(x) = temp_config.interval {
+        config.timing.interval = parse_interval("interval", &x)?;
+    }
+    if let Some(x) = temp_config.schedule {
+        config.timing.schedule = Some(parse_schedule(&x)?);
+    }
+    if let Some(x) = temp_config.dst_policy {
+        config.timing.dst_policy = x
+            .parse()
+            .map_err(|e| format!("Error parsing config dst_policy: {}", e))?;
+    }
+    if let Some(x) = temp_config.stagger_window {
+        config.stagger_window = Some(parse_interval("stagger_window", &x)?);
     }
-    if let Some(x) = temp_config.subvolume_path {
-        config.subvolume_path = x;
+    if let Some(x) = temp_config.stagger_jitter {
+        config.stagger_jitter = Some(parse_interval("stagger_jitter", &x)?);
     }
-    if let Some(x) = temp_config.subvolume_name {
-        config.subvolume_name = x;
+    if let Some(x) = temp_config.stagger_spacing {
+        config.stagger_spacing = Some(parse_interval("stagger_spacing", &x)?);
+    }
+    if let Some(x) = temp_config.catch_up {
+        config.catch_up = x;
+    }
+    if let Some(x) = temp_config.skip_unchanged {
+        config.skip_unchanged = x;
+    }
+    if let Some(x) = temp_config.skip_on_battery {
+        config.skip_on_battery = x;
+    }
+    if let Some(x) = temp_config.defer_io_pressure {
+        config.defer_io_pressure = Some(x);
+    }
+    if let Some(x) = temp_config.defer_load_average {
+        config.defer_load_average = Some(x);
+    }
+    if let Some(x) = temp_config.defer_max {
+        config.defer_max = parse_interval("defer_max", &x)?;
+    }
+    if let Some(x) = temp_config.failure_policy {
+        config.failure_policy = match x.to_lowercase().as_str() {
+            "retry" => FailurePolicy::Retry,
+            "backoff" => FailurePolicy::Backoff,
+            "stop" => FailurePolicy::Stop,
+            _ => {
+                return Err(format!(
+                    "Config failure_policy must be retry, backoff or stop: {}",
+                    x
+                ));
+            }
+        };
+    }
+    if let Some(x) = temp_config.failure_limit {
+        config.failure_limit = x;
+    }
+    if let Some(x) = temp_config.failure_backoff_max {
+        config.failure_backoff_max = parse_interval("failure_backoff_max", &x)?;
+    }
+    if let Some(x) = temp_config.state_path {
+        config.state_path = x;
+    }
+    if let Some(x) = temp_config.control_socket {
+        config.control_socket = x;
+    }
+    if let Some(x) = temp_config.blackout {
+        config.blackout = x
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<_, String>>()
+            .map_err(|e| format!("Error parsing config blackout. {}", e))?;
+    }
+    if let Some(x) = temp_config.snapshot_on_stop {
+        config.snapshot_on_stop = x;
+    }
+    if let Some(x) = temp_config.stop_timeout {
+        config.stop_timeout = parse_interval("stop_timeout", &x)?;
+    }
+    if let Some(x) = temp_config.min_interval {
+        config.min_interval = Some(parse_interval("min_interval", &x)?);
+    }
+    if let Some(x) = temp_config.name_template {
+        config.name_template = x.parse()?;
+    }
+    if let Some(x) = temp_config.timestamp_timezone {
+        config.timestamp_timezone = Some(TimeZone::get(&x).map_err(|e| {
+            format!(
+                "Error parsing config timestamp_timezone: {} | Error: {}",
+                x, e
+            )
+        })?);
+    }
+    if let Some(x) = temp_config.ignore_patterns {
+        config.ignore_patterns = x;
+    }
+    if let Some(x) = temp_config.trigger_paths {
+        config.trigger_paths = x;
+    }
+    if let Some(x) = temp_config.trigger_limit {
+        config.trigger_limit = x;
+    }
+    if let Some(x) = temp_config.trigger_keep_last {
+        config.trigger_keep_last = x;
     }
     if let Some(x) = temp_config.snapshot_path {
         config.snapshot_path = x;
     }
-    if let Some(x) = temp_config.hourly_limit {
-        config.hourly_limit = x;
+    let mut btrfs = BtrfsCommand::default();
+    if let Some(x) = temp_config.btrfs_path {
+        btrfs.path = x;
+    }
+    if let Some(x) = temp_config.command_wrapper {
+        btrfs.wrapper = x;
+    }
+    if let Some(x) = temp_config.command_timeout {
+        btrfs.timeout = match x.as_str() {
+            "off" => None,
+            _ => Some(parse_interval("command_timeout", &x)?),
+        };
+    }
+    match temp_config.subvolume {
+        Some(x) => {
+            if temp_config.subvolume_path.is_some() || temp_config.subvolume_name.is_some() {
+                return Err(
+                    "Config subvolume_path and subvolume_name can't be used with [[subvolume]] entries."
+                        .to_string(),
+                );
+            }
+
+            config.subvolumes = Vec::with_capacity(x.len());
+            // Disabled entries are dropped entirely, so they aren't validated either and may point
+            // at subvolumes that are currently missing.
+            for x in x.into_iter().filter(|x| x.enabled.unwrap_or(true)) {
+                let snapshot_path = x.snapshot_path.unwrap_or(config.snapshot_path.clone());
+                let subvolume_btrfs = BtrfsCommand {
+                    path: x.btrfs_path.unwrap_or(btrfs.path.clone()),
+                    wrapper: x.command_wrapper.unwrap_or(btrfs.wrapper.clone()),
+                    timeout: btrfs.timeout,
+                };
+
+                config.subvolumes.push(SubvolumeConfig {
+                    path: x.path,
+                    name: x.name,
+                    trigger_snapshot_path: x
+                        .trigger_snapshot_path
+                        .or(temp_config.trigger_snapshot_path.clone())
+                        .unwrap_or(snapshot_path.clone()),
+                    snapshot_path,
+                    // Setting any of them replaces the global timing entirely, so e.g. an
+                    // interval isn't overruled by a global schedule.
+                    timing: if x.minutes.is_some() || x.interval.is_some() || x.schedule.is_some() {
+                        Some(Timing {
+                            minutes: x.minutes.unwrap_or(config.timing.minutes),
+                            interval: match x.interval {
+                                Some(x) => parse_interval("subvolume.interval", &x)?,
+                                None => config.timing.interval,
+                            },
+                            schedule: match x.schedule {
+                                Some(x) => Some(parse_schedule(&x)?),
+                                None => None,
+                            },
+                            dst_policy: config.timing.dst_policy,
+                        })
+                    } else {
+                        None
+                    },
+                    stagger: match x.stagger {
+                        Some(x) => Some(parse_interval("subvolume.stagger", &x)?),
+                        None => None,
+                    },
+                    readonly: x.readonly.unwrap_or(true),
+                    include_nested: x.include_nested.unwrap_or(false),
+                    btrfs: subvolume_btrfs.clone(),
+                    backend: Arc::new(subvolume_btrfs),
+                });
+            }
+        }
+        None => {
+            let subvolume = config
+                .subvolumes
+                .first_mut()
+                .expect("Default config should have a subvolume.");
+
+            if let Some(x) = temp_config.subvolume_path {
+                subvolume.path = x;
+            }
+            if let Some(x) = temp_config.subvolume_name {
+                subvolume.name = x;
+            }
+            subvolume.snapshot_path = config.snapshot_path.clone();
+            subvolume.trigger_snapshot_path = temp_config
+                .trigger_snapshot_path
+                .unwrap_or(config.snapshot_path.clone());
+            subvolume.backend = Arc::new(btrfs.clone());
+            subvolume.btrfs = btrfs.clone();
+        }
+    }
+    config.groups = temp_config
+        .group
+        .unwrap_or_default()
+        .into_iter()
+        .map(|x| SnapshotGroup {
+            name: x.name,
+            subvolumes: x.subvolumes,
+        })
+        .collect();
+    config.retention = parse_retention_policy(
+        "",
+        TempRetentionPolicy {
+            frequent_limit: temp_config.frequent_limit,
+            hourly_limit: temp_config.hourly_limit,
+            daily_limit: temp_config.daily_limit,
+            weekly_limit: temp_config.weekly_limit,
+            week_start: temp_config.week_start,
+            monthly_limit: temp_config.monthly_limit,
+            yearly_limit: temp_config.yearly_limit,
+            keep_last: temp_config.keep_last,
+            keep_within: temp_config.keep_within,
+            immutable_for: temp_config.immutable_for,
+            max_age: temp_config.max_age,
+            space_budget_bytes: temp_config.space_budget_bytes,
+            tiers: temp_config.tiers,
+        },
+    )?;
+    for (name, x) in temp_config.policy.unwrap_or_default() {
+        let policy = parse_retention_policy(&format!("policy.{}.", name), x)?;
+        config.policies.insert(name, policy);
+    }
+    for (origin, policy) in temp_config.origin_policy.unwrap_or_default() {
+        let origin = origin
+            .parse()
+            .map_err(|e| format!("Error parsing config origin_policy: {}", e))?;
+        if origin == Origin::Post {
+            return Err(
+                "Config origin_policy can't set post, post snapshots are pruned with their pre snapshot."
+                    .to_string(),
+            );
+        }
+        if !config.policies.contains_key(&policy) {
+            return Err(format!(
+                "Config origin_policy names policy {}, but there is no [policy.{}].",
+                policy, policy
+            ));
+        }
+        config.origin_policies.insert(origin, policy);
+    }
+    if let Some(x) = temp_config.min_keep {
+        config.min_keep = x;
+    }
+    if let Some(x) = temp_config.trash_grace {
+        config.trash_grace = Some(parse_interval("trash_grace", &x)?);
+    }
+    if let Some(x) = temp_config.clone_max_age {
+        config.clone_max_age = Some(parse_interval("clone_max_age", &x)?);
     }
+    if let Some(x) = temp_config.min_free_bytes {
+        config.min_free_bytes = Some(x);
+    }
+    if let Some(x) = temp_config.min_unallocated_bytes {
+        config.min_unallocated_bytes = Some(x);
+    }
+    if let Some(x) = temp_config.min_metadata_headroom_bytes {
+        config.min_metadata_headroom_bytes = Some(x);
+    }
+    if let Some(x) = temp_config.min_free_percent {
+        config.min_free_percent = Some(x);
+    }
+    if let Some(x) = temp_config.fill_horizon {
+        config.fill_horizon = Some(parse_interval("fill_horizon", &x)?);
+    }
+    if let Some(x) = temp_config.max_deletions_per_cycle {
+        if x == 0 {
+            return Err("Config max_deletions_per_cycle must be positive: 0".to_string());
+        }
+        config.max_deletions_per_cycle = Some(x);
+    }
+    if let Some(x) = temp_config.deletion_sync_timeout {
+        config.deletion_sync_timeout = Some(parse_interval("deletion_sync_timeout", &x)?);
+    }
+    if let Some(x) = temp_config.metrics_path {
+        config.metrics_path = Some(x);
+    }
+    if let Some(x) = temp_config.restore_drill {
+        let mut drill_config = RestoreDrillConfig {
+            interval: 7.days(),
+            scratch_path: config.snapshot_path.join(".restore-drill"),
+            recent_snapshots: 24,
+            sample_files: 32,
+        };
+        if let Some(x) = x.interval {
+            drill_config.interval = parse_interval("restore_drill.interval", &x)?;
+        }
+        if let Some(x) = x.scratch_path {
+            drill_config.scratch_path = x;
+        }
+        if let Some(x) = x.recent_snapshots {
+            drill_config.recent_snapshots = x;
+        }
+        if let Some(x) = x.sample_files {
+            drill_config.sample_files = x;
+        }
+        config.restore_drill = Some(drill_config);
+    }
+    if let Some(x) = temp_config.hooks {
+        config.hooks = HooksConfig {
+            pre_snapshot: x.pre_snapshot,
+            post_snapshot: x.post_snapshot,
+            pre_prune: x.pre_prune,
+            post_prune: x.post_prune,
+            on_error: x.on_error,
+        };
+    }
+    let targets = match temp_config.replication {
+        Some(TempReplicationTargets::One(x)) => vec![*x],
+        Some(TempReplicationTargets::Many(x)) => x,
+        None => Vec::new(),
+    };
+    for x in targets {
+        config.replication.push(ReplicationConfig {
+            name: x.name.unwrap_or(x.host.clone()),
+            host: x.host,
+            path: x.path,
+            ssh_options: x.ssh_options.unwrap_or_default(),
+            interval: match x.interval {
+                Some(x) => Some(parse_interval("replication.interval", &x)?),
+                None => None,
+            },
+            keep_last: x.keep_last.unwrap_or(0),
+            policy: x.policy,
+            compression: match x.compression.as_deref() {
+                None | Some("none") => None,
+                Some("zstd") => Some(Compression::Zstd),
+                Some("compressed-data") => Some(Compression::CompressedData),
+                Some(x) => {
+                    return Err(format!(
+                        "Config replication.compression must be none, zstd or compressed-data: {}",
+                        x
+                    ));
+                }
+            },
+            store: match x.store.as_deref() {
+                None | Some("receive") => ReplicationStore::Receive,
+                Some("files") => ReplicationStore::Files,
+                Some(x) => {
+                    return Err(format!(
+                        "Config replication.store must be receive or files: {}",
+                        x
+                    ));
+                }
+            },
+            encryption: match (x.age_recipients, x.gpg_recipients) {
+                (Some(_), Some(_)) => {
+                    return Err(
+                        "Config replication can't set both age_recipients and gpg_recipients."
+                            .to_string(),
+                    );
+                }
+                (Some(x), None) => Some(Encryption::Age(x)),
+                (None, Some(x)) => Some(Encryption::Gpg(x)),
+                (None, None) => None,
+            },
+            full_send_interval: match x.full_send_interval {
+                Some(x) => parse_interval("replication.full_send_interval", &x)?,
+                None => 30.days(),
+            },
+            bandwidth_limit_bytes: match x.bandwidth_limit_bytes {
+                Some(0) => {
+                    return Err(
+                        "Config replication.bandwidth_limit_bytes must be positive: 0".to_string(),
+                    );
+                }
+                x => x,
+            },
+        });
+    }
+    if let Some(x) = temp_config.log_dir {
+        config.log_dir = x;
+    }
+    if let Some(x) = temp_config.log_max_age {
+        config.log_max_age = Some(parse_interval("log_max_age", &x)?);
+    }
+    if let Some(x) = temp_config.file_log_level {
+        config.file_log_level = parse_log_level("file_log_level", &x)?;
+    }
+    if let Some(x) = temp_config.stdout_log_level {
+        config.stdout_log_level = parse_log_level("stdout_log_level", &x)?;
+    }
+    if let Some(x) = temp_config.host_prefix {
+        apply_host_prefix(&mut config, &x);
+        config.host_prefix = Some(x);
+    }
+    config.btrfs = btrfs;
+
+    nested::include_nested(&mut config);
+    validate_config(&config)?;
+
+    Ok(config)
+}
+
+/// Moves every host path in `config` below `host_prefix`, where the host's root is mounted when
+/// running in a container. The metrics path is left alone as it belongs to the container.
+fn apply_host_prefix(config: &mut Config, host_prefix: &Path) {
+    let prefixed = |path: &Path| host_prefix.join(path.strip_prefix("/").unwrap_or(path));
+
+    config.snapshot_path = prefixed(&config.snapshot_path);
+    for subvolume in config.subvolumes.iter_mut() {
+        subvolume.path = prefixed(&subvolume.path);
+        subvolume.snapshot_path = prefixed(&subvolume.snapshot_path);
+        subvolume.trigger_snapshot_path = prefixed(&subvolume.trigger_snapshot_path);
+    }
+    if let Some(x) = &mut config.restore_drill {
+        x.scratch_path = prefixed(&x.scratch_path);
+    }
+    for path in config.trigger_paths.iter_mut() {
+        *path = prefixed(path);
+    }
+}
+
+/// Where the host path `path`, such as one given on the command line, is seen from here, below
+/// host_prefix when it is set.
+pub fn host_path(config: &Config, path: &Path) -> PathBuf {
+    match &config.host_prefix {
+        Some(x) => x.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Warns when running in a container that would snapshot its own filesystem rather than the
+/// host's, as neither host_prefix nor a command_wrapper reaching the host is configured.
+pub fn check_container(config: &Config) {
+    let in_container = Path::new("/.dockerenv").exists()
+        || Path::new("/run/.containerenv").exists()
+        || std::env::var_os("container").is_some()
+        || matches!(
+            (
+                fs::read_link("/proc/self/ns/mnt"),
+                fs::read_link("/proc/1/ns/mnt")
+            ),
+            (Ok(x), Ok(y)) if x != y
+        );
+
+    if !in_container {
+        return;
+    }
+    tracing::info!("Running in a container or separate mount namespace.");
+
+    if config.host_prefix.is_none() && config.subvolumes.iter().all(|x| x.btrfs.wrapper.is_empty())
+    {
+        tracing::warn!(
+            "Neither host_prefix nor command_wrapper is set, so snapshots are taken of the paths as seen inside the container."
+        );
+    }
+}
+
+/// Suggests the valid key closest to the unknown one in serde's unknown field error, so a typo
+/// such as `hourly_limt` points at `hourly_limit`.
+fn suggest_key(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (unknown_key, expected) = rest.split_once('`')?;
+
+    expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|x| (edit_distance(unknown_key, x), x))
+        .filter(|(distance, x)| *distance <= x.len().max(unknown_key.len()) / 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, x)| x)
+}
+
+/// Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Returns the drop-in config files in `drop_in_dir`, in the lexical order they are applied.
+fn drop_in_files(drop_in_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = match drop_in_dir.read_dir() {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(format!(
+                "Error reading config drop-in directory: {} | Error: {}",
+                drop_in_dir.to_string_lossy(),
+                e
+            ));
+        }
+    };
+    let mut drop_ins = Vec::new();
+
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+
+        if path.file_name().is_some_and(is_drop_in_file) && path.is_file() {
+            drop_ins.push(path);
+        }
+    }
+    drop_ins.sort();
+
+    Ok(drop_ins)
+}
+
+fn is_drop_in_file(file_name: &OsStr) -> bool {
+    let file_name = file_name.as_bytes();
+    file_name.ends_with(b".toml") && !file_name.starts_with(b".")
+}
+
+/// Merges `other` into `base`. Tables are merged key by key, arrays are appended so drop-ins can
+/// add `[[subvolume]]` entries, and any other value in `other` replaces the one in `base`.
+fn merge_tables(base: &mut Table, other: Table) {
+    for (key, value) in other {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(other)) => merge_tables(base, other),
+            (Some(Value::Array(base)), Value::Array(other)) => base.extend(other),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Sets the config key named by an environment variable such as `BTRFS_SNAPSHOTTER_HOURLY_LIMIT`.
+/// `__` separates nested keys, and numbers index into arrays, so
+/// `BTRFS_SNAPSHOTTER_SUBVOLUME__0__PATH` sets the first `[[subvolume]]` entry's path.
+fn apply_env_override(config_table: &mut Table, key: &str, value: &str) -> Result<(), String> {
+    let key_path = key[ENV_PREFIX.len()..].to_lowercase();
+    let key_path: Vec<&str> = key_path.split("__").collect();
+    // Values are read as the type the key takes, so a string such as "inf" or "true" stays one.
+    let value = match schema::key_type::<TempConfig>(&key_path) {
+        Some(KeyType::String) => Value::String(value.to_string()),
+        Some(KeyType::Bool) => match value {
+            "true" => Value::Boolean(true),
+            "false" => Value::Boolean(false),
+            _ => {
+                return Err(format!(
+                    "Env override {} must be true or false: {}",
+                    key, value
+                ));
+            }
+        },
+        Some(KeyType::Integer) => Value::Integer(
+            value
+                .parse()
+                .map_err(|_| format!("Env override {} must be an integer: {}", key, value))?,
+        ),
+        Some(KeyType::Float) => Value::Float(
+            value
+                .parse()
+                .map_err(|_| format!("Env override {} must be a number: {}", key, value))?,
+        ),
+        // Arrays and tables are written as TOML, e.g. ["-i", "/root/.ssh/replication"]. Unknown
+        // keys are left for the config's own error.
+        Some(KeyType::Other) | None => {
+            match toml::from_str::<Table>(&format!("value = {}", value)) {
+                Ok(mut x) => x
+                    .remove("value")
+                    .expect("Parsed table should contain value."),
+                Err(_) => Value::String(value.to_string()),
+            }
+        }
+    };
+
+    let mut target = config_table;
+    let mut i = 0;
+    loop {
+        if i == key_path.len() - 1 {
+            target.insert(key_path[i].to_string(), value);
+            return Ok(());
+        }
+
+        let next_is_index = key_path[i + 1].parse::<usize>().is_ok();
+        let next = target.entry(key_path[i].to_string()).or_insert_with(|| {
+            if next_is_index {
+                Value::Array(Vec::new())
+            } else {
+                Value::Table(Table::new())
+            }
+        });
+        i += 1;
+
+        let next = match (next, key_path[i].parse::<usize>()) {
+            (Value::Array(array), Ok(index)) => {
+                if index == array.len() {
+                    array.push(Value::Table(Table::new()));
+                }
+                i += 1;
 
-    config
+                if i == key_path.len() {
+                    return Err(format!(
+                        "Env override {} must name a key in the entry.",
+                        key
+                    ));
+                }
+                match array.get_mut(index) {
+                    Some(x) => x,
+                    None => return Err(format!("Env override {} index is out of range.", key)),
+                }
+            }
+            (next, _) => next,
+        };
+
+        target = match next {
+            Value::Table(x) => x,
+            _ => return Err(format!("Env override {} doesn't name a config key.", key)),
+        };
+    }
+}
+
+/// Checks the config is usable before anything is scheduled against it.
+fn validate_config(config: &Config) -> Result<(), String> {
+    if !(0..60).contains(&config.timing.minutes) {
+        return Err(format!(
+            "Config minutes must be between 0 and 59: {}",
+            config.timing.minutes
+        ));
+    }
+    if config.subvolumes.is_empty() {
+        return Err("Config has no enabled subvolumes to snapshot.".to_string());
+    }
+    for (i, subvolume) in config.subvolumes.iter().enumerate() {
+        if let Some(x) = &subvolume.timing
+            && !(0..60).contains(&x.minutes)
+        {
+            return Err(format!(
+                "Config subvolume {} minutes must be between 0 and 59: {}",
+                subvolume.name, x.minutes
+            ));
+        }
+        if subvolume.name.is_empty() || subvolume.name.contains('/') {
+            return Err(format!(
+                "Config subvolume name must be a non empty name without '/': {}",
+                subvolume.name
+            ));
+        }
+        if config.subvolumes[..i]
+            .iter()
+            .any(|x| x.name == subvolume.name)
+        {
+            return Err(format!(
+                "Config subvolume name is used more than once: {}",
+                subvolume.name
+            ));
+        }
+        if !subvolume.path.is_dir() {
+            return Err(format!(
+                "Config subvolume path is not a directory: {}",
+                subvolume.path.to_string_lossy()
+            ));
+        }
+        if !subvolume.snapshot_path.is_dir() {
+            return Err(format!(
+                "Config snapshot_path is not a directory: {}",
+                subvolume.snapshot_path.to_string_lossy()
+            ));
+        }
+        if !config.trigger_paths.is_empty() && !subvolume.trigger_snapshot_path.is_dir() {
+            return Err(format!(
+                "Config trigger_snapshot_path is not a directory: {}",
+                subvolume.trigger_snapshot_path.to_string_lossy()
+            ));
+        }
+        // Through a wrapper the paths may only be btrfs as btrfs itself sees them.
+        if subvolume.btrfs.native() {
+            validate_btrfs_paths(subvolume)?;
+        }
+    }
+    for (i, group) in config.groups.iter().enumerate() {
+        if group.name.is_empty() || config.groups[..i].iter().any(|x| x.name == group.name) {
+            return Err(format!(
+                "Config group name must be a non empty name used once: {}",
+                group.name
+            ));
+        }
+        if group.subvolumes.is_empty() {
+            return Err(format!("Config group {} has no subvolumes.", group.name));
+        }
+        for member in group.subvolumes.iter() {
+            let Some(subvolume) = config.subvolumes.iter().find(|x| x.name == *member) else {
+                return Err(format!(
+                    "Config group {} names subvolume {}, but there is no enabled subvolume by that name.",
+                    group.name, member
+                ));
+            };
+            if config.groups[..i]
+                .iter()
+                .chain(std::iter::once(group))
+                .flat_map(|x| x.subvolumes.iter())
+                .filter(|x| *x == member)
+                .count()
+                > 1
+            {
+                return Err(format!(
+                    "Config subvolume {} is in more than one group, or in group {} more than once.",
+                    member, group.name
+                ));
+            }
+            // Members are snapshotted at the same times, so the group has a single schedule.
+            if subvolume.timing.is_some() {
+                return Err(format!(
+                    "Config subvolume {} is in group {}, so it can't set its own minutes, interval or schedule.",
+                    member, group.name
+                ));
+            }
+        }
+    }
+    for (i, x) in config.replication.iter().enumerate() {
+        if x.host.is_empty() || x.host.starts_with('-') || !x.path.is_absolute() {
+            return Err(format!(
+                "Config replication needs a host and an absolute path: {} {}",
+                x.host,
+                x.path.to_string_lossy()
+            ));
+        }
+        if x.name.is_empty() || config.replication[..i].iter().any(|y| y.name == x.name) {
+            return Err(format!(
+                "Config replication name must be a non empty name used once, set name to tell targets on the same host apart: {}",
+                x.name
+            ));
+        }
+        match &x.encryption {
+            Some(_) if x.store == ReplicationStore::Receive => {
+                return Err(format!(
+                    "Config replication {} encrypts its streams, which btrfs receive can't read, so it needs store = \"files\".",
+                    x.name
+                ));
+            }
+            Some(Encryption::Age(y) | Encryption::Gpg(y))
+                if y.is_empty() || y.iter().any(|z| z.is_empty() || z.starts_with('-')) =>
+            {
+                return Err(format!(
+                    "Config replication {} recipients must be non empty and not start with '-'.",
+                    x.name
+                ));
+            }
+            _ => {}
+        }
+        if let Some(policy) = &x.policy {
+            match config.policies.get(policy) {
+                None => {
+                    return Err(format!(
+                        "Config replication {} names policy {}, but there is no [policy.{}].",
+                        x.name, policy, policy
+                    ));
+                }
+                Some(_) if x.keep_last > 0 => {
+                    return Err(format!(
+                        "Config replication {} can't set both keep_last and policy, set keep_last in [policy.{}] instead.",
+                        x.name, policy
+                    ));
+                }
+                // Exclusive usage is only measured on this host's filesystems.
+                Some(y) if y.space_budget_bytes.is_some() => {
+                    return Err(format!(
+                        "Config replication {} policy {} can't set space_budget_bytes, as the space the target uses isn't measured.",
+                        x.name, policy
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    if let Some(x) = config.trigger_paths.iter().find(|x| !x.is_absolute()) {
+        return Err(format!(
+            "Config trigger_paths must be absolute: {}",
+            x.to_string_lossy()
+        ));
+    }
+    if !config.trigger_paths.is_empty() && config.trigger_limit == 0 {
+        return Err("Config trigger_limit must be at least 1 with trigger_paths set.".to_string());
+    }
+    if config
+        .defer_io_pressure
+        .is_some_and(|x| !(0.0..100.0).contains(&x))
+    {
+        return Err(format!(
+            "Config defer_io_pressure must be at least 0 and below 100: {}",
+            config.defer_io_pressure.unwrap_or_default()
+        ));
+    }
+    if config
+        .defer_load_average
+        .is_some_and(|x| x.is_nan() || x < 0.0)
+    {
+        return Err(format!(
+            "Config defer_load_average can't be negative: {}",
+            config.defer_load_average.unwrap_or_default()
+        ));
+    }
+    if config.min_free_percent.is_some_and(|x| x > 100) {
+        return Err(format!(
+            "Config min_free_percent must be between 0 and 100: {}",
+            config.min_free_percent.unwrap_or_default()
+        ));
+    }
+    if config.file_log_level != filter::LevelFilter::OFF
+        && config.log_dir.exists()
+        && !config.log_dir.is_dir()
+    {
+        return Err(format!(
+            "Config log_dir is not a directory: {}",
+            config.log_dir.to_string_lossy()
+        ));
+    }
+    if let Some(x) = &config.metrics_path
+        && !x.parent().is_some_and(|x| x.is_dir())
+    {
+        return Err(format!(
+            "Config metrics_path directory does not exist: {}",
+            x.to_string_lossy()
+        ));
+    }
+    if let Some(x) = &config.restore_drill {
+        if x.recent_snapshots == 0 || x.sample_files == 0 {
+            return Err(
+                "Config restore_drill recent_snapshots and sample_files must be positive."
+                    .to_string(),
+            );
+        }
+        if !x.scratch_path.parent().is_some_and(|x| x.is_dir()) {
+            return Err(format!(
+                "Config restore_drill scratch_path directory does not exist: {}",
+                x.scratch_path.to_string_lossy()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the path of `subvolume` is a btrfs subvolume and its snapshot_path is on the same
+/// filesystem, as snapshots can't cross filesystems.
+fn validate_btrfs_paths(subvolume: &SubvolumeConfig) -> Result<(), String> {
+    if !subvolume
+        .backend
+        .is_subvolume(&subvolume.path)
+        .is_ok_and(|x| x)
+    {
+        return Err(format!(
+            "Config subvolume path is not a btrfs subvolume: {}",
+            subvolume.path.to_string_lossy()
+        ));
+    }
+    let uuid = |path: &Path| {
+        ioctl::filesystem_uuid(path).map_err(|e| {
+            format!(
+                "Error reading the btrfs filesystem of {}. {}",
+                path.to_string_lossy(),
+                e
+            )
+        })
+    };
+    if uuid(&subvolume.path)? != uuid(&subvolume.snapshot_path)? {
+        return Err(format!(
+            "Config snapshot_path {} is not on the same btrfs filesystem as subvolume {}.",
+            subvolume.snapshot_path.to_string_lossy(),
+            subvolume.path.to_string_lossy()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parses the retention keys of a policy, naming them with `key_prefix` in errors.
+fn parse_retention_policy(
+    key_prefix: &str,
+    temp_policy: TempRetentionPolicy,
+) -> Result<RetentionPolicy, String> {
+    let mut policy = RetentionPolicy::default();
+
+    if let Some(x) = temp_policy.frequent_limit {
+        policy.frequent_limit = x;
+    }
+    if let Some(x) = temp_policy.hourly_limit {
+        policy.hourly_limit = x;
+    }
+    if let Some(x) = temp_policy.daily_limit {
+        policy.daily_limit = x;
+    }
+    if let Some(x) = temp_policy.weekly_limit {
+        policy.weekly_limit = x;
+    }
+    if let Some(x) = temp_policy.week_start {
+        policy.week_start = match x.to_lowercase().as_str() {
+            "monday" => Weekday::Monday,
+            "sunday" => Weekday::Sunday,
+            _ => {
+                return Err(format!(
+                    "Config {}week_start must be monday or sunday: {}",
+                    key_prefix, x
+                ));
+            }
+        };
+    }
+    if let Some(x) = temp_policy.monthly_limit {
+        policy.monthly_limit = x;
+    }
+    if let Some(x) = temp_policy.yearly_limit {
+        policy.yearly_limit = x;
+    }
+    if let Some(x) = temp_policy.keep_last {
+        policy.keep_last = x;
+    }
+    if let Some(x) = temp_policy.keep_within {
+        policy.keep_within = Some(parse_interval(&format!("{}keep_within", key_prefix), &x)?);
+    }
+    if let Some(x) = temp_policy.immutable_for {
+        policy.immutable_for = Some(parse_interval(&format!("{}immutable_for", key_prefix), &x)?);
+    }
+    if let Some(x) = temp_policy.max_age {
+        policy.max_age = Some(parse_interval(&format!("{}max_age", key_prefix), &x)?);
+    }
+    if let Some(x) = temp_policy.space_budget_bytes {
+        policy.space_budget_bytes = Some(x);
+    }
+    for x in temp_policy.tiers.unwrap_or_default() {
+        let key = format!("{}tiers period", key_prefix);
+        let period = TierPeriod::from_span(parse_interval(&key, &x.period)?)
+            .map_err(|e| format!("Config {} {}.", key, e))?;
+        policy.tiers.push(RetentionTier {
+            name: x.period,
+            period,
+            limit: x.limit,
+        });
+    }
+
+    if policy.has_no_limits() && policy.space_budget_bytes.is_none() {
+        return Err(format!(
+            "Config {}retention keeps no snapshots, every snapshot would be deleted.",
+            key_prefix
+        ));
+    }
+
+    Ok(policy)
+}
+
+fn parse_log_level(key: &str, value: &str) -> Result<filter::LevelFilter, String> {
+    value.parse().map_err(|_| {
+        format!(
+            "Error parsing config {}: {} | Expected one of off, error, warn, info, debug or trace.",
+            key, value
+        )
+    })
+}
+
+fn parse_schedule(value: &str) -> Result<CalendarSchedule, String> {
+    let schedule: CalendarSchedule = value
+        .parse()
+        .map_err(|e| format!("Error parsing config schedule: {} | Error: {}", value, e))?;
+    // Early resolves every time the clocks skip, so only a day or year that never comes fails.
+    if schedule
+        .next_at_or_after(&Zoned::now(), DstPolicy::Early)
+        .is_none()
+    {
+        return Err(format!(
+            "Config schedule never matches a time from now on: {}",
+            value
+        ));
+    }
+
+    Ok(schedule)
+}
+
+fn parse_interval(key: &str, value: &str) -> Result<Span, String> {
+    match value.parse::<Span>() {
+        Ok(x) if x.is_positive() => Ok(x),
+        Ok(_) => Err(format!("Config {} must be positive: {}", key, value)),
+        Err(e) => Err(format!(
+            "Error parsing config {}: {} | Error: {}",
+            key, value, e
+        )),
+    }
 }
@@ -0,0 +1,552 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::backend::SubvolumeInfo;
+use jiff::Timestamp;
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs::{self, File},
+    io,
+    mem::MaybeUninit,
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+/// The type every btrfs ioctl is numbered under.
+const BTRFS_IOCTL_MAGIC: u64 = 0x94;
+const BTRFS_IOC_SNAP_DESTROY: u64 = iow(15, size_of::<VolArgs>());
+const BTRFS_IOC_TREE_SEARCH: u64 = iowr(17, size_of::<SearchArgs>());
+const BTRFS_IOC_INO_LOOKUP: u64 = iowr(18, size_of::<InoLookupArgs>());
+const BTRFS_IOC_WAIT_SYNC: u64 = iow(22, size_of::<u64>());
+const BTRFS_IOC_SNAP_CREATE_V2: u64 = iow(23, size_of::<VolArgsV2>());
+const BTRFS_IOC_START_SYNC: u64 = ior(24, size_of::<u64>());
+const BTRFS_IOC_FS_INFO: u64 = ior(31, size_of::<FsInfoArgs>());
+const BTRFS_IOC_GET_SUBVOL_INFO: u64 = ior(60, size_of::<SubvolInfoArgs>());
+const BTRFS_IOC_GET_SUBVOL_ROOTREF: u64 = iowr(61, size_of::<RootrefArgs>());
+const BTRFS_IOC_INO_LOOKUP_USER: u64 = iowr(62, size_of::<InoLookupUserArgs>());
+
+const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+
+const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+/// The first subvolume ID, and the inode of each subvolume's root directory.
+const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+const BTRFS_LAST_FREE_OBJECTID: u64 = -256i64 as u64;
+const BTRFS_ROOT_BACKREF_KEY: u32 = 144;
+
+const fn ioc(direction: u64, number: u64, size: usize) -> u64 {
+    (direction << 30) | ((size as u64) << 16) | (BTRFS_IOCTL_MAGIC << 8) | number
+}
+
+const fn iow(number: u64, size: usize) -> u64 {
+    ioc(1, number, size)
+}
+
+const fn ior(number: u64, size: usize) -> u64 {
+    ioc(2, number, size)
+}
+
+const fn iowr(number: u64, size: usize) -> u64 {
+    ioc(3, number, size)
+}
+
+#[repr(C)]
+struct VolArgs {
+    fd: i64,
+    name: [u8; 4088],
+}
+
+#[repr(C)]
+struct VolArgsV2 {
+    fd: i64,
+    transid: u64,
+    flags: u64,
+    unused: [u64; 4],
+    name: [u8; 4040],
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: [u32; 1],
+    unused_more: [u64; 4],
+}
+
+#[repr(C)]
+struct SearchArgs {
+    key: SearchKey,
+    buf: [u8; 4096 - size_of::<SearchKey>()],
+}
+
+#[repr(C)]
+struct InoLookupArgs {
+    treeid: u64,
+    objectid: u64,
+    name: [u8; 4080],
+}
+
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+#[repr(C)]
+struct IoctlTimespec {
+    sec: u64,
+    nsec: u32,
+}
+
+#[repr(C)]
+struct SubvolInfoArgs {
+    treeid: u64,
+    name: [u8; 256],
+    parent_id: u64,
+    dirid: u64,
+    generation: u64,
+    flags: u64,
+    uuid: [u8; 16],
+    parent_uuid: [u8; 16],
+    received_uuid: [u8; 16],
+    ctransid: u64,
+    otransid: u64,
+    stransid: u64,
+    rtransid: u64,
+    ctime: IoctlTimespec,
+    otime: IoctlTimespec,
+    stime: IoctlTimespec,
+    rtime: IoctlTimespec,
+    reserved: [u64; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Rootref {
+    treeid: u64,
+    dirid: u64,
+}
+
+#[repr(C)]
+struct RootrefArgs {
+    min_treeid: u64,
+    rootref: [Rootref; 255],
+    num_items: u8,
+    align: [u8; 7],
+}
+
+#[repr(C)]
+struct InoLookupUserArgs {
+    dirid: u64,
+    treeid: u64,
+    name: [u8; 256],
+    path: [u8; 3824],
+}
+
+/// Runs the btrfs ioctl `request` on `file` with `args`.
+///
+/// # Safety
+///
+/// `args` must be the struct `request` is numbered with.
+unsafe fn ioctl<T>(file: &File, request: u64, args: &mut T) -> io::Result<()> {
+    // SAFETY: Guaranteed by the caller.
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, args as *mut T) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Copies `name` into the nul terminated `buffer` of an ioctl, as long as it fits.
+fn copy_name(buffer: &mut [u8], name: &OsStr) -> io::Result<()> {
+    let name = name.as_bytes();
+    if name.len() >= buffer.len() || name.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid subvolume name {}", name.escape_ascii()),
+        ));
+    }
+    buffer[..name.len()].copy_from_slice(name);
+
+    Ok(())
+}
+
+/// Opens the directory `path` is in, returning it with the last component of `path`.
+fn open_parent(path: &Path) -> io::Result<(File, &OsStr)> {
+    let name = path.file_name().ok_or(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} has no file name", path.to_string_lossy()),
+    ))?;
+    let parent = match path.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+
+    Ok((File::open(parent)?, name))
+}
+
+/// Snapshots the subvolume at `source` to `destination`, read-only if `readonly`.
+pub fn create_snapshot(source: &Path, destination: &Path, readonly: bool) -> io::Result<()> {
+    let source = File::open(source)?;
+    let (parent, name) = open_parent(destination)?;
+    let mut args = VolArgsV2 {
+        fd: source.as_raw_fd() as i64,
+        transid: 0,
+        flags: if readonly { BTRFS_SUBVOL_RDONLY } else { 0 },
+        unused: [0; 4],
+        name: [0; 4040],
+    };
+    copy_name(&mut args.name, name)?;
+
+    // SAFETY: args is a btrfs_ioctl_vol_args_v2.
+    unsafe { ioctl(&parent, BTRFS_IOC_SNAP_CREATE_V2, &mut args) }
+}
+
+/// Deletes the subvolume at `path`, leaving the transaction to commit later.
+pub fn delete_subvolume(path: &Path) -> io::Result<()> {
+    let (parent, name) = open_parent(path)?;
+    let mut args = VolArgs {
+        fd: 0,
+        name: [0; 4088],
+    };
+    copy_name(&mut args.name, name)?;
+
+    // SAFETY: args is a btrfs_ioctl_vol_args.
+    unsafe { ioctl(&parent, BTRFS_IOC_SNAP_DESTROY, &mut args) }
+}
+
+/// Commits the current transaction of the filesystem containing `path`, waiting until it is on
+/// disk.
+pub fn commit(path: &Path) -> io::Result<()> {
+    let file = File::open(path)?;
+    let mut transid = 0u64;
+
+    // SAFETY: Both take the u64 transaction ID, which START_SYNC writes and WAIT_SYNC reads.
+    unsafe {
+        ioctl(&file, BTRFS_IOC_START_SYNC, &mut transid)?;
+        ioctl(&file, BTRFS_IOC_WAIT_SYNC, &mut transid)
+    }
+}
+
+/// Whether `path` is the top directory of a btrfs subvolume.
+pub fn is_subvolume(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+
+    // SAFETY: c_path is a valid nul terminated string and stat is only read after statfs succeeds.
+    let stat = unsafe {
+        if libc::statfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    // The magic is truncated as f_type is signed where it is 32 bits.
+    Ok(stat.f_type as u32 == BTRFS_SUPER_MAGIC
+        && fs::metadata(path)?.ino() == BTRFS_FIRST_FREE_OBJECTID)
+}
+
+/// Returns what btrfs records about the subvolume at `path`, or `None` if it is a plain directory.
+pub fn subvolume_info(path: &Path) -> io::Result<Option<SubvolumeInfo>> {
+    if !is_subvolume(path)? {
+        return Ok(None);
+    }
+    let file = File::open(path)?;
+    // SAFETY: Every field is an integer or an array of them, so all zeroes is valid.
+    let mut args: SubvolInfoArgs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: args is a btrfs_ioctl_get_subvol_info_args.
+    unsafe { ioctl(&file, BTRFS_IOC_GET_SUBVOL_INFO, &mut args)? };
+
+    Ok(Some(SubvolumeInfo {
+        path: path.to_path_buf(),
+        id: args.treeid,
+        generation: args.generation,
+        created_generation: args.otransid,
+        uuid: format_uuid(&args.uuid),
+        parent_uuid: format_uuid(&args.parent_uuid),
+        received_uuid: format_uuid(&args.received_uuid),
+        created: Timestamp::new(args.otime.sec as i64, args.otime.nsec as i32).ok(),
+    }))
+}
+
+/// Returns the path of every subvolume nested anywhere inside the subvolume at `path`, which
+/// snapshots of it leave out.
+pub fn nested_subvolumes(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let file = File::open(path)?;
+    let mut nested = Vec::new();
+    let mut args = RootrefArgs {
+        min_treeid: 0,
+        rootref: [Rootref {
+            treeid: 0,
+            dirid: 0,
+        }; 255],
+        num_items: 0,
+        align: [0; 7],
+    };
+
+    loop {
+        // SAFETY: args is a btrfs_ioctl_get_subvol_rootref_args.
+        let more = match unsafe { ioctl(&file, BTRFS_IOC_GET_SUBVOL_ROOTREF, &mut args) } {
+            Ok(()) => false,
+            // Only as many as fit are returned, the rest are found from after the last.
+            Err(e) if e.raw_os_error() == Some(libc::EOVERFLOW) => true,
+            Err(e) => return Err(e),
+        };
+
+        for rootref in args.rootref[..args.num_items as usize].iter() {
+            let mut lookup = InoLookupUserArgs {
+                dirid: rootref.dirid,
+                treeid: rootref.treeid,
+                name: [0; 256],
+                path: [0; 3824],
+            };
+            // SAFETY: lookup is a btrfs_ioctl_ino_lookup_user_args.
+            unsafe { ioctl(&file, BTRFS_IOC_INO_LOOKUP_USER, &mut lookup)? };
+
+            let child = path
+                .join(nul_terminated(&lookup.path))
+                .join(nul_terminated(&lookup.name));
+            nested.extend(nested_subvolumes(&child)?);
+            nested.push(child);
+        }
+        match args.rootref[..args.num_items as usize].last() {
+            Some(x) if more => args.min_treeid = x.treeid + 1,
+            _ => break,
+        }
+    }
+    nested.sort();
+
+    Ok(nested)
+}
+
+/// The bytes of `buffer` before its first nul.
+fn nul_terminated(buffer: &[u8]) -> &OsStr {
+    let len = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+
+    OsStr::from_bytes(&buffer[..len])
+}
+
+/// Formats `uuid` as btrfs shows it, `None` when it is unset.
+fn format_uuid(uuid: &[u8; 16]) -> Option<String> {
+    if uuid.iter().all(|x| *x == 0) {
+        return None;
+    }
+    let hex: String = uuid.iter().map(|x| format!("{:02x}", x)).collect();
+
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// Returns the UUID of the btrfs filesystem containing `path`, the same for all its subvolumes.
+pub fn filesystem_uuid(path: &Path) -> io::Result<[u8; 16]> {
+    let file = File::open(path)?;
+    let mut args = FsInfoArgs {
+        max_id: 0,
+        num_devices: 0,
+        fsid: [0; 16],
+        nodesize: 0,
+        sectorsize: 0,
+        clone_alignment: 0,
+        csum_type: 0,
+        csum_size: 0,
+        flags: 0,
+        generation: 0,
+        metadata_uuid: [0; 16],
+        reserved: [0; 944],
+    };
+
+    // SAFETY: args is a btrfs_ioctl_fs_info_args.
+    unsafe { ioctl(&file, BTRFS_IOC_FS_INFO, &mut args)? };
+
+    Ok(args.fsid)
+}
+
+/// Returns the path of every subvolume on the filesystem containing `path`, relative to the
+/// filesystem's top level, as `btrfs subvolume list` does. Needs CAP_SYS_ADMIN.
+pub fn list_subvolumes(path: &Path) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    // Each subvolume's ID with its parent's, the directory it is in and its name there.
+    let mut backrefs: Vec<(u64, u64, u64, String)> = Vec::new();
+    let mut args = SearchArgs {
+        key: SearchKey {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            min_objectid: BTRFS_FIRST_FREE_OBJECTID,
+            max_objectid: BTRFS_LAST_FREE_OBJECTID,
+            max_offset: u64::MAX,
+            max_transid: u64::MAX,
+            min_type: BTRFS_ROOT_BACKREF_KEY,
+            max_type: BTRFS_ROOT_BACKREF_KEY,
+            ..Default::default()
+        },
+        buf: [0; 4096 - size_of::<SearchKey>()],
+    };
+
+    loop {
+        args.key.nr_items = 4096;
+        // SAFETY: args is a btrfs_ioctl_search_args.
+        unsafe { ioctl(&file, BTRFS_IOC_TREE_SEARCH, &mut args)? };
+        if args.key.nr_items == 0 {
+            break;
+        }
+
+        let mut items = &args.buf[..];
+        let mut last = (0, 0);
+        for _ in 0..args.key.nr_items {
+            // Each item is a btrfs_ioctl_search_header, the transaction, key and data length in
+            // native byte order, followed by its data.
+            let (Some(header), Some(len)) =
+                (items.get(..32), bytes(items, 28).map(u32::from_ne_bytes))
+            else {
+                break;
+            };
+            let Some(data) = items.get(32..32 + len as usize) else {
+                break;
+            };
+            let objectid = bytes(header, 8).map_or(0, u64::from_ne_bytes);
+            let key_offset = bytes(header, 16).map_or(0, u64::from_ne_bytes);
+            let kind = bytes(header, 24).map_or(0, u32::from_ne_bytes);
+            items = &items[32 + len as usize..];
+            last = (objectid, key_offset);
+
+            // The data is a btrfs_root_ref, the directory, a sequence number and the name length,
+            // then the name, stored little endian.
+            let (Some(dirid), Some(name_len)) = (
+                bytes(data, 0).map(u64::from_le_bytes),
+                bytes(data, 16).map(u16::from_le_bytes),
+            ) else {
+                continue;
+            };
+            if kind != BTRFS_ROOT_BACKREF_KEY {
+                continue;
+            }
+            let name = data.get(18..18 + name_len as usize).unwrap_or_default();
+            backrefs.push((
+                objectid,
+                key_offset,
+                dirid,
+                String::from_utf8_lossy(name).to_string(),
+            ));
+        }
+
+        // Carry on after the last item found.
+        match last.1.checked_add(1) {
+            Some(x) => {
+                args.key.min_objectid = last.0;
+                args.key.min_offset = x;
+            }
+            None if last.0 < BTRFS_LAST_FREE_OBJECTID => {
+                args.key.min_objectid = last.0 + 1;
+                args.key.min_offset = 0;
+            }
+            None => break,
+        }
+    }
+
+    let mut paths: HashMap<u64, String> = HashMap::new();
+    paths.insert(BTRFS_FS_TREE_OBJECTID, String::new());
+    // Parents sort before their children by ID, but a subvolume can be moved into a newer one.
+    while !backrefs.is_empty() {
+        let before = backrefs.len();
+        let mut unresolved = Vec::new();
+        for (id, parent, dirid, name) in backrefs {
+            match paths.get(&parent) {
+                Some(x) => {
+                    let path = format!("{}/{}{}", x, directory_path(&file, parent, dirid)?, name);
+                    paths.entry(id).or_insert(path);
+                }
+                None => unresolved.push((id, parent, dirid, name)),
+            }
+        }
+        backrefs = unresolved;
+        if backrefs.len() == before {
+            break;
+        }
+    }
+    paths.remove(&BTRFS_FS_TREE_OBJECTID);
+
+    let mut subvolumes: Vec<(u64, String)> = paths
+        .into_iter()
+        .map(|(id, path)| (id, path.trim_start_matches('/').to_string()))
+        .collect();
+    subvolumes.sort();
+
+    Ok(subvolumes.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Returns the `N` bytes of `buffer` at `at`, if there are that many.
+fn bytes<const N: usize>(buffer: &[u8], at: usize) -> Option<[u8; N]> {
+    buffer.get(at..at + N)?.try_into().ok()
+}
+
+/// Returns the path of directory `dirid` in subvolume `treeid`, with a trailing slash unless it is
+/// the subvolume's root.
+fn directory_path(file: &File, treeid: u64, dirid: u64) -> io::Result<String> {
+    if dirid == BTRFS_FIRST_FREE_OBJECTID {
+        return Ok(String::new());
+    }
+    let mut args = InoLookupArgs {
+        treeid,
+        objectid: dirid,
+        name: [0; 4080],
+    };
+
+    // SAFETY: args is a btrfs_ioctl_ino_lookup_args.
+    unsafe { ioctl(file, BTRFS_IOC_INO_LOOKUP, &mut args)? };
+    let len = args
+        .name
+        .iter()
+        .position(|x| *x == 0)
+        .unwrap_or(args.name.len());
+
+    Ok(String::from_utf8_lossy(&args.name[..len]).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_match_the_kernel_headers() {
+        assert_eq!(BTRFS_IOC_SNAP_DESTROY, 0x5000_940f);
+        assert_eq!(BTRFS_IOC_TREE_SEARCH, 0xd000_9411);
+        assert_eq!(BTRFS_IOC_INO_LOOKUP, 0xd000_9412);
+        assert_eq!(BTRFS_IOC_WAIT_SYNC, 0x4008_9416);
+        assert_eq!(BTRFS_IOC_SNAP_CREATE_V2, 0x5000_9417);
+        assert_eq!(BTRFS_IOC_START_SYNC, 0x8008_9418);
+        assert_eq!(BTRFS_IOC_FS_INFO, 0x8400_941f);
+        assert_eq!(BTRFS_IOC_GET_SUBVOL_INFO, 0x81f8_943c);
+        assert_eq!(BTRFS_IOC_GET_SUBVOL_ROOTREF, 0xd000_943d);
+        assert_eq!(BTRFS_IOC_INO_LOOKUP_USER, 0xd000_943e);
+    }
+}
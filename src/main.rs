@@ -1,42 +1,388 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
 
-use jiff::{RoundMode, ToSpan, Unit, Zoned, ZonedRound};
+use backend::{SnapshotBackend, SubvolumeInfo};
+use cli::CliCommand;
+use control::ControlRequest;
+use events::EventReceiver;
+use group::SnapshotGroup;
+use jiff::{RoundMode, Span, ToSpan, Unit, Zoned, ZonedRound, tz::TimeZone};
+use metrics::RetentionMetrics;
+use naming::NameTemplate;
+use origin::Origin;
+use retention::RetentionPolicy;
+use schedule::{BlackoutWindow, DstPolicy, Timing};
 use std::{
     cmp::Ordering,
+    collections::BTreeMap,
+    ffi::CString,
+    hash::{BuildHasher, RandomState},
     io,
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, net::UnixStream},
     path::{Path, PathBuf},
-    process::Command,
-    thread::sleep,
+    process::{Command, Output, Stdio, exit},
+    sync::{
+        Arc,
+        atomic::{self, AtomicBool},
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
 };
-use tracing::info_span;
+use tracing::{info_span, level_filters::LevelFilter};
+use trigger::TriggerLimiter;
 
+mod backend;
+mod btrfs_json;
+mod cli;
+mod clones;
+mod command;
+mod control;
+mod drill;
+mod events;
+mod group;
+mod hooks;
 mod init;
+mod ioctl;
+mod metrics;
+mod naming;
+mod nested;
+mod origin;
+mod power;
+mod pressure;
+mod replication;
+mod retention;
+mod schedule;
+mod schema;
+mod state;
+mod timespec;
+mod trash;
+mod trend;
+mod trigger;
 
 struct Config {
-    minutes: i8,
-    subvolume_path: PathBuf,
-    subvolume_name: String,
+    timing: Timing,
+    stagger_window: Option<Span>,
+    /// A random delay of up to this long added to each snapshot's stagger, chosen afresh every
+    /// cycle.
+    stagger_jitter: Option<Span>,
+    /// The least time between one snapshot of a cycle finishing and the next starting.
+    stagger_spacing: Option<Span>,
+    catch_up: bool,
+    /// Daily windows in which nothing is snapshotted or pruned.
+    blackout: Vec<BlackoutWindow>,
+    failure_policy: FailurePolicy,
+    failure_limit: u32,
+    failure_backoff_max: Span,
+    /// Where the daemon keeps state between cycles and restarts.
+    state_path: PathBuf,
+    /// The socket the daemon takes requests such as on-demand snapshots on. Only read at startup.
+    control_socket: PathBuf,
+    /// Cycles are deferred while IO pressure, as a percentage, or the load average is above these.
+    defer_io_pressure: Option<f64>,
+    defer_load_average: Option<f64>,
+    /// The longest a cycle is deferred for.
+    defer_max: Span,
+    /// Skip scheduled snapshots while on battery, catching up once on AC power.
+    skip_on_battery: bool,
+    /// Skip scheduled snapshots of subvolumes unchanged since their newest snapshot.
+    skip_unchanged: bool,
+    /// Take a final snapshot of every subvolume when stopped.
+    snapshot_on_stop: bool,
+    /// How long to finish the current cycle and final snapshots for once stopped.
+    stop_timeout: Span,
+    min_interval: Option<Span>,
+    name_template: NameTemplate,
+    ignore_patterns: Vec<String>,
+    /// Paths watched for changes that take an extra, tagged snapshot.
+    trigger_paths: Vec<PathBuf>,
+    /// The most trigger snapshots taken per hour.
+    trigger_limit: usize,
+    /// How many trigger snapshots of each subvolume are kept.
+    trigger_keep_last: usize,
+    /// The time zone snapshot names are written in, the system time zone when unset.
+    timestamp_timezone: Option<TimeZone>,
     snapshot_path: PathBuf,
-    hourly_limit: usize,
+    /// How btrfs is run outside of any one subvolume, such as by `prune --external`.
+    btrfs: BtrfsCommand,
+    subvolumes: Vec<SubvolumeConfig>,
+    /// Subvolumes snapshotted together as one restore point.
+    groups: Vec<SnapshotGroup>,
+    retention: RetentionPolicy,
+    /// Named retention policies, for pruning snapshots made by other tools.
+    policies: BTreeMap<String, RetentionPolicy>,
+    /// The named policy pruning each origin's snapshots. Origins without one are never pruned.
+    origin_policies: BTreeMap<Origin, String>,
+    /// The newest snapshots of each subvolume that are never pruned, whatever the retention
+    /// rules and free space say.
+    min_keep: usize,
+    /// Pruned snapshots are moved to the trash and only deleted after this long when set.
+    trash_grace: Option<Span>,
+    /// The writable subvolumes rollbacks leave are deleted once this old when set.
+    clone_max_age: Option<Span>,
+    min_free_bytes: Option<u64>,
+    /// Snapshots are refused while the filesystem has less unallocated space than this.
+    min_unallocated_bytes: Option<u64>,
+    /// Or less room for metadata to grow, free metadata space plus unallocated space.
+    min_metadata_headroom_bytes: Option<u64>,
+    min_free_percent: Option<u8>,
+    /// The oldest snapshots are pruned while the trend of free space projects the filesystem
+    /// filling within this long.
+    fill_horizon: Option<Span>,
+    /// At most this many snapshots of each subvolume are deleted a cycle when set, the rest
+    /// waiting in the deletion queue in the state file.
+    max_deletions_per_cycle: Option<usize>,
+    deletion_sync_timeout: Option<Span>,
+    metrics_path: Option<PathBuf>,
+    restore_drill: Option<RestoreDrillConfig>,
+    hooks: HooksConfig,
+    /// The targets snapshots are replicated to.
+    replication: Vec<ReplicationConfig>,
+    /// Where the host's root is mounted when running in a container. Already applied to every
+    /// host path in the config.
+    host_prefix: Option<PathBuf>,
+    /// Only read at startup, as the logger can't be replaced once installed.
+    log_dir: PathBuf,
+    /// How long log files are kept for, in one file per day, unset for a single growing file.
+    log_max_age: Option<Span>,
+    file_log_level: LevelFilter,
+    stdout_log_level: LevelFilter,
+}
+
+struct SubvolumeConfig {
+    path: PathBuf,
+    name: String,
+    snapshot_path: PathBuf,
+    /// Where trigger snapshots go, which may be another btrfs filesystem.
+    trigger_snapshot_path: PathBuf,
+    /// Replaces the global snapshot timing for this subvolume when set.
+    timing: Option<Timing>,
+    stagger: Option<Span>,
+    readonly: bool,
+    btrfs: BtrfsCommand,
+    /// What snapshots are taken and pruned with, btrfs itself outside of tests.
+    backend: Arc<dyn SnapshotBackend>,
+    /// Whether the subvolumes nested in this one were added as subvolumes of their own.
+    include_nested: bool,
+}
+
+/// How to run btrfs, for hosts where it isn't on the PATH or must run outside a container.
+#[derive(Clone)]
+struct BtrfsCommand {
+    path: PathBuf,
+    /// A command and its arguments that btrfs is run through, such as `nsenter` or `chroot`.
+    wrapper: Vec<String>,
+    /// How long a btrfs command may run before it is killed, apart from send and receive.
+    timeout: Option<Span>,
+}
+
+impl Default for BtrfsCommand {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("btrfs"),
+            wrapper: Vec::new(),
+            timeout: Some(10.minutes()),
+        }
+    }
+}
+
+impl BtrfsCommand {
+    /// Runs `command`, made with `command`, under the command timeout.
+    fn output(&self, command: &mut Command) -> Result<Output, String> {
+        command::output_with_timeout(command, self.timeout)
+    }
+
+    /// Whether snapshots are created, deleted and listed with btrfs ioctls rather than by running
+    /// btrfs, which they are unless btrfs must run through a wrapper to reach the filesystem.
+    fn native(&self) -> bool {
+        self.wrapper.is_empty()
+    }
+
+    fn command(&self) -> Command {
+        match self.wrapper.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args).arg(&self.path);
+                command
+            }
+            None => Command::new(&self.path),
+        }
+    }
+}
+
+/// What to do about a subvolume whose snapshots keep failing.
+#[derive(Clone, Copy)]
+enum FailurePolicy {
+    /// Try again every cycle.
+    Retry,
+    /// Wait twice as long after each consecutive failure, up to failure_backoff_max.
+    Backoff,
+    /// Stop after failure_limit consecutive failures until resumed.
+    Stop,
+}
+
+/// Shell commands run around snapshots and pruning, e.g. to quiesce a database.
+#[derive(Default)]
+struct HooksConfig {
+    pre_snapshot: Option<String>,
+    post_snapshot: Option<String>,
+    pre_prune: Option<String>,
+    post_prune: Option<String>,
+    on_error: Option<String>,
+}
+
+/// Where snapshots are sent with `btrfs send | ssh host btrfs receive` after each cycle.
+struct ReplicationConfig {
+    /// What the target is called in logs and the state file, the host unless set.
+    name: String,
+    host: String,
+    path: PathBuf,
+    ssh_options: Vec<String>,
+    /// How long to wait between replications, every cycle when unset.
+    interval: Option<Span>,
+    /// How many snapshots of each subvolume the target keeps, all of them when 0.
+    keep_last: usize,
+    /// The named policy pruning the target instead of keep_last, usually keeping more than the
+    /// local retention does.
+    policy: Option<String>,
+    compression: Option<Compression>,
+    store: ReplicationStore,
+    /// What stream files are encrypted to before they leave this host.
+    encryption: Option<Encryption>,
+    /// How often a stream file is sent in full rather than incrementally, so older chains of
+    /// stream files can be pruned.
+    full_send_interval: Span,
+    /// The most bytes a second sent to the target, after any compression and encryption.
+    bandwidth_limit_bytes: Option<u64>,
+}
+
+/// How a replication target keeps the snapshots sent to it.
+#[derive(Clone, Copy, PartialEq)]
+enum ReplicationStore {
+    /// As subvolumes, with `btrfs receive`.
+    Receive,
+    /// As send stream files, for storage that isn't btrfs or isn't trusted. Each incremental
+    /// stream needs the chain of streams before it back to a full one to be restored.
+    Files,
+}
+
+/// Who send stream files are encrypted to.
+enum Encryption {
+    /// age recipients, e.g. `age1...` public keys.
+    Age(Vec<String>),
+    /// GnuPG key IDs or fingerprints, from root's keyring.
+    Gpg(Vec<String>),
+}
+
+/// How send streams are compressed on their way to a replication target.
+#[derive(Clone, Copy, PartialEq)]
+enum Compression {
+    /// Through zstd on both ends, which the target needs installed too.
+    Zstd,
+    /// Extents btrfs already stored compressed are sent as they are, with
+    /// `btrfs send --compressed-data`.
+    CompressedData,
+}
+
+struct RestoreDrillConfig {
+    interval: Span,
+    scratch_path: PathBuf,
+    recent_snapshots: usize,
+    sample_files: usize,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            minutes: 0,
-            subvolume_path: PathBuf::from("/"),
-            subvolume_name: "@rootfs".to_string(),
+            timing: Timing {
+                minutes: 0,
+                interval: 1.hour(),
+                schedule: None,
+                dst_policy: DstPolicy::Skip,
+            },
+            stagger_window: None,
+            stagger_jitter: None,
+            stagger_spacing: None,
+            catch_up: false,
+            skip_unchanged: false,
+            skip_on_battery: false,
+            defer_io_pressure: None,
+            defer_load_average: None,
+            defer_max: 30.minutes(),
+            failure_policy: FailurePolicy::Retry,
+            failure_limit: 3,
+            failure_backoff_max: 1.day(),
+            state_path: init::default_state_path(),
+            control_socket: init::default_control_socket(),
+            blackout: Vec::new(),
+            snapshot_on_stop: false,
+            stop_timeout: 60.seconds(),
+            min_interval: None,
+            name_template: naming::DEFAULT_NAME_TEMPLATE
+                .parse()
+                .expect("Default name template should be valid."),
+            timestamp_timezone: None,
+            ignore_patterns: Vec::new(),
+            trigger_paths: Vec::new(),
+            trigger_limit: 1,
+            trigger_keep_last: 10,
             snapshot_path: PathBuf::from("/snapshots"),
-            hourly_limit: 48,
+            btrfs: BtrfsCommand::default(),
+            subvolumes: vec![SubvolumeConfig {
+                path: PathBuf::from("/"),
+                name: "@rootfs".to_string(),
+                snapshot_path: PathBuf::from("/snapshots"),
+                trigger_snapshot_path: PathBuf::from("/snapshots"),
+                timing: None,
+                stagger: None,
+                readonly: true,
+                btrfs: BtrfsCommand::default(),
+                backend: Arc::new(BtrfsCommand::default()),
+                include_nested: false,
+            }],
+            groups: Vec::new(),
+            retention: RetentionPolicy::default(),
+            policies: BTreeMap::new(),
+            origin_policies: BTreeMap::new(),
+            min_keep: 1,
+            trash_grace: None,
+            clone_max_age: None,
+            min_free_bytes: None,
+            min_unallocated_bytes: None,
+            min_metadata_headroom_bytes: None,
+            min_free_percent: None,
+            fill_horizon: None,
+            max_deletions_per_cycle: None,
+            deletion_sync_timeout: None,
+            metrics_path: None,
+            restore_drill: None,
+            hooks: HooksConfig::default(),
+            replication: Vec::new(),
+            host_prefix: None,
+            log_dir: init::default_log_dir(),
+            log_max_age: None,
+            file_log_level: LevelFilter::INFO,
+            stdout_log_level: LevelFilter::INFO,
         }
     }
 }
 
+enum Event {
+    ReloadConfig,
+    /// A change to the path under one of the trigger paths.
+    Trigger(PathBuf),
+    /// SIGTERM or SIGINT, e.g. from systemd at shutdown.
+    Stop,
+    /// A request over the control socket, with the connection to answer it on.
+    Control(ControlRequest, UnixStream),
+}
+
 struct Snapshot {
     snapshot_path: PathBuf,
     time: Zoned,
     keep: bool,
+    /// What btrfs records about the snapshot, unless it wasn't listed from this filesystem.
+    info: Option<SubvolumeInfo>,
 }
 
 impl Ord for Snapshot {
@@ -58,11 +404,66 @@ impl PartialEq for Snapshot {
     }
 }
 
+/// Set by `--read-only`, under which snapshots are never created or deleted and config files are
+/// never written, while metrics are still reported.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
 fn main() {
-    let config = init::load_config();
+    let cli = cli::parse_args();
+    READ_ONLY.store(cli.read_only, atomic::Ordering::Relaxed);
+
+    match cli.command {
+        CliCommand::Daemon => run_daemon(),
+        CliCommand::ValidateConfig(x) => cli::validate_config(x),
+        CliCommand::List {
+            path,
+            since,
+            until,
+            groups: false,
+        } => cli::list_snapshots(path, since, until),
+        CliCommand::List {
+            path,
+            since,
+            until,
+            groups: true,
+        } => cli::list_groups(path, since, until),
+        CliCommand::Status(x) => cli::status(x),
+        CliCommand::Resume(x) => cli::resume(x.as_deref()),
+        CliCommand::SnapshotNow(origin, x) => cli::snapshot_now(origin, x.as_deref()),
+        CliCommand::Next { path, count } => cli::next(path, count),
+        CliCommand::Nearest {
+            path,
+            time,
+            subvolume,
+        } => cli::nearest(path, &time, subvolume.as_deref()),
+        CliCommand::Rollback { path, group, time } => cli::rollback_group(path, &group, &time),
+        CliCommand::ListClones(x) => cli::list_clones(x),
+        CliCommand::DeleteClone { path, clone } => cli::delete_clone(path, &clone),
+        CliCommand::VerifyReplica { path, target } => cli::verify_replica(path, &target),
+        CliCommand::PruneExternal {
+            config_path,
+            dir,
+            pattern,
+            policy,
+            explain,
+        } => cli::prune_external(config_path, &dir, &pattern, policy.as_deref(), explain),
+        CliCommand::ExplainPruning(x) => cli::explain_pruning(x),
+        CliCommand::InitConfig {
+            path,
+            detect,
+            force,
+        } => cli::init_config(path, detect, force),
+        CliCommand::Version { json } => cli::version(json),
+    }
+}
+
+fn run_daemon() {
+    let mut config = init::load_config();
 
     // Guard must live for the life of the program to ensure logs are written to log file.
-    let _guard = init::init_logging();
+    let _guard = init::init_logging(&config);
+    init::check_container(&config);
+    nested::warn_uncovered(&config);
     let start_time = Zoned::now()
         .round(
             ZonedRound::new()
@@ -70,101 +471,1379 @@ fn main() {
                 .mode(RoundMode::Trunc),
         )
         .expect("Should never fail as it matches jiff invariants.");
-    let first_snapshot_time = Zoned::now()
-        .round(
-            ZonedRound::new()
-                .smallest(Unit::Second)
-                .mode(RoundMode::Trunc),
-        )
-        .expect("Should never fail as it matches jiff invariants.")
-        .with()
-        .minute(config.minutes)
-        .second(0)
-        .build()
-        .expect("Timestamp should be valid.");
-    let start_to_first_snapshot = &start_time
-        .until(&first_snapshot_time)
-        .expect("Should never fail as it matches jiff invariants.");
-    let mut snapshot_time = if start_to_first_snapshot.is_negative() {
-        first_snapshot_time
-            .checked_add(1.hour())
-            .expect("Time should never overflow.")
-    } else {
-        first_snapshot_time
-    };
+    let mut next_times = first_snapshot_times(&config, &start_time);
     tracing::info!("Starting program at {}.", &start_time);
-    tracing::info!("First snapshot time: {}.", &snapshot_time);
+    if read_only() {
+        tracing::info!("Running in read-only mode, no snapshots will be created or deleted.");
+    }
+    tracing::info!("First snapshot time: {}.", earliest(&next_times));
+    state::compact(&config);
+
+    if config.catch_up && !in_blackout(&config, &start_time) {
+        for subvolume in config.subvolumes.iter() {
+            if let Ok(snapshots) = matching_snapshots(&config, subvolume)
+                && let Some(newest) = snapshots.last()
+                && subvolume_timing(&config, subvolume).next_after(&newest.time) <= start_time
+            {
+                tracing::info!(
+                    "Missed a scheduled snapshot of {} while stopped, taking a catch-up snapshot.",
+                    subvolume.name
+                );
+                take_scheduled_snapshot(&config, subvolume, &start_time);
+            }
+        }
+    }
+
+    let events = init::init_events(&config);
+    let mut trigger_limiter = TriggerLimiter::default();
+    let mut last_drill_time: Option<Zoned> = None;
+    // When each replication target was last replicated to, by name.
+    let mut last_replication_times: BTreeMap<String, Zoned> = BTreeMap::new();
+    let mut retention_metrics = RetentionMetrics::default();
+    // Subvolumes whose snapshots were skipped on battery, to catch up once on AC power.
+    let mut skipped_on_battery: Vec<String> = Vec::new();
 
     let _main_loop_span = tracing::info_span!("main_loop").entered();
     tracing::info!("Beginning main loop.");
     loop {
-        sleep_until(&snapshot_time);
+        let snapshot_time = earliest(&next_times).clone();
+        // The power supply is polled while snapshots are being skipped on battery.
+        let wake_time = if skipped_on_battery.is_empty() {
+            snapshot_time.clone()
+        } else {
+            snapshot_time
+                .clone()
+                .min(truncated_now().saturating_add(1.minute()))
+        };
+        match events.wait_until(&wake_time) {
+            Some(Event::ReloadConfig) => {
+                reload_config(&mut config);
+                next_times = first_snapshot_times(&config, &Zoned::now());
+                tracing::info!("Next snapshot time: {}.", earliest(&next_times));
+                continue;
+            }
+            Some(Event::Trigger(x)) => {
+                trigger::on_trigger(&config, &mut trigger_limiter, &x);
+                continue;
+            }
+            Some(Event::Control(request, stream)) => {
+                control::respond(&config, request, stream);
+                continue;
+            }
+            Some(Event::Stop) => {
+                let deadline = start_stop_deadline(&config);
+                if config.snapshot_on_stop {
+                    take_final_snapshots(&config, &deadline);
+                }
+                tracing::info!("Stopped.");
+                return;
+            }
+            None => {}
+        }
+
+        if !skipped_on_battery.is_empty() && !power::on_battery() {
+            let now = truncated_now();
+
+            if !in_blackout(&config, &now) {
+                tracing::info!("Back on AC power, taking catch-up snapshots.");
+                for subvolume in config
+                    .subvolumes
+                    .iter()
+                    .filter(|x| skipped_on_battery.contains(&x.name))
+                {
+                    take_scheduled_snapshot(&config, subvolume, &now);
+                }
+            }
+            skipped_on_battery.clear();
+        }
+        if Zoned::now() < snapshot_time {
+            continue;
+        }
+
+        // Each due subvolume with the time its snapshot is named after and whether it is a
+        // catch-up snapshot. Waking after a subvolume's following scheduled time as well means the
+        // machine was asleep or stalled, so the missed times are replaced by a single catch-up
+        // snapshot or skipped rather than all being taken at once.
+        let now = truncated_now();
+        let mut due_subvolumes: Vec<(usize, Zoned, bool)> = Vec::new();
+        for (i, subvolume) in config.subvolumes.iter().enumerate() {
+            let timing = subvolume_timing(&config, subvolume);
+
+            if next_times[i] > now {
+                continue;
+            }
+            if now < timing.next_after(&next_times[i]) {
+                due_subvolumes.push((i, next_times[i].clone(), false));
+            } else if config.catch_up {
+                tracing::info!(
+                    "Missed scheduled snapshots of {}, taking a catch-up snapshot.",
+                    subvolume.name
+                );
+                due_subvolumes.push((i, now.clone(), true));
+            } else {
+                next_times[i] = timing.first_at_or_after(&now);
+                tracing::info!(
+                    "Missed scheduled snapshots of {}, skipping to {}.",
+                    subvolume.name,
+                    &next_times[i]
+                );
+            }
+        }
+        due_subvolumes.retain(|(i, time, _)| {
+            if !in_blackout(&config, time) {
+                return true;
+            }
+            let subvolume = &config.subvolumes[*i];
+            next_times[*i] = subvolume_timing(&config, subvolume)
+                .first_at_or_after(&time.saturating_add(1.second()));
+            tracing::info!(
+                "Skipping the snapshot of {} at {} as it is in a blackout window, next at {}.",
+                subvolume.name,
+                time,
+                &next_times[*i]
+            );
+            false
+        });
+        if due_subvolumes.is_empty() {
+            continue;
+        }
+        if config.skip_on_battery && power::on_battery() {
+            for (i, time, _) in due_subvolumes {
+                let subvolume = &config.subvolumes[i];
+
+                next_times[i] = subvolume_timing(&config, subvolume)
+                    .first_at_or_after(&time.saturating_add(1.second()));
+                if !skipped_on_battery.contains(&subvolume.name) {
+                    skipped_on_battery.push(subvolume.name.clone());
+                }
+            }
+            tracing::info!(
+                "On battery power, skipping the cycle. Next snapshot time: {}.",
+                earliest(&next_times)
+            );
+            continue;
+        }
+
+        // Once stopped, the rest of the cycle is snapshotted straight away and pruned before
+        // exiting, leaving no subvolume with a half finished cycle.
+        let mut reload_pending = false;
+        let mut stop_deadline = None;
+        let deferred_until = defer_cycle(
+            &config,
+            &events,
+            &mut trigger_limiter,
+            &mut reload_pending,
+            &mut stop_deadline,
+        );
+
+        // Snapshots are named after the scheduled time even when staggered or deferred so each
+        // cycle's snapshots line up as a set.
+        let mut staggered_subvolumes: Vec<(Zoned, &SubvolumeConfig, &Zoned)> = due_subvolumes
+            .iter()
+            .map(|(i, time, _)| {
+                let subvolume = &config.subvolumes[*i];
+                let start_time = deferred_until.as_ref().unwrap_or(time);
+                (
+                    start_time.saturating_add(stagger_offset(&config, subvolume, time)),
+                    subvolume,
+                    time,
+                )
+            })
+            .collect();
+        // A group's members all start at the earliest of their staggered times and are taken
+        // back-to-back in the group's order, keeping its snapshots as close together as possible.
+        let group_start_times: Vec<Option<Zoned>> = (0..config.groups.len())
+            .map(|i| {
+                staggered_subvolumes
+                    .iter()
+                    .filter(|x| group::group_of(&config, x.1).is_some_and(|(y, _)| y == i))
+                    .map(|x| x.0.clone())
+                    .min()
+            })
+            .collect();
+        for (due_time, subvolume, _) in staggered_subvolumes.iter_mut() {
+            if let Some((i, _)) = group::group_of(&config, subvolume)
+                && let Some(x) = &group_start_times[i]
+            {
+                *due_time = x.clone();
+            }
+        }
+        staggered_subvolumes.sort_by_key(|x| {
+            let member = group::group_of(&config, x.1).map(|(i, group)| {
+                let position = group.subvolumes.iter().position(|y| *y == x.1.name);
+                (i, position)
+            });
+            (x.0.clone(), member)
+        });
+
+        let mut previous_finished: Option<(Zoned, Option<usize>)> = None;
+        let mut group_members_taken: BTreeMap<&str, PathBuf> = BTreeMap::new();
+        for (due_time, subvolume, time) in staggered_subvolumes {
+            let group = group::group_of(&config, subvolume).map(|(i, _)| i);
+            let due_time = match (&previous_finished, config.stagger_spacing) {
+                (Some((_, Some(x))), _) if group == Some(*x) => due_time,
+                (Some((x, _)), Some(y)) => due_time.max(x.saturating_add(y)),
+                _ => due_time,
+            };
+            while stop_deadline.is_none()
+                && let Some(event) = events.wait_until(&due_time)
+            {
+                handle_cycle_event(
+                    &config,
+                    &mut trigger_limiter,
+                    event,
+                    &mut reload_pending,
+                    &mut stop_deadline,
+                );
+            }
+
+            let taken = take_scheduled_snapshot(&config, subvolume, time);
+            if group.is_some()
+                && let Some(x) = taken
+            {
+                group_members_taken.insert(&subvolume.name, x);
+            }
+            previous_finished = Some((Zoned::now(), group));
+        }
+        record_group_snapshots(&config, &due_subvolumes, &group_members_taken);
+
+        for subvolume in due_subvolumes
+            .iter()
+            .map(|(i, _, _)| &config.subvolumes[*i])
+        {
+            let hook_env = hooks::subvolume_env(subvolume);
+
+            if let Err(e) =
+                hooks::run_hook("pre_prune", config.hooks.pre_prune.as_deref(), &hook_env)
+            {
+                hooks::report_error(&config.hooks, &hook_env, &e);
+                continue;
+            }
+            prune_snapshots(&config, subvolume, &mut retention_metrics);
+            trigger::prune_trigger_snapshots(&config, subvolume);
+            origin::prune_origin_snapshots(&config, subvolume);
+            if let Err(e) =
+                hooks::run_hook("post_prune", config.hooks.post_prune.as_deref(), &hook_env)
+            {
+                hooks::report_error(&config.hooks, &hook_env, &e);
+            }
+        }
+        if let Some(x) = &config.metrics_path
+            && let Err(e) = retention_metrics.write(x)
+        {
+            tracing::error!("Error writing metrics file. {}", e);
+        }
+        if stop_deadline.is_some() {
+            tracing::info!("Stopped after finishing the current cycle.");
+            return;
+        }
+
+        for replication in config.replication.iter() {
+            if last_replication_times
+                .get(&replication.name)
+                .is_none_or(|x| {
+                    replication
+                        .interval
+                        .is_none_or(|y| x.saturating_add(y) <= snapshot_time)
+                })
+            {
+                replication::replicate(&config, replication);
+                last_replication_times.insert(replication.name.clone(), snapshot_time.clone());
+            }
+        }
+
+        if let Some(drill_config) = &config.restore_drill
+            && last_drill_time
+                .as_ref()
+                .is_none_or(|x| x.saturating_add(drill_config.interval) <= snapshot_time)
+        {
+            match drill::restore_drill(&config, drill_config) {
+                Ok(()) => tracing::info!("Restore drill succeeded."),
+                Err(e) => tracing::error!("Restore drill failed. {}", e),
+            }
+            last_drill_time = Some(snapshot_time.clone());
+        }
+
+        if reload_pending {
+            reload_config(&mut config);
+            next_times = first_snapshot_times(&config, &Zoned::now());
+        } else {
+            for (i, time, catching_up) in due_subvolumes {
+                let timing = subvolume_timing(&config, &config.subvolumes[i]);
+
+                // A catch-up snapshot is taken off schedule, so the schedule is rejoined from it.
+                next_times[i] = if catching_up {
+                    timing.first_at_or_after(&time.saturating_add(1.second()))
+                } else {
+                    timing.next_after(&time)
+                };
+            }
+        }
+        tracing::info!("Next snapshot time: {}.", earliest(&next_times))
+    }
+}
+
+/// Waits while the system is too busy to snapshot, up to defer_max, handling events in the
+/// meantime. Returns when the deferral ended if the cycle was deferred.
+fn defer_cycle(
+    config: &Config,
+    events: &EventReceiver,
+    trigger_limiter: &mut TriggerLimiter,
+    reload_pending: &mut bool,
+    stop_deadline: &mut Option<Zoned>,
+) -> Option<Zoned> {
+    let defer_limit = truncated_now().saturating_add(config.defer_max);
+    let mut deferred = false;
+
+    while stop_deadline.is_none()
+        && let Some(reason) = pressure::busy(config)
+    {
+        let now = truncated_now();
+        if now >= defer_limit {
+            tracing::warn!(
+                "Still busy after defer_max as {}, not deferring further.",
+                reason
+            );
+            break;
+        }
+        let recheck_time = now.saturating_add(1.minute()).min(defer_limit.clone());
+        tracing::info!(
+            "Deferring the cycle as {}, checking again at {}.",
+            reason,
+            recheck_time
+        );
+        deferred = true;
 
-        let mut snapshot_path = config.snapshot_path.clone();
-        snapshot_path.push(
-            config.subvolume_name.clone() + "-" + &snapshot_time.to_string().replace("/", "__"),
+        while stop_deadline.is_none()
+            && let Some(event) = events.wait_until(&recheck_time)
+        {
+            handle_cycle_event(
+                config,
+                trigger_limiter,
+                event,
+                reload_pending,
+                stop_deadline,
+            );
+        }
+    }
+
+    deferred.then(truncated_now)
+}
+
+/// Handles an event received part way through a cycle, holding reloads until it ends.
+fn handle_cycle_event(
+    config: &Config,
+    trigger_limiter: &mut TriggerLimiter,
+    event: Event,
+    reload_pending: &mut bool,
+    stop_deadline: &mut Option<Zoned>,
+) {
+    match event {
+        Event::ReloadConfig => *reload_pending = true,
+        Event::Trigger(x) => trigger::on_trigger(config, trigger_limiter, &x),
+        Event::Stop => *stop_deadline = Some(start_stop_deadline(config)),
+        Event::Control(request, stream) => control::respond(config, request, stream),
+    }
+}
+
+/// Takes a scheduled snapshot of `subvolume` unless the failure policy is holding it back after
+/// earlier failures, recording the outcome in the state file. Returns the snapshot taken, if one
+/// was.
+fn take_scheduled_snapshot(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot_time: &Zoned,
+) -> Option<PathBuf> {
+    if let Some(x) = state::held_back(config, &subvolume.name, snapshot_time) {
+        tracing::warn!("Not snapshotting {}, {}.", subvolume.name, x);
+        return None;
+    }
+
+    let result = take_snapshot(config, subvolume, snapshot_time);
+    record_outcome(config, subvolume, snapshot_time, &result);
+    result.ok().flatten()
+}
+
+/// Records the set of snapshots each group with members in `due_subvolumes` took this cycle,
+/// from `taken` by subvolume name, warning when some members have no snapshot in it.
+fn record_group_snapshots(
+    config: &Config,
+    due_subvolumes: &[(usize, Zoned, bool)],
+    taken: &BTreeMap<&str, PathBuf>,
+) {
+    for group in config.groups.iter() {
+        let Some((_, time, _)) = due_subvolumes
+            .iter()
+            .find(|(i, _, _)| group.subvolumes.contains(&config.subvolumes[*i].name))
+        else {
+            continue;
+        };
+        let snapshots: BTreeMap<String, String> = group
+            .subvolumes
+            .iter()
+            .filter_map(|x| {
+                Some((
+                    x.clone(),
+                    taken.get(x.as_str())?.to_string_lossy().to_string(),
+                ))
+            })
+            .collect();
+
+        if snapshots.is_empty() {
+            continue;
+        }
+        if snapshots.len() < group.subvolumes.len() {
+            tracing::warn!(
+                "Group {} has snapshots of only {} of its {} subvolumes at {}, so it can't be rolled back to that time.",
+                group.name,
+                snapshots.len(),
+                group.subvolumes.len(),
+                time
+            );
+        }
+        state::record_group(config, &group.name, time, snapshots);
+    }
+}
+
+/// Takes a snapshot of `subvolume` from `origin` now as asked for over the control socket,
+/// regardless of the schedule or skip_unchanged, recording the outcome in the state file. Nothing
+/// is taken within min_interval of the newest snapshot in the origin's series or the timeline.
+fn take_origin_snapshot(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    origin: Origin,
+) -> Result<PathBuf, String> {
+    let snapshot_time = truncated_now();
+    if let Some(min_interval) = config.min_interval {
+        let series = series_snapshots(
+            config,
+            subvolume,
+            &subvolume.snapshot_path,
+            &origin.series_name(subvolume),
+        )
+        .unwrap_or_default();
+        let timeline = matching_snapshots(config, subvolume).unwrap_or_default();
+        if let Some(newest) = series.iter().chain(timeline.iter()).max()
+            && newest.time.saturating_add(min_interval) > snapshot_time
+        {
+            return Err(format!(
+                "Not snapshotting {} as {} is less than {:#} older.",
+                subvolume.name,
+                newest.snapshot_path.to_string_lossy(),
+                min_interval
+            ));
+        }
+    }
+    let destination = subvolume.snapshot_path.join(config.name_template.render(
+        &origin.series_name(subvolume),
+        &snapshot_time.with_time_zone(name_time_zone(config)),
+    ));
+    let result = create_snapshot(
+        config,
+        subvolume,
+        &destination,
+        &snapshot_time,
+        hooks::subvolume_env(subvolume),
+    )
+    .map(|()| Some(destination.clone()));
+
+    record_outcome(config, subvolume, &snapshot_time, &result);
+    result.map(|_| destination)
+}
+
+/// Records the outcome of snapshotting `subvolume` at `snapshot_time` in the state file, with
+/// the generation of the snapshot taken, if one was.
+fn record_outcome(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot_time: &Zoned,
+    result: &Result<Option<PathBuf>, String>,
+) {
+    let generation = match result {
+        Ok(Some(x)) => match subvolume.backend.generations(x) {
+            Ok((_, creation)) => Some((x.as_path(), creation)),
+            Err(e) => {
+                tracing::warn!(
+                    "Error reading the generation of {}. {}",
+                    x.to_string_lossy(),
+                    e
+                );
+                None
+            }
+        },
+        _ => None,
+    };
+    state::record_snapshot(
+        config,
+        &subvolume.name,
+        snapshot_time,
+        &result.as_ref().map(|_| ()).map_err(Clone::clone),
+        generation,
+    );
+}
+
+/// Takes a snapshot of `subvolume` named after `snapshot_time`, running the snapshot hooks around
+/// it. Skipped if the subvolume already has a snapshot less than min_interval older.
+fn take_snapshot(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot_time: &Zoned,
+) -> Result<Option<PathBuf>, String> {
+    let snapshots = matching_snapshots(config, subvolume).unwrap_or_default();
+    let newest = snapshots.last();
+
+    if let Some(min_interval) = config.min_interval
+        && let Some(newest) = newest
+        && newest.time.saturating_add(min_interval) > *snapshot_time
+    {
+        tracing::info!(
+            "Skipping snapshot of {} as {} is less than {:#} older.",
+            subvolume.name,
+            newest.snapshot_path.to_string_lossy(),
+            min_interval
         );
-        if let Err(e) = create_btrfs_snapshot(
-            config.subvolume_path.as_path(),
-            snapshot_path.as_path(),
-            true,
-        ) {
-            eprintln!("{}", e);
-        }
-
-        let snapshots = btrfs_snapshots(config.snapshot_path.as_path());
-
-        match snapshots {
-            Ok(x) => {
-                let mut matching_snapshots: Vec<Snapshot> = Vec::with_capacity(x.len());
-                let subvolume_name = config.subvolume_name.clone() + "-";
-
-                for snapshot in x.iter() {
-                    let snapshot_dirname = snapshot
-                        .file_name()
-                        .expect("Snapshot path should be valid.")
-                        .to_str()
-                        .expect("Snapshot path should be valid utf8.");
-
-                    if snapshot_dirname.starts_with(&subvolume_name) {
-                        matching_snapshots.push(Snapshot {
-                            snapshot_path: snapshot.to_path_buf(),
-                            time: snapshot_dirname.replace("__", "/")[subvolume_name.len()..]
-                                .parse()
-                                .expect("Time string should be parsed by jiff."),
-                            keep: false,
-                        })
-                    }
+        return Ok(None);
+    }
+    // Group members are snapshotted regardless, so every set of the group is complete.
+    if config.skip_unchanged
+        && group::group_of(config, subvolume).is_none()
+        && let Some(newest) = newest
+    {
+        match unchanged_since(config, subvolume, newest) {
+            Ok(true) => {
+                tracing::info!(
+                    "Skipping snapshot of {} as it hasn't changed since {}.",
+                    subvolume.name,
+                    newest.snapshot_path.to_string_lossy()
+                );
+                return Ok(None);
+            }
+            Ok(false) => {}
+            Err(e) => tracing::warn!(
+                "Error comparing generations of {}, taking the snapshot anyway. {}",
+                subvolume.name,
+                e
+            ),
+        }
+    }
+
+    let destination = snapshot_path(config, subvolume, snapshot_time);
+    create_snapshot(
+        config,
+        subvolume,
+        &destination,
+        snapshot_time,
+        hooks::subvolume_env(subvolume),
+    )?;
+
+    Ok(Some(destination))
+}
+
+/// Snapshots `subvolume` to `destination`, running the snapshot hooks around it with `hook_env`
+/// and the snapshot's path and time. Errors are reported as they happen and also returned.
+fn create_snapshot(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    destination: &Path,
+    snapshot_time: &Zoned,
+    mut hook_env: Vec<(&'static str, String)>,
+) -> Result<(), String> {
+    hook_env.push((
+        "SNAPSHOTTER_SNAPSHOT_PATH",
+        destination.to_string_lossy().to_string(),
+    ));
+    hook_env.push(("SNAPSHOTTER_SNAPSHOT_TIME", snapshot_time.to_string()));
+
+    if let Err(e) = check_headroom(config, subvolume) {
+        hooks::report_error(&config.hooks, &hook_env, &e);
+        return Err(e);
+    }
+    // A failed pre_snapshot hook may have left the subvolume unprepared, e.g. a database that
+    // wasn't quiesced, so no snapshot is taken.
+    if let Err(e) = hooks::run_hook(
+        "pre_snapshot",
+        config.hooks.pre_snapshot.as_deref(),
+        &hook_env,
+    ) {
+        hooks::report_error(&config.hooks, &hook_env, &e);
+        return Err(e);
+    }
+
+    let result = subvolume
+        .backend
+        .create(&subvolume.path, destination, subvolume.readonly);
+    if let Err(e) = &result {
+        hooks::report_error(&config.hooks, &hook_env, e);
+    }
+
+    // Runs even when the snapshot failed so anything pre_snapshot paused is resumed.
+    let status = if result.is_ok() { "success" } else { "failure" };
+    hook_env.push(("SNAPSHOTTER_STATUS", status.to_string()));
+    if let Err(e) = hooks::run_hook(
+        "post_snapshot",
+        config.hooks.post_snapshot.as_deref(),
+        &hook_env,
+    ) {
+        hooks::report_error(&config.hooks, &hook_env, &e);
+    }
+
+    result
+}
+
+/// Checks the filesystem of `subvolume` has the headroom min_unallocated_bytes and
+/// min_metadata_headroom_bytes ask for. A nearly full filesystem can run out of metadata space
+/// for deleting snapshots too, which is hard to recover from, so it is better to stop creating
+/// them early. Usage that can't be read doesn't stop snapshots.
+fn check_headroom(config: &Config, subvolume: &SubvolumeConfig) -> Result<(), String> {
+    if config.min_unallocated_bytes.is_none() && config.min_metadata_headroom_bytes.is_none() {
+        return Ok(());
+    }
+    let (unallocated, metadata_free) = match subvolume.backend.allocation(&subvolume.path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::warn!(
+                "Error reading the allocation of {}, not checking headroom. {}",
+                subvolume.path.to_string_lossy(),
+                e
+            );
+            return Ok(());
+        }
+    };
+
+    if let Some(x) = config.min_unallocated_bytes
+        && unallocated < x
+    {
+        return Err(format!(
+            "Not snapshotting {} as its filesystem has {} bytes unallocated, below min_unallocated_bytes {}.",
+            subvolume.name, unallocated, x
+        ));
+    }
+    if let Some(x) = config.min_metadata_headroom_bytes
+        && metadata_free.saturating_add(unallocated) < x
+    {
+        return Err(format!(
+            "Not snapshotting {} as its filesystem has {} bytes of metadata headroom, below min_metadata_headroom_bytes {}.",
+            subvolume.name,
+            metadata_free.saturating_add(unallocated),
+            x
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns whether `subvolume` is unchanged since `snapshot` was taken of it. Taking a snapshot
+/// commits a transaction touching the subvolume too, so it is unchanged while its generation is
+/// still no newer than the one the snapshot was created in, taken from the state file when it
+/// was recorded there.
+fn unchanged_since(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot: &Snapshot,
+) -> Result<bool, String> {
+    let (generation, _) = subvolume.backend.generations(&subvolume.path)?;
+    let snapshot_creation =
+        match state::snapshot_generation(config, &subvolume.name, &snapshot.snapshot_path)
+            .or(snapshot.info.as_ref().map(|x| x.created_generation))
+        {
+            Some(x) => x,
+            None => subvolume.backend.generations(&snapshot.snapshot_path)?.1,
+        };
+
+    Ok(generation <= snapshot_creation)
+}
+
+fn in_blackout(config: &Config, time: &Zoned) -> bool {
+    config.blackout.iter().any(|x| x.contains(time))
+}
+
+/// Logs the stop and returns the time it must be finished by, after which the process exits
+/// regardless so it isn't killed part way through by the service manager.
+fn start_stop_deadline(config: &Config) -> Zoned {
+    let deadline = Zoned::now().saturating_add(config.stop_timeout);
+    tracing::info!("Stopping, finishing by {}.", deadline);
+
+    let timeout = Zoned::now().duration_until(&deadline).unsigned_abs();
+    thread::spawn(move || {
+        sleep(timeout);
+        tracing::error!("Didn't finish stopping within stop_timeout, exiting.");
+        exit(1);
+    });
+
+    deadline
+}
+
+/// Takes a snapshot of every subvolume before the daemon exits, skipping the rest once past
+/// `deadline`.
+fn take_final_snapshots(config: &Config, deadline: &Zoned) {
+    let snapshot_time = truncated_now();
+    if in_blackout(config, &snapshot_time) {
+        tracing::info!("In a blackout window, not taking final snapshots.");
+        return;
+    }
+
+    for subvolume in config.subvolumes.iter() {
+        if &Zoned::now() >= deadline {
+            tracing::warn!(
+                "Reached stop_timeout, not taking a final snapshot of {}.",
+                subvolume.name
+            );
+            continue;
+        }
+        tracing::info!("Taking a final snapshot of {}.", subvolume.name);
+        take_scheduled_snapshot(config, subvolume, &snapshot_time);
+    }
+}
+
+/// Replaces `config` with the config file's current contents, keeping the old config if the new
+/// one fails to load.
+fn reload_config(config: &mut Config) {
+    let config_file_path = init::config_file_path();
+    tracing::info!(
+        "Reloading config file {}.",
+        config_file_path.to_string_lossy()
+    );
+
+    match init::read_config(config_file_path.as_path()) {
+        Ok(x) => {
+            *config = x;
+            tracing::info!("Config reloaded.");
+            nested::warn_uncovered(config);
+            state::compact(config);
+        }
+        Err(e) => tracing::error!("Error reloading config, keeping current config. {}", e),
+    }
+}
+
+/// The snapshot timing of `subvolume`, its own if set or the global one.
+fn subvolume_timing<'a>(config: &'a Config, subvolume: &'a SubvolumeConfig) -> &'a Timing {
+    subvolume.timing.as_ref().unwrap_or(&config.timing)
+}
+
+/// The first snapshot time of each subvolume at or after `start_time`, in config order.
+fn first_snapshot_times(config: &Config, start_time: &Zoned) -> Vec<Zoned> {
+    config
+        .subvolumes
+        .iter()
+        .map(|x| subvolume_timing(config, x).first_at_or_after(start_time))
+        .collect()
+}
+
+fn earliest(times: &[Zoned]) -> &Zoned {
+    times
+        .iter()
+        .min()
+        .expect("Config should always have a subvolume.")
+}
+
+/// How long after the scheduled time to snapshot `subvolume`. An explicit stagger takes priority,
+/// otherwise subvolumes are spread deterministically across the stagger window by name. Any
+/// jitter is added on top.
+fn stagger_offset(config: &Config, subvolume: &SubvolumeConfig, snapshot_time: &Zoned) -> Span {
+    let offset = match (subvolume.stagger, config.stagger_window) {
+        (Some(x), _) => x,
+        (None, Some(window)) => {
+            ((stable_hash(&subvolume.name) % span_seconds(window, snapshot_time)) as i64).seconds()
+        }
+        (None, None) => Span::new(),
+    };
+
+    match config.stagger_jitter {
+        Some(x) => {
+            // Every RandomState is seeded differently, so this is a fresh random number.
+            let jitter =
+                RandomState::new().hash_one(&subvolume.name) % span_seconds(x, snapshot_time);
+            offset
+                .checked_add(((jitter as i64).seconds(), snapshot_time))
+                .expect("Stagger should never overflow.")
+        }
+        None => offset,
+    }
+}
+
+/// The whole seconds in `span` from `relative_to`, at least one so it can be used as a modulus.
+fn span_seconds(span: Span, relative_to: &Zoned) -> u64 {
+    let seconds = span
+        .to_duration(relative_to)
+        .expect("Stagger should fit in a duration.")
+        .as_secs();
+
+    seconds.max(1) as u64
+}
+
+/// FNV-1a, used where a hash must stay the same across restarts and builds.
+fn stable_hash(value: &str) -> u64 {
+    value.bytes().fold(0xcbf29ce484222325, |hash, x| {
+        (hash ^ x as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn snapshot_path(config: &Config, subvolume: &SubvolumeConfig, snapshot_time: &Zoned) -> PathBuf {
+    subvolume.snapshot_path.join(config.name_template.render(
+        &subvolume.name,
+        &snapshot_time.with_time_zone(name_time_zone(config)),
+    ))
+}
+
+/// Applies the retention rules to `subvolume`'s snapshots, deleting every snapshot no rule keeps.
+fn prune_snapshots(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    retention_metrics: &mut RetentionMetrics,
+) {
+    let planner_start = Instant::now();
+    let mut matching_snapshots = match matching_snapshots(config, subvolume) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+
+    let now = Zoned::now();
+    let kept = plan_retention(&config.retention, &mut matching_snapshots, &now);
+    let over_budget = apply_space_budget(
+        &*subvolume.backend,
+        &config.retention,
+        &mut matching_snapshots,
+        &now,
+    );
+    if over_budget > 0 {
+        tracing::info!(
+            "Pruning {} more snapshots of {} to fit space_budget_bytes.",
+            over_budget,
+            subvolume.name
+        );
+    }
+    enforce_min_keep(config, &subvolume.name, &mut matching_snapshots);
+    let doomed: Vec<&Snapshot> = match config.max_deletions_per_cycle {
+        Some(x) => state::take_deletion_batch(config, &subvolume.name, &matching_snapshots, x),
+        None => matching_snapshots.iter().filter(|x| !x.keep).collect(),
+    };
+    let estimated_freed_bytes = if doomed.is_empty() {
+        Some(0)
+    } else {
+        estimate_freed_bytes(subvolume, &doomed)
+    };
+    let planner_duration = planner_start.elapsed();
+    if !doomed.is_empty() {
+        match estimated_freed_bytes {
+            Some(x) => tracing::info!(
+                "Deleting {} snapshots of {}, expected to free {} bytes.",
+                doomed.len(),
+                subvolume.name,
+                x
+            ),
+            None => tracing::info!(
+                "Deleting {} snapshots of {}, space freed can't be estimated.",
+                doomed.len(),
+                subvolume.name
+            ),
+        }
+    }
+
+    let free_bytes_before = filesystem_free_bytes(subvolume.snapshot_path.as_path());
+    clones::expire(config, subvolume);
+    let mut deleted = trash::empty(config, subvolume, false);
+    let mut trashed = 0;
+    if read_only() {
+        for snapshot in doomed.iter() {
+            tracing::info!(
+                "Read-only mode, not deleting snapshot {}.",
+                snapshot.snapshot_path.to_string_lossy()
+            );
+        }
+    } else {
+        for (snapshot, result) in doomed
+            .iter()
+            .zip(trash::discard_all(config, subvolume, &doomed))
+        {
+            match result {
+                Ok(true) => deleted += 1,
+                Ok(false) => trashed += 1,
+                Err(e) => report_delete_error(config, subvolume, snapshot, &e),
+            }
+        }
+    }
+    if deleted > 0 {
+        wait_for_space_release(config, subvolume);
+    }
+    if let (Ok(before), Ok(after), Some(estimate)) = (
+        &free_bytes_before,
+        filesystem_free_bytes(subvolume.snapshot_path.as_path()),
+        estimated_freed_bytes,
+    ) && deleted > 0
+        && trashed == 0
+        && after.saturating_sub(*before) < estimate / 2
+    {
+        tracing::warn!(
+            "Pruning {} freed {} bytes, well short of the estimated {} bytes. btrfs may still be releasing the space in the background.",
+            subvolume.name,
+            after.saturating_sub(*before),
+            estimate
+        );
+    }
+    if below_min_free(config, subvolume.snapshot_path.as_path()) && config.trash_grace.is_some() {
+        tracing::warn!(
+            "Free space in {} is below the configured minimum, emptying the trash.",
+            subvolume.snapshot_path.to_string_lossy()
+        );
+        let emptied = trash::empty(config, subvolume, true);
+        if emptied > 0 {
+            deleted += emptied;
+            wait_for_space_release(config, subvolume);
+        }
+    }
+    if below_min_free(config, subvolume.snapshot_path.as_path()) {
+        tracing::warn!(
+            "Free space in {} is below the configured minimum, deleting the oldest snapshots.",
+            subvolume.snapshot_path.to_string_lossy()
+        );
+        deleted += delete_oldest_kept(config, subvolume, &mut matching_snapshots, |_| {
+            below_min_free(config, subvolume.snapshot_path.as_path())
+        });
+        if below_min_free(config, subvolume.snapshot_path.as_path()) {
+            tracing::warn!(
+                "Free space in {} is still below the configured minimum.",
+                subvolume.snapshot_path.to_string_lossy()
+            );
+        }
+    }
+    if let Ok(x) = free_bytes_before
+        && let Some(mut short) = trend::bytes_short(config, subvolume, x, &Zoned::now())
+    {
+        tracing::warn!(
+            "Deleting the oldest snapshots of {} to free {} bytes before fill_horizon.",
+            subvolume.name,
+            short
+        );
+        // Space is released in the background, so each deletion is weighed by the exclusive
+        // usage it frees rather than by watching the free space.
+        deleted += delete_oldest_kept(config, subvolume, &mut matching_snapshots, |snapshot| {
+            if short == 0 {
+                return false;
+            }
+            match subvolume.backend.exclusive_bytes(&snapshot.snapshot_path) {
+                Ok(x) => {
+                    short = short.saturating_sub(x);
+                    true
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Error reading the usage of {}, not pruning for fill_horizon. {}",
+                        snapshot.snapshot_path.to_string_lossy(),
+                        e
+                    );
+                    false
                 }
-                matching_snapshots.sort();
+            }
+        });
+    }
+    let freed_bytes = match (
+        free_bytes_before,
+        filesystem_free_bytes(subvolume.snapshot_path.as_path()),
+    ) {
+        (Ok(before), Ok(after)) => after.saturating_sub(before),
+        _ => 0,
+    };
 
-                for (i, snapshot) in matching_snapshots.iter_mut().rev().enumerate() {
-                    if i >= config.hourly_limit {
-                        break;
-                    }
+    retention_metrics.record_cycle(
+        &subvolume.name,
+        kept,
+        deleted,
+        estimated_freed_bytes,
+        freed_bytes,
+        planner_duration,
+    );
+}
 
-                    snapshot.keep = true;
+/// Deletes the oldest of the snapshots retention keeps, one at a time while `more` says the next
+/// one should go, returning how many were deleted. The newest snapshots under min_keep and those
+/// protected by immutable_for are never deleted. `snapshots` must be sorted oldest first.
+fn delete_oldest_kept(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshots: &mut [Snapshot],
+    mut more: impl FnMut(&Snapshot) -> bool,
+) -> usize {
+    // The newest snapshot is never deleted so the subvolume always has one to restore from.
+    let candidates = snapshots.len().saturating_sub(config.min_keep.max(1));
+    let mut deleted = 0;
+
+    for snapshot in snapshots[..candidates]
+        .iter_mut()
+        .filter(|x| x.keep && !is_immutable(config, x))
+    {
+        if !more(snapshot) {
+            break;
+        }
+        if read_only() {
+            tracing::info!(
+                "Read-only mode, not deleting snapshot {}.",
+                snapshot.snapshot_path.to_string_lossy()
+            );
+            break;
+        }
+        match verify_snapshot(&*subvolume.backend, &snapshot.snapshot_path)
+            .and_then(|()| delete_btrfs_snapshot(config, &*subvolume.backend, snapshot))
+        {
+            Ok(()) => {
+                snapshot.keep = false;
+                deleted += 1;
+                wait_for_space_release(config, subvolume);
+            }
+            Err(e) => report_delete_error(config, subvolume, snapshot, &e),
+        }
+    }
+
+    deleted
+}
+
+/// Marks the snapshots `policy` keeps as of `now`, returning how many each rule kept.
+fn plan_retention(
+    policy: &RetentionPolicy,
+    snapshots: &mut [Snapshot],
+    now: &Zoned,
+) -> Vec<(String, usize)> {
+    let times: Vec<Zoned> = snapshots.iter().map(|x| x.time.clone()).collect();
+    let plan = retention::plan(policy, &times, now);
+
+    for (snapshot, keep) in snapshots.iter_mut().zip(plan.keep) {
+        snapshot.keep = keep;
+    }
+
+    plan.kept_by
+}
+
+/// Keeps the newest min_keep of `snapshots` even if no retention rule does, so a bad edit to the
+/// limits can't delete every snapshot in one cycle. `snapshots` must be sorted oldest first.
+fn enforce_min_keep(config: &Config, series: &str, snapshots: &mut [Snapshot]) {
+    let mut keep: Vec<bool> = snapshots.iter().map(|x| x.keep).collect();
+    let saved = retention::enforce_min_keep(&mut keep, config.min_keep);
+
+    for (snapshot, keep) in snapshots.iter_mut().zip(keep) {
+        snapshot.keep = keep;
+    }
+    if saved > 0 {
+        tracing::warn!(
+            "Keeping {} snapshots of {} that retention would delete, to keep min_keep {}.",
+            saved,
+            series,
+            config.min_keep
+        );
+    }
+}
+
+/// Stops keeping the oldest of the snapshots `policy` keeps until the exclusive usage of the rest
+/// fits in its space budget, returning how many were given up. The newest snapshot and those
+/// protected by immutable_for are always kept. `snapshots` must be sorted oldest first.
+fn apply_space_budget(
+    backend: &dyn SnapshotBackend,
+    policy: &RetentionPolicy,
+    snapshots: &mut [Snapshot],
+    now: &Zoned,
+) -> usize {
+    let Some(budget) = policy.space_budget_bytes else {
+        return 0;
+    };
+    let mut usage = Vec::with_capacity(snapshots.len());
+    for snapshot in snapshots.iter() {
+        if !snapshot.keep {
+            usage.push(0);
+            continue;
+        }
+        match backend.exclusive_bytes(&snapshot.snapshot_path) {
+            Ok(x) => usage.push(x),
+            Err(e) => {
+                tracing::warn!(
+                    "Error reading the usage of {}, not applying space_budget_bytes. {}",
+                    snapshot.snapshot_path.to_string_lossy(),
+                    e
+                );
+                return 0;
+            }
+        }
+    }
+
+    // Data shared only with a deleted snapshot becomes exclusive to the ones left, so this can
+    // underestimate and the budget is met over the following cycles.
+    let mut total: u64 = usage.iter().sum();
+    let newest = snapshots.len().saturating_sub(1);
+    let mut given_up = 0;
+    for (snapshot, bytes) in snapshots[..newest].iter_mut().zip(usage) {
+        if total <= budget {
+            break;
+        }
+        if !snapshot.keep
+            || policy
+                .immutable_for
+                .is_some_and(|x| snapshot.time > now.saturating_sub(x))
+        {
+            continue;
+        }
+        snapshot.keep = false;
+        total -= bytes;
+        given_up += 1;
+    }
+    if total > budget {
+        tracing::warn!(
+            "Snapshots use {} bytes, still over space_budget_bytes {} with only protected snapshots left.",
+            total,
+            budget
+        );
+    }
+
+    given_up
+}
+
+/// Returns when each of `snapshots` will first be pruned under the current policy, assuming
+/// `subvolume`'s snapshots keep being taken on schedule from `now`. `None` means it is still kept
+/// after `max_cycles` more snapshots.
+fn projected_expiry(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshots: &[Snapshot],
+    now: &Zoned,
+    max_cycles: usize,
+) -> Vec<Option<Zoned>> {
+    let mut simulated: Vec<Snapshot> = snapshots
+        .iter()
+        .map(|x| Snapshot {
+            snapshot_path: x.snapshot_path.clone(),
+            time: x.time.clone(),
+            keep: false,
+            info: None,
+        })
+        .collect();
+    let mut expiry = vec![None; snapshots.len()];
+    let timing = subvolume_timing(config, subvolume);
+    let mut time = timing.first_at_or_after(now);
+
+    plan_retention(&config.retention, &mut simulated, now);
+    for (i, snapshot) in simulated.iter().enumerate() {
+        if !snapshot.keep {
+            expiry[i] = Some(now.clone());
+        }
+    }
+
+    for _ in 0..max_cycles {
+        if expiry.iter().all(Option::is_some) {
+            break;
+        }
+        // Snapshots pruned in earlier cycles are gone, so they no longer fill retention buckets.
+        simulated.retain(|x| x.keep);
+        simulated.push(Snapshot {
+            snapshot_path: PathBuf::new(),
+            time: time.clone(),
+            keep: false,
+            info: None,
+        });
+        for snapshot in simulated.iter_mut() {
+            snapshot.keep = false;
+        }
+        plan_retention(&config.retention, &mut simulated, &time);
+
+        for snapshot in simulated.iter().filter(|x| !x.keep) {
+            if let Some(i) = snapshots
+                .iter()
+                .position(|x| x.snapshot_path == snapshot.snapshot_path)
+                && expiry[i].is_none()
+            {
+                expiry[i] = Some(time.clone());
+            }
+        }
+        time = timing.next_after(&time);
+    }
+
+    expiry
+}
+
+/// Estimates the bytes deleting `snapshots` would free from their exclusive usage, the data no
+/// other subvolume shares. Returns `None` if the usage of any snapshot can't be read.
+fn estimate_freed_bytes(subvolume: &SubvolumeConfig, snapshots: &[&Snapshot]) -> Option<u64> {
+    let mut estimate = 0;
+
+    for snapshot in snapshots {
+        match subvolume.backend.exclusive_bytes(&snapshot.snapshot_path) {
+            Ok(x) => estimate += x,
+            Err(e) => {
+                tracing::debug!("Error reading snapshot usage. {}", e);
+                return None;
+            }
+        }
+    }
+
+    Some(estimate)
+}
+
+/// Matches `name` against a glob `pattern` where `*` matches any run of characters and `?` any
+/// single character.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was seen and how much of the name it had matched, to backtrack to.
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(x) if *x == '?' || *x == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
                 }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|x| *x == '*')
+}
 
-                for snapshot in matching_snapshots.iter() {
-                    if !snapshot.keep
-                        && let Err(e) = delete_btrfs_snapshot(snapshot.snapshot_path.as_path())
-                    {
-                        tracing::error!("{}", e);
-                    }
+fn name_time_zone(config: &Config) -> TimeZone {
+    config
+        .timestamp_timezone
+        .clone()
+        .unwrap_or_else(TimeZone::system)
+}
+
+/// Waits up to deletion_sync_timeout for btrfs to finish cleaning up deleted snapshots, as the
+/// space they held is only released once it has.
+fn wait_for_space_release(config: &Config, subvolume: &SubvolumeConfig) {
+    let Some(timeout) = config.deletion_sync_timeout else {
+        return;
+    };
+
+    tracing::info!("Waiting for deleted snapshots to be cleaned up.");
+    match subvolume.backend.sync(&subvolume.snapshot_path, timeout) {
+        Ok(true) => (),
+        Ok(false) => tracing::warn!(
+            "Deleted snapshots of {} still aren't cleaned up after {:#}.",
+            subvolume.name,
+            timeout
+        ),
+        Err(e) => tracing::error!("{}", e),
+    }
+}
+
+/// Runs `btrfs subvolume sync` on `dir` until it finishes or `timeout` passes, returning whether
+/// it finished.
+fn sync_btrfs_deletions(btrfs: &BtrfsCommand, dir: &Path, timeout: Span) -> Result<bool, String> {
+    let deadline = Zoned::now().saturating_add(timeout);
+
+    let mut command = btrfs.command();
+    command
+        .args(["subvolume", "sync"])
+        .arg(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let mut child = command
+        .spawn()
+        .map_err(|e| format!("Error running btrfs subvolume sync. {}", e))?;
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(x)) if x.success() => return Ok(true),
+            Ok(Some(x)) => return Err(format!("btrfs subvolume sync failed with {}.", x)),
+            Ok(None) if Zoned::now() >= deadline => {
+                if let Err(e) = child.kill().and_then(|()| child.wait()) {
+                    tracing::error!("Error stopping btrfs subvolume sync. {}", e);
                 }
+                return Ok(false);
             }
-            Err(e) => tracing::error!("{}", e),
+            Ok(None) => sleep(Duration::from_millis(500)),
+            Err(e) => return Err(format!("Error waiting for btrfs subvolume sync. {}", e)),
+        }
+    }
+}
+
+fn report_delete_error(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot: &Snapshot,
+    error: &str,
+) {
+    let mut hook_env = hooks::subvolume_env(subvolume);
+    hook_env.push((
+        "SNAPSHOTTER_SNAPSHOT_PATH",
+        snapshot.snapshot_path.to_string_lossy().to_string(),
+    ));
+    hooks::report_error(&config.hooks, &hook_env, error);
+}
+
+/// Returns the snapshots of `subvolume`, sorted oldest first.
+fn matching_snapshots(config: &Config, subvolume: &SubvolumeConfig) -> io::Result<Vec<Snapshot>> {
+    series_snapshots(config, subvolume, &subvolume.snapshot_path, &subvolume.name)
+}
+
+/// Returns the snapshots of `subvolume` in `snapshot_dir` named after `series`, sorted oldest
+/// first.
+fn series_snapshots(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshot_dir: &Path,
+    series: &str,
+) -> io::Result<Vec<Snapshot>> {
+    let snapshots = subvolume.backend.list(snapshot_dir)?;
+    let mut matching_snapshots: Vec<Snapshot> = Vec::with_capacity(snapshots.len());
+    let time_zone = name_time_zone(config);
+
+    for snapshot in snapshots {
+        let snapshot_dirname = snapshot
+            .path
+            .file_name()
+            .expect("Snapshot path should be valid.")
+            .to_str()
+            .expect("Snapshot path should be valid utf8.");
+
+        if config
+            .ignore_patterns
+            .iter()
+            .any(|x| glob_match(x, snapshot_dirname))
+        {
+            continue;
         }
 
-        snapshot_time = snapshot_time
-            .checked_add(1.hour())
-            .expect("Time should never be near Zoned limit.");
-        tracing::info!("Next snapshot time: {}.", &snapshot_time)
+        // Another subvolume's name may start with this one's, so only names that parse belong
+        // to this subvolume.
+        if let Some(time) = config
+            .name_template
+            .parse(series, snapshot_dirname, &time_zone)
+        {
+            // Names may embed other zones from before timestamp_timezone was changed, so
+            // retention buckets consistently in one zone.
+            matching_snapshots.push(Snapshot {
+                snapshot_path: snapshot.path.clone(),
+                time: time.with_time_zone(time_zone.clone()),
+                keep: false,
+                info: Some(snapshot),
+            })
+        }
     }
+    matching_snapshots.sort();
+
+    Ok(matching_snapshots)
 }
 
-fn btrfs_snapshots(snapshot_dir: &Path) -> io::Result<Vec<PathBuf>> {
+/// Returns the newest of `snapshots` taken at or before `time`. `snapshots` must be sorted oldest
+/// first.
+fn nearest_snapshot<'a>(snapshots: &'a [Snapshot], time: &Zoned) -> Option<&'a Snapshot> {
+    let end = snapshots.partition_point(|x| x.time <= *time);
+
+    end.checked_sub(1).map(|x| &snapshots[x])
+}
+
+/// Returns every subvolume directly in `snapshot_dir`, skipping plain directories.
+fn btrfs_snapshots(btrfs: &BtrfsCommand, snapshot_dir: &Path) -> io::Result<Vec<SubvolumeInfo>> {
     tracing::info!(
         "Getting btrfs snapshots from snapshot dir: {}.",
         snapshot_dir.to_string_lossy()
@@ -173,54 +1852,343 @@ fn btrfs_snapshots(snapshot_dir: &Path) -> io::Result<Vec<PathBuf>> {
 
     for entry in snapshot_dir.read_dir()? {
         let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
 
-        if path.is_dir() {
-            btrfs_snapshots.push(PathBuf::from(
-                path.to_str()
-                    .expect("Path should be valid utf8.")
-                    .replace("___", "/"),
-            ));
+        if btrfs.native() {
+            if let Some(x) = ioctl::subvolume_info(&path)? {
+                btrfs_snapshots.push(x);
+            }
+        } else {
+            // btrfs can't show a plain directory.
+            match btrfs_subvolume_info(btrfs, &path) {
+                Ok(x) => btrfs_snapshots.push(x),
+                Err(e) => tracing::debug!("Skipping {}. {}", path.to_string_lossy(), e),
+            }
         }
     }
 
     Ok(btrfs_snapshots)
 }
 
-fn sleep_until(next_time: &Zoned) {
-    let now = Zoned::now()
+/// Returns what `btrfs subvolume show` says about the subvolume at `path`, read from its JSON
+/// where btrfs can write it.
+fn btrfs_subvolume_info(btrfs: &BtrfsCommand, path: &Path) -> Result<SubvolumeInfo, String> {
+    if let Some(x) = btrfs_json::output(btrfs, &["subvolume", "show"], path)?
+        .and_then(|x| btrfs_json::subvolume_info(&x, path))
+    {
+        return Ok(x);
+    }
+    let output = btrfs_subvolume_show(btrfs, path)?;
+    let field = |x: &str| subvolume_show_field(&output, path, x);
+    let number = |x: &str| {
+        field(x)?
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid {} for {}. {}", x, path.to_string_lossy(), e))
+    };
+    let uuid = |x: &str| field(x).ok().filter(|x| !x.is_empty() && x != "-");
+
+    Ok(SubvolumeInfo {
+        path: path.to_path_buf(),
+        id: number("Subvolume ID")?,
+        generation: number("Generation")?,
+        created_generation: number("Gen at creation")?,
+        uuid: uuid("UUID"),
+        parent_uuid: uuid("Parent UUID"),
+        received_uuid: uuid("Received UUID"),
+        created: parse_creation_time(&field("Creation time")?)
+            .ok()
+            .map(|x| x.timestamp()),
+    })
+}
+
+/// Returns the bytes available to unprivileged users on the filesystem containing `path`.
+fn filesystem_free_bytes(path: &Path) -> io::Result<u64> {
+    filesystem_space(path).map(|(free_bytes, _)| free_bytes)
+}
+
+/// Whether the filesystem holding `path` has less free space than min_free_bytes or
+/// min_free_percent allow. Filesystems that can't be queried are never considered full.
+fn below_min_free(config: &Config, path: &Path) -> bool {
+    let Ok((free_bytes, total_bytes)) = filesystem_space(path) else {
+        return false;
+    };
+
+    config.min_free_bytes.is_some_and(|x| free_bytes < x)
+        || config
+            .min_free_percent
+            .is_some_and(|x| free_bytes as u128 * 100 < x as u128 * total_bytes as u128)
+}
+
+/// Returns the bytes available to unprivileged users and the total size of the filesystem
+/// containing `path`.
+fn filesystem_space(path: &Path) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: path is a valid nul terminated string and stat is only read after statvfs succeeds.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    Ok((
+        stat.f_bavail as u64 * stat.f_frsize as u64,
+        stat.f_blocks as u64 * stat.f_frsize as u64,
+    ))
+}
+
+fn truncated_now() -> Zoned {
+    Zoned::now()
         .round(
             ZonedRound::new()
                 .smallest(Unit::Second)
                 .mode(RoundMode::Trunc),
         )
-        .expect("Should never fail as it matches jiff invariants.");
-    let sleep_duration = now
-        .until(next_time)
-        .expect("Should never fail as it matches jiff invariants")
-        .to_duration(&now)
-        .expect("Should never overflow span.")
-        .unsigned_abs();
+        .expect("Should never fail as it matches jiff invariants.")
+}
 
-    tracing::info!(
-        "Sleeping for {} seconds until {}.",
-        sleep_duration.as_secs_f64(),
-        next_time
-    );
-    sleep(sleep_duration);
+/// Returns the path of every subvolume on the filesystem containing `path`, relative to the
+/// filesystem's top level.
+fn btrfs_subvolume_list(btrfs: &BtrfsCommand, path: &Path) -> Result<Vec<String>, String> {
+    if btrfs.native() {
+        return ioctl::list_subvolumes(path).map_err(|e| {
+            format!(
+                "Error listing subvolumes of {}. {}",
+                path.to_string_lossy(),
+                e
+            )
+        });
+    }
+    if let Some(x) = btrfs_json::output(btrfs, &["subvolume", "list"], path)?
+        .and_then(|x| btrfs_json::subvolume_paths(&x))
+    {
+        return Ok(x);
+    }
+    let mut command = btrfs.command();
+    command.args(["subvolume", "list"]).arg(path);
+
+    let output = btrfs.output(&mut command)?;
+
+    if output.status.success() {
+        // Each line looks like "ID 256 gen 7 top level 5 path @home".
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|x| x.split_once(" path ").map(|(_, x)| x.to_string()))
+            .collect())
+    } else {
+        let stderr = str::from_utf8(&output.stderr)
+            .expect("Stderr should be utf8.")
+            .to_string();
+
+        tracing::error!("Error running btrfs command. Output: {}", stderr);
+
+        Err(stderr)
+    }
+}
+
+/// Parses a creation time as `btrfs subvolume show` writes it, like "2026-01-02 03:04:05 +0000".
+fn parse_creation_time(value: &str) -> Result<Zoned, String> {
+    Zoned::strptime("%Y-%m-%d %H:%M:%S %z", value)
+        .map(|x| x.with_time_zone(TimeZone::system()))
+        .map_err(|e| e.to_string())
+}
+
+/// Returns the generation of the subvolume at `path`, the last transaction that changed it, and
+/// the generation it was created in.
+fn btrfs_generations(btrfs: &BtrfsCommand, path: &Path) -> Result<(u64, u64), String> {
+    btrfs_subvolume_info(btrfs, path).map(|x| (x.generation, x.created_generation))
+}
+
+/// Returns the output of `btrfs subvolume show` for the subvolume at `path`.
+fn btrfs_subvolume_show(btrfs: &BtrfsCommand, path: &Path) -> Result<String, String> {
+    let mut command = btrfs.command();
+    command.args(["subvolume", "show"]).arg(path);
+
+    let output = btrfs.output(&mut command)?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Checks that the subvolume at `path` is a snapshot before it is pruned, as a plain subvolume
+/// someone named like one ours never is.
+fn verify_snapshot(backend: &dyn SnapshotBackend, path: &Path) -> Result<(), String> {
+    if backend.is_snapshot(path)? {
+        Ok(())
+    } else {
+        Err(format!(
+            "Refusing to delete {} as it isn't a snapshot.",
+            path.to_string_lossy()
+        ))
+    }
+}
+
+/// Returns the value of `field` in `output`, the `btrfs subvolume show` output for `path`.
+fn subvolume_show_field(output: &str, path: &Path, field: &str) -> Result<String, String> {
+    // Lines look like "Gen at creation:    123" once trimmed of their indent.
+    output
+        .lines()
+        .find_map(|x| {
+            x.trim()
+                .strip_prefix(field)
+                .and_then(|x| x.strip_prefix(':'))
+                .map(|x| x.trim().to_string())
+        })
+        .ok_or(format!("No {} for {}.", field, path.to_string_lossy()))
+}
+
+/// Returns the UUID of the btrfs filesystem containing `path`.
+fn btrfs_filesystem_uuid(btrfs: &BtrfsCommand, path: &Path) -> Result<String, String> {
+    let mut command = btrfs.command();
+    command.args(["filesystem", "show"]).arg(path);
+
+    let output = btrfs.output(&mut command)?;
+
+    if output.status.success() {
+        // The first line looks like "Label: 'data'  uuid: 0f2a...".
+        String::from_utf8_lossy(&output.stdout)
+            .split_once("uuid: ")
+            .and_then(|(_, x)| x.split_whitespace().next())
+            .map(str::to_string)
+            .ok_or(format!(
+                "No filesystem UUID for {}.",
+                path.to_string_lossy()
+            ))
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_string())
+    }
+}
+
+/// Returns the exclusive bytes of the subvolume at `path` from its qgroup, falling back to
+/// walking its extents with `btrfs filesystem du` when quotas are disabled.
+fn btrfs_exclusive_bytes(btrfs: &BtrfsCommand, path: &Path) -> Result<u64, String> {
+    let args = ["qgroup", "show", "--raw", "-f"];
+    if let Ok(Some(x)) = btrfs_json::output(btrfs, &args, path)
+        .map(|x| x.and_then(|x| btrfs_json::exclusive_bytes(&x)))
+    {
+        return Ok(x);
+    }
+    // qgroup lines look like "0/257  16384  16384  path", du lines like "16384  16384  0  path".
+    btrfs_command_field(btrfs, &args, path, |x| match x {
+        [qgroup, _, exclusive, ..] if qgroup.contains('/') => exclusive.parse().ok(),
+        _ => None,
+    })
+    .or_else(|_| {
+        btrfs_command_field(
+            btrfs,
+            &["filesystem", "du", "-s", "--raw"],
+            path,
+            |x| match x {
+                [_, exclusive, ..] => exclusive.parse().ok(),
+                _ => None,
+            },
+        )
+    })
+}
+
+/// Returns the unallocated bytes of the filesystem holding `path` and the free bytes in its
+/// allocated metadata chunks.
+fn btrfs_allocation(btrfs: &BtrfsCommand, path: &Path) -> Result<(u64, u64), String> {
+    let args = ["filesystem", "usage", "--raw"];
+    if let Some(x) =
+        btrfs_json::output(btrfs, &args, path)?.and_then(|x| btrfs_json::allocation(&x))
+    {
+        return Ok(x);
+    }
+    // Lines look like "Device unallocated:  1234" and "Metadata,DUP: Size:4096, Used:1024 (25.00%)".
+    let unallocated = btrfs_command_field(btrfs, &args, path, |x| match x {
+        ["Device", "unallocated:", bytes] => bytes.parse().ok(),
+        _ => None,
+    })?;
+    let metadata_free = btrfs_command_field(btrfs, &args, path, |x| match x {
+        [profile, size, used, ..] if profile.starts_with("Metadata,") => {
+            let size: u64 = size
+                .strip_prefix("Size:")?
+                .trim_end_matches(',')
+                .parse()
+                .ok()?;
+            let used: u64 = used.strip_prefix("Used:")?.parse().ok()?;
+            Some(size.saturating_sub(used))
+        }
+        _ => None,
+    })?;
+
+    Ok((unallocated, metadata_free))
+}
+
+/// Runs btrfs with `args` and `path`, returning the first value `parse_line` finds in the output
+/// lines split into fields.
+fn btrfs_command_field(
+    btrfs: &BtrfsCommand,
+    args: &[&str],
+    path: &Path,
+    parse_line: impl Fn(&[&str]) -> Option<u64>,
+) -> Result<u64, String> {
+    let mut command = btrfs.command();
+    command.args(args).arg(path);
+
+    let output = btrfs.output(&mut command)?;
+
+    if output.status.success() {
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|x| parse_line(&x.split_whitespace().collect::<Vec<&str>>()))
+            .ok_or(format!("No usage found for {}.", path.to_string_lossy()))
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr)
+            .trim_end()
+            .to_string())
+    }
+}
+
+fn read_only() -> bool {
+    READ_ONLY.load(atomic::Ordering::Relaxed)
 }
 
 fn create_btrfs_snapshot(
+    btrfs: &BtrfsCommand,
     btrfs_subvolume_path: &Path,
     snapshot_destination: &Path,
     readonly: bool,
 ) -> Result<(), String> {
-    let mut command = Command::new("btrfs");
-    let mut args: Vec<&str> = Vec::new();
+    if read_only() {
+        return Err(format!(
+            "Read-only mode, not creating snapshot {}.",
+            snapshot_destination.to_string_lossy()
+        ));
+    }
     let span = info_span!("create_btrfs_snapshot");
     let _span_guard = span.entered();
 
     tracing::info!("Creating btrfs snapshot.");
 
+    if btrfs.native() {
+        return ioctl::create_snapshot(btrfs_subvolume_path, snapshot_destination, readonly)
+            .map_err(|e| {
+                let e = format!(
+                    "Error snapshotting {} to {}. {}",
+                    btrfs_subvolume_path.to_string_lossy(),
+                    snapshot_destination.to_string_lossy(),
+                    e
+                );
+                tracing::error!("{}", e);
+                e
+            });
+    }
+    let mut command = btrfs.command();
+    let mut args: Vec<&str> = Vec::new();
+
     args.push("subvolume");
     args.push("snapshot");
 
@@ -242,10 +2210,7 @@ fn create_btrfs_snapshot(
     tracing::debug!("With args. {:?}", args);
     command.args(args);
 
-    let output = match command.output() {
-        Ok(x) => x,
-        Err(e) => return Err(e.to_string()),
-    };
+    let output = btrfs.output(&mut command)?;
 
     if output.status.success() {
         Ok(())
@@ -260,28 +2225,144 @@ fn create_btrfs_snapshot(
     }
 }
 
-fn delete_btrfs_snapshot(snapshot_path: &Path) -> Result<(), String> {
-    let mut command = Command::new("btrfs");
-    let mut args: Vec<&str> = Vec::new();
+/// Whether `snapshot` was taken or created within immutable_for, so nothing may delete it yet.
+fn is_immutable(config: &Config, snapshot: &Snapshot) -> bool {
+    let Some(window) = config.retention.immutable_for else {
+        return false;
+    };
+    let cutoff = Zoned::now().saturating_sub(window).timestamp();
+
+    snapshot.time.timestamp() > cutoff
+        || snapshot
+            .info
+            .as_ref()
+            .and_then(|x| x.created)
+            .is_some_and(|x| x > cutoff)
+}
+
+/// Deletes `snapshot`, refusing while it is immutable.
+fn delete_btrfs_snapshot(
+    config: &Config,
+    backend: &dyn SnapshotBackend,
+    snapshot: &Snapshot,
+) -> Result<(), String> {
+    delete_snapshots(config, backend, &[snapshot])
+        .pop()
+        .unwrap_or_else(|| missing_delete_result(&snapshot.snapshot_path))
+}
+
+/// The outcome for `path` when the backend returned fewer results than it was given paths,
+/// which is never counted as deleted.
+fn missing_delete_result(path: &Path) -> Result<(), String> {
+    Err(format!(
+        "No result from the backend for deleting {}.",
+        path.to_string_lossy()
+    ))
+}
+
+/// Deletes every one of `snapshots` in one go, returning the outcome for each in the same order.
+/// Every deletion of a snapshot goes through here, so those still immutable are always refused.
+fn delete_snapshots(
+    config: &Config,
+    backend: &dyn SnapshotBackend,
+    snapshots: &[&Snapshot],
+) -> Vec<Result<(), String>> {
+    let immutable: Vec<bool> = snapshots.iter().map(|x| is_immutable(config, x)).collect();
+    let deletable: Vec<&Path> = snapshots
+        .iter()
+        .zip(immutable.iter())
+        .filter(|(_, x)| !**x)
+        .map(|(x, _)| x.snapshot_path.as_path())
+        .collect();
+    let mut deleted = backend.delete(&deletable).into_iter();
+
+    snapshots
+        .iter()
+        .zip(immutable)
+        .map(|(snapshot, immutable)| {
+            if immutable {
+                Err(format!(
+                    "Not deleting {} as it is within immutable_for.",
+                    snapshot.snapshot_path.to_string_lossy()
+                ))
+            } else {
+                deleted
+                    .next()
+                    .unwrap_or_else(|| missing_delete_result(&snapshot.snapshot_path))
+            }
+        })
+        .collect()
+}
+
+/// Deletes the subvolume at `path` that isn't a snapshot in any series, such as a drill's scratch
+/// copy, which immutable_for doesn't cover.
+fn delete_subvolume(backend: &dyn SnapshotBackend, path: &Path) -> Result<(), String> {
+    backend
+        .delete(&[path])
+        .pop()
+        .unwrap_or_else(|| missing_delete_result(path))
+}
+
+/// Deletes every snapshot in `snapshot_paths` with one btrfs command and one transaction commit,
+/// returning the outcome for each in the same order.
+fn delete_btrfs_snapshots(
+    btrfs: &BtrfsCommand,
+    snapshot_paths: &[&Path],
+) -> Vec<Result<(), String>> {
+    if snapshot_paths.is_empty() {
+        return Vec::new();
+    }
+    if read_only() {
+        return snapshot_paths
+            .iter()
+            .map(|x| {
+                Err(format!(
+                    "Read-only mode, not deleting snapshot {}.",
+                    x.to_string_lossy()
+                ))
+            })
+            .collect();
+    }
     let span = info_span!("delete_btrfs_snapshot");
     let _span_guard = span.entered();
 
-    tracing::info!("Deleting btrfs snapshot.");
+    tracing::info!("Deleting {} btrfs snapshots.", snapshot_paths.len());
 
-    args.push("subvolume");
-    args.push("delete");
-    args.push("-C");
-    args.push(snapshot_path.to_str().expect("Path should be valid utf8."));
+    if btrfs.native() {
+        let results: Vec<Result<(), String>> = snapshot_paths
+            .iter()
+            .map(|x| {
+                ioctl::delete_subvolume(x).map_err(|e| {
+                    let e = format!("Error deleting {}. {}", x.to_string_lossy(), e);
+                    tracing::error!("{}", e);
+                    e
+                })
+            })
+            .collect();
+        // Committed once after the last deletion, as with btrfs subvolume delete -c.
+        // The snapshots are gone either way, a failed commit only leaves them to a later one.
+        if results.iter().any(Result::is_ok)
+            && let Some(x) = snapshot_paths.first().and_then(|x| x.parent())
+            && let Err(e) = ioctl::commit(x)
+        {
+            tracing::warn!("Error committing the deletions. {}", e);
+        }
+        return results;
+    }
+    let mut command = btrfs.command();
 
-    command.args(args);
+    // -c commits once after the last deletion, where -C would commit after each of them.
+    command
+        .args(["subvolume", "delete", "-c"])
+        .args(snapshot_paths);
 
-    let output = match command.output() {
+    let output = match btrfs.output(&mut command) {
         Ok(x) => x,
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return snapshot_paths.iter().map(|_| Err(e.clone())).collect(),
     };
 
     if output.status.success() {
-        Ok(())
+        snapshot_paths.iter().map(|_| Ok(())).collect()
     } else {
         let stderr = str::from_utf8(&output.stderr)
             .expect("Stderr should be utf8.")
@@ -289,6 +2370,16 @@ fn delete_btrfs_snapshot(snapshot_path: &Path) -> Result<(), String> {
 
         tracing::error!("Error running btrfs command. Output: {}", stderr);
 
-        Err(stderr)
+        // btrfs carries on past a snapshot it can't delete, so the ones left are the failures.
+        snapshot_paths
+            .iter()
+            .map(|x| {
+                if x.exists() {
+                    Err(stderr.clone())
+                } else {
+                    Ok(())
+                }
+            })
+            .collect()
     }
 }
@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use std::{collections::BTreeMap, fmt::Write, fs, io, path::Path, time::Duration};
+
+/// A metric's name, help text, type and how to read it from a subvolume's metrics.
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&SubvolumeMetrics) -> String,
+);
+
+/// Retention statistics per subvolume.
+#[derive(Default)]
+pub struct RetentionMetrics {
+    subvolumes: BTreeMap<String, SubvolumeMetrics>,
+}
+
+/// Retention statistics for the last cycle, plus running totals since the daemon started.
+#[derive(Default)]
+struct SubvolumeMetrics {
+    kept: Vec<(String, usize)>,
+    deleted: usize,
+    deleted_total: u64,
+    estimated_freed_bytes: Option<u64>,
+    freed_bytes: u64,
+    freed_bytes_total: u64,
+    planner_duration: Duration,
+}
+
+impl RetentionMetrics {
+    pub fn record_cycle(
+        &mut self,
+        subvolume: &str,
+        kept: Vec<(String, usize)>,
+        deleted: usize,
+        estimated_freed_bytes: Option<u64>,
+        freed_bytes: u64,
+        planner_duration: Duration,
+    ) {
+        let metrics = self.subvolumes.entry(subvolume.to_string()).or_default();
+
+        metrics.kept = kept;
+        metrics.deleted = deleted;
+        metrics.deleted_total += deleted as u64;
+        metrics.estimated_freed_bytes = estimated_freed_bytes;
+        metrics.freed_bytes = freed_bytes;
+        metrics.freed_bytes_total += freed_bytes;
+        metrics.planner_duration = planner_duration;
+    }
+
+    /// Atomically writes the metrics to `path` in the Prometheus text exposition format.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "btrfs_snapshotter_snapshots_kept",
+            "Snapshots kept by each retention tier in the last cycle.",
+            "gauge",
+        );
+        for (subvolume, metrics) in self.subvolumes.iter() {
+            for (tier, kept) in metrics.kept.iter() {
+                writeln!(
+                    output,
+                    "btrfs_snapshotter_snapshots_kept{{subvolume=\"{}\",tier=\"{}\"}} {}",
+                    escape_label(subvolume),
+                    escape_label(tier),
+                    kept
+                )
+                .expect("Writing to a String should never fail.");
+            }
+        }
+
+        let values: [Metric; 6] = [
+            (
+                "btrfs_snapshotter_snapshots_deleted",
+                "Snapshots deleted in the last cycle.",
+                "gauge",
+                |x| x.deleted.to_string(),
+            ),
+            (
+                "btrfs_snapshotter_snapshots_deleted_total",
+                "Snapshots deleted since the daemon started.",
+                "counter",
+                |x| x.deleted_total.to_string(),
+            ),
+            (
+                "btrfs_snapshotter_estimated_freed_bytes",
+                "Exclusive bytes of the snapshots planned for deletion in the last cycle, NaN if unknown.",
+                "gauge",
+                |x| match x.estimated_freed_bytes {
+                    Some(x) => x.to_string(),
+                    None => "NaN".to_string(),
+                },
+            ),
+            (
+                "btrfs_snapshotter_freed_bytes",
+                "Estimated bytes freed by the last cycle's deletions.",
+                "gauge",
+                |x| x.freed_bytes.to_string(),
+            ),
+            (
+                "btrfs_snapshotter_freed_bytes_total",
+                "Estimated bytes freed by deletions since the daemon started.",
+                "counter",
+                |x| x.freed_bytes_total.to_string(),
+            ),
+            (
+                "btrfs_snapshotter_planner_duration_seconds",
+                "Time taken to plan the last cycle's retention.",
+                "gauge",
+                |x| x.planner_duration.as_secs_f64().to_string(),
+            ),
+        ];
+        for (name, help, metric_type, value) in values.iter() {
+            write_header(&mut output, name, help, metric_type);
+            for (subvolume, metrics) in self.subvolumes.iter() {
+                writeln!(
+                    output,
+                    "{}{{subvolume=\"{}\"}} {}",
+                    name,
+                    escape_label(subvolume),
+                    value(metrics)
+                )
+                .expect("Writing to a String should never fail.");
+            }
+        }
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, output)?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(output, "# HELP {} {}", name, help).expect("Writing to a String should never fail.");
+    writeln!(output, "# TYPE {} {}", name, metric_type)
+        .expect("Writing to a String should never fail.");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,419 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use jiff::{Timestamp, Zoned, civil, tz::TimeZone};
+use std::str::FromStr;
+
+/// The names snapshots were given before templates existed.
+pub const DEFAULT_NAME_TEMPLATE: &str = "{subvolume}-{timestamp}";
+
+/// A snapshot naming template such as `{subvolume}-{year}-{month}-{day}T{hour}{minute}`, which
+/// can render names and parse them back to the time they were taken.
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(PartialEq)]
+enum Part {
+    Literal(String),
+    Subvolume,
+    /// The full zoned timestamp, encoded by `encode_component` to be filename safe.
+    Timestamp,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    /// The UTC offset as `+HHMM`, which disambiguates repeated times at DST transitions.
+    Offset,
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(0) if rest.starts_with('{') => {
+                    let end = rest
+                        .find('}')
+                        .ok_or(format!("Unclosed placeholder in name template: {}", s))?;
+                    parts.push(match &rest[1..end] {
+                        "subvolume" => Part::Subvolume,
+                        "timestamp" => Part::Timestamp,
+                        "year" => Part::Year,
+                        "month" => Part::Month,
+                        "day" => Part::Day,
+                        "hour" => Part::Hour,
+                        "minute" => Part::Minute,
+                        "second" => Part::Second,
+                        "offset" => Part::Offset,
+                        x => return Err(format!("Unknown name template placeholder: {{{}}}", x)),
+                    });
+                    rest = &rest[end + 1..];
+                }
+                Some(0) => return Err(format!("Unopened placeholder in name template: {}", s)),
+                Some(x) => {
+                    parts.push(Part::Literal(rest[..x].to_string()));
+                    rest = &rest[x..];
+                }
+                None => {
+                    parts.push(Part::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+
+        if parts
+            .iter()
+            .any(|x| matches!(x, Part::Literal(x) if x.contains('/')))
+        {
+            return Err(format!("Name template can't contain '/': {}", s));
+        }
+        if !parts.contains(&Part::Subvolume) {
+            return Err(format!("Name template must contain {{subvolume}}: {}", s));
+        }
+        if !parts.contains(&Part::Timestamp)
+            && [Part::Year, Part::Month, Part::Day, Part::Hour, Part::Minute]
+                .iter()
+                .any(|x| !parts.contains(x))
+        {
+            return Err(format!(
+                "Name template must contain {{timestamp}} or {{year}}, {{month}}, {{day}}, {{hour}} and {{minute}}: {}",
+                s
+            ));
+        }
+        for pair in parts.windows(2) {
+            if pair[0] == Part::Timestamp && !matches!(pair[1], Part::Literal(_)) {
+                return Err(format!(
+                    "Name template {{timestamp}} must be followed by text or end the template: {}",
+                    s
+                ));
+            }
+        }
+
+        Ok(Self { parts })
+    }
+}
+
+impl NameTemplate {
+    pub fn render(&self, subvolume: &str, time: &Zoned) -> String {
+        let mut name = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(x) => name.push_str(x),
+                Part::Subvolume => name.push_str(subvolume),
+                Part::Timestamp => name.push_str(&encode_component(&time.to_string())),
+                Part::Year => name.push_str(&format!("{:04}", time.year())),
+                Part::Month => name.push_str(&format!("{:02}", time.month())),
+                Part::Day => name.push_str(&format!("{:02}", time.day())),
+                Part::Hour => name.push_str(&format!("{:02}", time.hour())),
+                Part::Minute => name.push_str(&format!("{:02}", time.minute())),
+                Part::Second => name.push_str(&format!("{:02}", time.second())),
+                Part::Offset => {
+                    let seconds = time.offset().seconds();
+                    let sign = if seconds < 0 { '-' } else { '+' };
+                    let seconds = seconds.unsigned_abs();
+                    name.push_str(&format!(
+                        "{}{:02}{:02}",
+                        sign,
+                        seconds / 3600,
+                        seconds / 60 % 60
+                    ));
+                }
+            }
+        }
+
+        name
+    }
+
+    /// Returns the time a snapshot of `subvolume` named `name` was taken, or `None` if the name
+    /// wasn't rendered from this template for that subvolume. Names without a full timestamp are
+    /// read in `time_zone`.
+    pub fn parse(&self, subvolume: &str, name: &str, time_zone: &TimeZone) -> Option<Zoned> {
+        let mut rest = name;
+        let mut timestamp = None;
+        let (mut year, mut month, mut day) = (None, None, None);
+        let (mut hour, mut minute, mut second) = (None, None, 0);
+        let mut offset_seconds = None;
+
+        for (i, part) in self.parts.iter().enumerate() {
+            match part {
+                Part::Literal(x) => rest = rest.strip_prefix(x.as_str())?,
+                Part::Subvolume => rest = rest.strip_prefix(subvolume)?,
+                Part::Timestamp => {
+                    let end = match self.parts.get(i + 1) {
+                        Some(Part::Literal(x)) => rest.rfind(x.as_str())?,
+                        _ => rest.len(),
+                    };
+                    let text = &rest[..end];
+                    // Names from before the encoding was percent based used `__` for `/`, which
+                    // time zone names never contain.
+                    let decoded = if text.contains('%') {
+                        decode_component(text)?
+                    } else {
+                        text.replace("__", "/")
+                    };
+                    let parsed = decoded.parse::<Zoned>().ok()?;
+                    // Only the exact form render writes is accepted, so a hand made name that
+                    // happens to parse is never taken for one of our snapshots and pruned.
+                    if parsed.to_string() != decoded {
+                        return None;
+                    }
+                    timestamp = Some(parsed);
+                    rest = &rest[end..];
+                }
+                Part::Year => year = Some(take_number(&mut rest, 4)?),
+                Part::Month => month = Some(take_number(&mut rest, 2)? as i8),
+                Part::Day => day = Some(take_number(&mut rest, 2)? as i8),
+                Part::Hour => hour = Some(take_number(&mut rest, 2)? as i8),
+                Part::Minute => minute = Some(take_number(&mut rest, 2)? as i8),
+                Part::Second => second = take_number(&mut rest, 2)? as i8,
+                Part::Offset => {
+                    let sign = match rest.chars().next()? {
+                        '+' => 1,
+                        '-' => -1,
+                        _ => return None,
+                    };
+                    rest = &rest[1..];
+                    let hours = take_number(&mut rest, 2)? as i32;
+                    let minutes = take_number(&mut rest, 2)? as i32;
+                    offset_seconds = Some(sign * (hours * 3600 + minutes * 60));
+                }
+            }
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        if let Some(x) = timestamp {
+            return Some(x);
+        }
+
+        let datetime = civil::DateTime::new(year?, month?, day?, hour?, minute?, second, 0).ok()?;
+        match offset_seconds {
+            Some(x) => {
+                let offset = jiff::tz::Offset::from_seconds(x).ok()?;
+                let timestamp: Timestamp = offset.to_timestamp(datetime).ok()?;
+                Some(timestamp.to_zoned(time_zone.clone()))
+            }
+            None => datetime.to_zoned(time_zone.clone()).ok(),
+        }
+    }
+}
+
+/// Makes `value` safe to use in a file name by percent encoding `%` and `/`. Reversed exactly by
+/// `decode_component`.
+fn encode_component(value: &str) -> String {
+    value.replace('%', "%25").replace('/', "%2F")
+}
+
+/// Reverses `encode_component`, returning `None` for any other percent sequence.
+fn decode_component(value: &str) -> Option<String> {
+    let mut decoded = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(i) = rest.find('%') {
+        decoded.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 3)? {
+            "25" => decoded.push('%'),
+            "2F" => decoded.push('/'),
+            _ => return None,
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+
+    Some(decoded)
+}
+
+fn take_number(rest: &mut &str, digits: usize) -> Option<i16> {
+    let number = rest.get(..digits)?;
+    if !number.bytes().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+    *rest = &rest[digits..];
+
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jiff::Span;
+
+    const TIME_ZONES: [&str; 6] = [
+        "UTC",
+        "Europe/London",
+        "America/New_York",
+        "America/Argentina/Buenos_Aires",
+        "Australia/Lord_Howe",
+        "Asia/Kathmandu",
+    ];
+
+    /// Times spread across several years, including both sides of DST transitions, from a fixed
+    /// seed so failures are reproducible.
+    fn sample_times() -> Vec<Zoned> {
+        let mut times = Vec::new();
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+
+        for time_zone in TIME_ZONES {
+            let time_zone = TimeZone::get(time_zone).expect("Test time zone should exist.");
+            let start = civil::date(2024, 1, 1)
+                .at(0, 0, 0, 0)
+                .to_zoned(time_zone)
+                .expect("Test start time should be valid.");
+
+            for _ in 0..500 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let offset = Span::new().seconds((state % (4 * 366 * 86400)) as i64);
+                times.push(start.saturating_add(offset));
+            }
+        }
+
+        times
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let values = [
+            "",
+            "/",
+            "%",
+            "%2F",
+            "%25",
+            "__",
+            "___",
+            "a_/b",
+            "America/Argentina/Buenos_Aires",
+            "100% /done//%%",
+        ];
+
+        for value in values {
+            let encoded = encode_component(value);
+            assert!(!encoded.contains('/'), "{} encoded to {}", value, encoded);
+            assert_eq!(decode_component(&encoded).as_deref(), Some(value));
+        }
+        assert_eq!(decode_component("%2"), None);
+        assert_eq!(decode_component("%41"), None);
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        let templates = [
+            DEFAULT_NAME_TEMPLATE,
+            "{timestamp}.{subvolume}",
+            "{subvolume}-{year}-{month}-{day}T{hour}{minute}{second}{offset}",
+        ];
+
+        for template in templates {
+            let template: NameTemplate = template.parse().expect("Template should be valid.");
+
+            for time in sample_times() {
+                let name = template.render("@home", &time);
+                assert!(!name.contains('/'), "{} contains '/'", name);
+
+                let parsed = template
+                    .parse("@home", &name, time.time_zone())
+                    .unwrap_or_else(|| panic!("{} should parse.", name));
+                assert_eq!(parsed.timestamp(), time.timestamp(), "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn full_timestamps_keep_their_time_zone() {
+        let template: NameTemplate = DEFAULT_NAME_TEMPLATE
+            .parse()
+            .expect("Template should be valid.");
+
+        for time in sample_times() {
+            let name = template.render("@", &time);
+            let parsed = template
+                .parse("@", &name, &TimeZone::UTC)
+                .unwrap_or_else(|| panic!("{} should parse.", name));
+            assert_eq!(parsed, time);
+        }
+    }
+
+    #[test]
+    fn legacy_names_parse() {
+        let template: NameTemplate = DEFAULT_NAME_TEMPLATE
+            .parse()
+            .expect("Template should be valid.");
+        let parsed = template.parse(
+            "@rootfs",
+            "@rootfs-2026-01-02T03:00:00+00:00[Europe__London]",
+            &TimeZone::UTC,
+        );
+
+        assert_eq!(
+            parsed,
+            Some(
+                "2026-01-02T03:00:00+00:00[Europe/London]"
+                    .parse()
+                    .expect("Test time should be valid.")
+            )
+        );
+    }
+
+    #[test]
+    fn hand_made_names_do_not_parse() {
+        let template: NameTemplate = DEFAULT_NAME_TEMPLATE
+            .parse()
+            .expect("Template should be valid.");
+        let names = [
+            "@rootfs-important",
+            "@rootfs-2026-01-02T03:00+00:00[UTC]",
+            "@rootfs-2026-01-02 03:00:00+00:00[UTC]",
+            "@rootfs-2026-01-02T03:00:00-00:00[UTC]",
+            "@rootfs-2026-01-02T03:00:00+00:00[utc]",
+        ];
+
+        for name in names {
+            assert_eq!(
+                template.parse("@rootfs", name, &TimeZone::UTC),
+                None,
+                "{}",
+                name
+            );
+        }
+        assert!(
+            template
+                .parse(
+                    "@rootfs",
+                    "@rootfs-2026-01-02T03:00:00+00:00[UTC]",
+                    &TimeZone::UTC
+                )
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn other_subvolumes_do_not_parse() {
+        let template: NameTemplate = DEFAULT_NAME_TEMPLATE
+            .parse()
+            .expect("Template should be valid.");
+        let time = civil::date(2026, 3, 4)
+            .at(5, 0, 0, 0)
+            .to_zoned(TimeZone::UTC)
+            .expect("Test time should be valid.");
+        let name = template.render("@home-alice", &time);
+
+        assert_eq!(template.parse("@home", &name, &TimeZone::UTC), None);
+        assert_eq!(
+            template.parse("@home-alice", &name, &TimeZone::UTC),
+            Some(time.clone())
+        );
+        assert_eq!(
+            template.parse("@home-alice", &format!("{}x", name), &TimeZone::UTC),
+            None
+        );
+    }
+}
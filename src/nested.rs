@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{Config, SubvolumeConfig, ioctl};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Adds every subvolume nested in a subvolume with include_nested as a subvolume of its own,
+/// snapshotted and pruned alongside it under its name and the nested path, e.g. `@rootfs-var-lib`
+/// for /var/lib in @rootfs. Subvolumes whose nested subvolumes can't be read are left to fail
+/// validation.
+pub fn include_nested(config: &mut Config) {
+    let mut included = Vec::new();
+
+    for subvolume in config.subvolumes.iter().filter(|x| x.include_nested) {
+        let Ok(nested) = uncovered_subvolumes(config, subvolume) else {
+            continue;
+        };
+        for path in nested {
+            let relative = path.strip_prefix(&subvolume.path).unwrap_or(&path);
+            included.push(SubvolumeConfig {
+                name: format!(
+                    "{}-{}",
+                    subvolume.name,
+                    relative.to_string_lossy().replace('/', "-")
+                ),
+                path,
+                snapshot_path: subvolume.snapshot_path.clone(),
+                trigger_snapshot_path: subvolume.trigger_snapshot_path.clone(),
+                timing: subvolume.timing.clone(),
+                stagger: subvolume.stagger,
+                readonly: subvolume.readonly,
+                btrfs: subvolume.btrfs.clone(),
+                backend: subvolume.backend.clone(),
+                include_nested: false,
+            });
+        }
+    }
+
+    config.subvolumes.extend(included);
+}
+
+/// Warns about each subvolume nested in a configured one that no snapshot covers, as btrfs
+/// snapshots don't recurse into them.
+pub fn warn_uncovered(config: &Config) {
+    for subvolume in config
+        .subvolumes
+        .iter()
+        .filter(|x| !x.include_nested && x.btrfs.native())
+    {
+        match uncovered_subvolumes(config, subvolume) {
+            Ok(x) if x.is_empty() => {}
+            Ok(x) => tracing::warn!(
+                "Snapshots of {} don't include the subvolumes nested in it: {}. Configure them as subvolumes of their own or set include_nested to snapshot them too.",
+                subvolume.name,
+                x.iter()
+                    .map(|x| x.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(e) => tracing::warn!(
+                "Error looking for subvolumes nested in {}. {}",
+                subvolume.path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+}
+
+/// Returns the subvolumes nested in `subvolume` that aren't covered by a configured subvolume of
+/// their own, leaving out the snapshot directories and everything in them.
+fn uncovered_subvolumes(config: &Config, subvolume: &SubvolumeConfig) -> io::Result<Vec<PathBuf>> {
+    let covered: Vec<&Path> = config
+        .subvolumes
+        .iter()
+        .filter(|x| x.path != subvolume.path && x.path.starts_with(&subvolume.path))
+        .map(|x| x.path.as_path())
+        .chain(
+            config
+                .subvolumes
+                .iter()
+                .flat_map(|x| [x.snapshot_path.as_path(), x.trigger_snapshot_path.as_path()]),
+        )
+        .collect();
+
+    Ok(ioctl::nested_subvolumes(&subvolume.path)?
+        .into_iter()
+        .filter(|x| !covered.iter().any(|y| x.starts_with(y)))
+        .collect())
+}
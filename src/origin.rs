@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, Snapshot, SubvolumeConfig, apply_space_budget, enforce_min_keep, hooks, is_immutable,
+    plan_retention, read_only, series_snapshots, trash,
+};
+use jiff::Zoned;
+use std::str::FromStr;
+
+/// How a snapshot outside the timeline came to be taken. Each origin is named with its own tag
+/// after the subvolume's name, keeping it out of the timeline's retention, and is only pruned
+/// if origin_policy gives it a policy.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Origin {
+    /// Asked for with `snapshotter snapshot now`.
+    Manual,
+    /// Taken before a change such as a package upgrade, with `snapshotter snapshot pre`.
+    Pre,
+    /// Taken after the change, pairing with the pre snapshot before it. Pruned with its pair
+    /// under the pre policy rather than a policy of its own.
+    Post,
+}
+
+impl Origin {
+    pub const ALL: [Origin; 3] = [Origin::Manual, Origin::Pre, Origin::Post];
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Origin::Manual => "manual",
+            Origin::Pre => "pre",
+            Origin::Post => "post",
+        }
+    }
+
+    /// The name snapshots of `subvolume` from this origin are rendered with.
+    pub fn series_name(self, subvolume: &SubvolumeConfig) -> String {
+        format!("{}.{}", subvolume.name, self.tag())
+    }
+}
+
+impl FromStr for Origin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|x| x.tag() == s).ok_or(format!(
+            "Unknown snapshot origin: {} (expected manual, pre or post)",
+            s
+        ))
+    }
+}
+
+/// Applies each origin's policy from origin_policy to `subvolume`'s snapshots of that origin.
+pub fn prune_origin_snapshots(config: &Config, subvolume: &SubvolumeConfig) {
+    for (origin, policy) in config.origin_policies.iter() {
+        let Some(policy) = config.policies.get(policy) else {
+            continue;
+        };
+        let Some(mut snapshots) = origin_snapshots(config, subvolume, *origin) else {
+            continue;
+        };
+        let now = Zoned::now();
+        plan_retention(policy, &mut snapshots, &now);
+        apply_space_budget(&*subvolume.backend, policy, &mut snapshots, &now);
+        enforce_min_keep(config, &origin.series_name(subvolume), &mut snapshots);
+
+        if *origin == Origin::Pre
+            && let Some(mut posts) = origin_snapshots(config, subvolume, Origin::Post)
+        {
+            follow_pre_snapshots(&snapshots, &mut posts);
+            discard_unkept(config, subvolume, Origin::Post, &posts);
+        }
+        discard_unkept(config, subvolume, *origin, &snapshots);
+    }
+}
+
+/// Keeps each of `posts` exactly when the pre snapshot it pairs with is kept, so neither half of a
+/// pair outlives the other. A post snapshot pairs with the newest pre snapshot before it, unless an
+/// earlier post snapshot already took that one. Post snapshots without a pair aren't kept. Both
+/// must be sorted oldest first.
+fn follow_pre_snapshots(pres: &[Snapshot], posts: &mut [Snapshot]) {
+    let mut paired = vec![false; pres.len()];
+
+    for post in posts.iter_mut() {
+        let pre = pres.partition_point(|x| x.time <= post.time).checked_sub(1);
+
+        post.keep = match pre {
+            Some(i) if !paired[i] => {
+                paired[i] = true;
+                pres[i].keep
+            }
+            _ => false,
+        };
+    }
+}
+
+fn origin_snapshots(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    origin: Origin,
+) -> Option<Vec<Snapshot>> {
+    match series_snapshots(
+        config,
+        subvolume,
+        &subvolume.snapshot_path,
+        &origin.series_name(subvolume),
+    ) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            tracing::error!(
+                "Error reading {} snapshots of {}. {}",
+                origin.tag(),
+                subvolume.name,
+                e
+            );
+            None
+        }
+    }
+}
+
+fn discard_unkept(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    origin: Origin,
+    snapshots: &[Snapshot],
+) {
+    // Those within immutable_for wait out the window whatever the origin's policy says.
+    let doomed: Vec<&Snapshot> = snapshots
+        .iter()
+        .filter(|x| !x.keep && !is_immutable(config, x))
+        .collect();
+    if read_only() {
+        for snapshot in doomed {
+            tracing::info!(
+                "Read-only mode, not deleting snapshot {}.",
+                snapshot.snapshot_path.to_string_lossy()
+            );
+        }
+        return;
+    }
+
+    for (snapshot, result) in doomed
+        .iter()
+        .zip(trash::discard_all(config, subvolume, &doomed))
+    {
+        if let Err(e) = result {
+            hooks::report_error(
+                &config.hooks,
+                &hooks::subvolume_env(subvolume),
+                &format!(
+                    "Error deleting {} snapshot {}. {}",
+                    origin.tag(),
+                    snapshot.snapshot_path.to_string_lossy(),
+                    e
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jiff::{civil, tz::TimeZone};
+    use std::path::PathBuf;
+
+    fn snapshots(hours: &[(i8, bool)]) -> Vec<Snapshot> {
+        hours
+            .iter()
+            .map(|(hour, keep)| Snapshot {
+                snapshot_path: PathBuf::from(hour.to_string()),
+                time: civil::date(2026, 3, 1)
+                    .at(*hour, 0, 0, 0)
+                    .to_zoned(TimeZone::UTC)
+                    .expect("Test time should be valid."),
+                keep: *keep,
+                info: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn posts_follow_their_pre() {
+        let pres = snapshots(&[(1, true), (3, false), (5, true), (7, true)]);
+        // 0 has no pre, 6 is a second post for the pre at 5 and 7's pre has no post yet.
+        let mut posts = snapshots(&[(0, true), (2, false), (4, true), (5, false), (6, true)]);
+
+        follow_pre_snapshots(&pres, &mut posts);
+
+        let kept: Vec<bool> = posts.iter().map(|x| x.keep).collect();
+        assert_eq!(kept, [false, true, false, true, false]);
+    }
+}
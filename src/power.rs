@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use std::{fs, path::Path};
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Returns whether the machine is running on battery, meaning it has a battery and no AC adapter
+/// or USB supply is online. Machines without a battery, or whose power supplies can't be read,
+/// are never on battery.
+pub fn on_battery() -> bool {
+    let Ok(entries) = fs::read_dir(POWER_SUPPLY_DIR) else {
+        return false;
+    };
+    let mut has_battery = false;
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_battery = read_attribute(&path, "type").as_deref() == Some("Battery");
+
+        if is_battery {
+            // Peripherals such as mice report batteries too, but they don't power the system.
+            has_battery |= read_attribute(&path, "scope").as_deref() != Some("Device");
+        } else if read_attribute(&path, "online").as_deref() == Some("1") {
+            return false;
+        }
+    }
+
+    has_battery
+}
+
+fn read_attribute(power_supply: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(power_supply.join(name))
+        .ok()
+        .map(|x| x.trim().to_string())
+}
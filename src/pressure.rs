@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::Config;
+use std::{fs, io};
+
+const IO_PRESSURE_PATH: &str = "/proc/pressure/io";
+const LOAD_AVERAGE_PATH: &str = "/proc/loadavg";
+
+/// Returns why the system is too busy to snapshot or prune now, if defer_io_pressure or
+/// defer_load_average is exceeded. Pressure that can't be read, such as on kernels without PSI,
+/// never defers.
+pub fn busy(config: &Config) -> Option<String> {
+    if let Some(limit) = config.defer_io_pressure {
+        match io_pressure() {
+            Ok(x) if x > limit => {
+                return Some(format!(
+                    "IO pressure is {:.2}%, above defer_io_pressure {}%",
+                    x, limit
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Error reading {}. {}", IO_PRESSURE_PATH, e),
+        }
+    }
+    if let Some(limit) = config.defer_load_average {
+        match load_average() {
+            Ok(x) if x > limit => {
+                return Some(format!(
+                    "the load average is {:.2}, above defer_load_average {}",
+                    x, limit
+                ));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::debug!("Error reading {}. {}", LOAD_AVERAGE_PATH, e),
+        }
+    }
+
+    None
+}
+
+/// The share of the last 10 seconds in which some task was stalled on IO, as a percentage.
+fn io_pressure() -> io::Result<f64> {
+    let contents = fs::read_to_string(IO_PRESSURE_PATH)?;
+
+    contents
+        .lines()
+        .find_map(|x| x.strip_prefix("some "))
+        .and_then(|x| x.split_whitespace().find_map(|y| y.strip_prefix("avg10=")))
+        .and_then(|x| x.parse().ok())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no some avg10 field",
+        ))
+}
+
+/// The load average over the last minute.
+fn load_average() -> io::Result<f64> {
+    fs::read_to_string(LOAD_AVERAGE_PATH)?
+        .split_whitespace()
+        .next()
+        .and_then(|x| x.parse().ok())
+        .ok_or(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no load average field",
+        ))
+}
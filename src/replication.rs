@@ -0,0 +1,1035 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Compression, Config, Encryption, ReplicationConfig, ReplicationStore, Snapshot,
+    SnapshotBackend, SubvolumeConfig, delete_subvolume, enforce_min_keep, hooks, is_immutable,
+    matching_snapshots, name_time_zone, plan_retention, read_only,
+    state::{self, State},
+    subvolume_show_field,
+};
+use jiff::Zoned;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read, Write},
+    os::fd::OwnedFd,
+    path::Path,
+    process::{Child, Command, Stdio},
+    thread::{self, ScopedJoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::info_span;
+
+/// Sends every local snapshot newer than the newest one the replication target has received in
+/// full, each incrementally from the one before it, then prunes the target.
+pub fn replicate(config: &Config, replication: &ReplicationConfig) {
+    let span = info_span!("replicate", target = replication.name);
+    let _span_guard = span.entered();
+
+    if read_only() {
+        tracing::info!("Read-only mode, not replicating to {}.", replication.host);
+        return;
+    }
+
+    for subvolume in config.subvolumes.iter() {
+        if !subvolume.readonly {
+            tracing::warn!(
+                "Not replicating {} as only read-only snapshots can be sent.",
+                subvolume.name
+            );
+            continue;
+        }
+
+        if let Err(e) = replicate_subvolume(config, replication, subvolume)
+            .and_then(|()| prune_target(config, replication, subvolume))
+        {
+            hooks::report_error(
+                &config.hooks,
+                &hooks::subvolume_env(subvolume),
+                &format!(
+                    "Error replicating {} to {}. {}",
+                    subvolume.name, replication.name, e
+                ),
+            );
+        }
+    }
+}
+
+/// Sends the snapshots of `subvolume` newer than the newest one the target has received in full,
+/// checking each was received in full before sending the next from it. What the target has
+/// received is kept in the state file, so a snapshot left half received isn't sent from later.
+fn replicate_subvolume(
+    config: &Config,
+    replication: &ReplicationConfig,
+    subvolume: &SubvolumeConfig,
+) -> Result<(), String> {
+    let local_snapshots = matching_snapshots(config, subvolume).map_err(|e| e.to_string())?;
+    let remote_snapshots = remote_snapshots(config, replication, subvolume)?;
+    let state = State::load(&config.state_path)?;
+    // Snapshots since deleted from the target are forgotten.
+    let mut received: BTreeMap<String, String> = state
+        .replication
+        .get(&replication.name)
+        .and_then(|x| x.received.get(&subvolume.name))
+        .into_iter()
+        .flatten()
+        .filter(|(x, _)| {
+            remote_snapshots
+                .iter()
+                .any(|y| remote_name(replication, y) == *x)
+        })
+        .map(|(x, y)| (x.clone(), y.clone()))
+        .collect();
+
+    // Snapshots on the target that aren't in the state file yet, such as ones sent before it
+    // tracked them, are checked once before being sent from. Ones left partly received by an
+    // interrupted transfer are deleted, to be sent again from the newest received in full.
+    let mut newest_received = None;
+    for (i, snapshot) in local_snapshots.iter().enumerate().rev() {
+        let name = file_name(snapshot);
+
+        if received.get(name) == Some(&local_uuid(snapshot)) {
+            newest_received = Some(i);
+            break;
+        }
+        if !remote_snapshots
+            .iter()
+            .any(|x| remote_name(replication, x) == name)
+        {
+            continue;
+        }
+        match verify_received(replication, snapshot) {
+            Ok(true) => {
+                received.insert(name.to_string(), local_uuid(snapshot));
+                newest_received = Some(i);
+                break;
+            }
+            Ok(false) => delete_partly_received(replication, snapshot)?,
+            Err(e) => tracing::warn!("Not sending incrementally from {}. {}", name, e),
+        }
+    }
+    state::record_received(config, &replication.name, &subvolume.name, &received);
+
+    let (mut parent, pending) = match newest_received {
+        Some(x) => (Some(&local_snapshots[x]), &local_snapshots[x + 1..]),
+        None => (
+            None,
+            &local_snapshots[local_snapshots.len().saturating_sub(1)..],
+        ),
+    };
+
+    // Stream files are sent in full every full_send_interval, so older chains can be pruned.
+    let mut last_full_send = remote_snapshots
+        .iter()
+        .rev()
+        .find(|x| stream_parent(replication, x).is_none())
+        .map(|x| x.time.clone());
+    for snapshot in pending {
+        if replication.store == ReplicationStore::Files
+            && last_full_send
+                .as_ref()
+                .is_none_or(|x| x.saturating_add(replication.full_send_interval) <= snapshot.time)
+        {
+            parent = None;
+        }
+        if parent.is_none() {
+            last_full_send = Some(snapshot.time.clone());
+        }
+        let sent = send_snapshot(subvolume, replication, snapshot, parent).and_then(|()| {
+            match verify_received(replication, snapshot)? {
+                true => Ok(()),
+                false => Err(format!("{} wasn't received in full.", file_name(snapshot))),
+            }
+        });
+        if let Err(e) = sent {
+            // Left for the next replication to send again, as it is still the newest received in
+            // full.
+            if let Some(x) = parent {
+                tracing::info!(
+                    "Replication of {} will resume from {}.",
+                    subvolume.name,
+                    file_name(x)
+                );
+            }
+            if let Ok(false) = verify_received(replication, snapshot)
+                && let Err(e) = delete_partly_received(replication, snapshot)
+            {
+                tracing::error!("{}", e);
+            }
+            return Err(e);
+        }
+        received.insert(file_name(snapshot).to_string(), local_uuid(snapshot));
+        state::record_received(config, &replication.name, &subvolume.name, &received);
+        parent = Some(snapshot);
+    }
+
+    Ok(())
+}
+
+/// What `verify-replica` found comparing the snapshots of a subvolume on a target with the local
+/// ones and those the state file records the target received.
+pub struct ReplicaCheck {
+    /// How many snapshots on the target were found received in full.
+    pub verified: usize,
+    /// Each snapshot missing from the target, or not matching the snapshot it is named after.
+    pub problems: Vec<String>,
+    /// The local snapshots newer than the newest on the target, left for the next replication.
+    pub unsent: Vec<String>,
+}
+
+/// Checks every snapshot of `subvolume` on the target against the local snapshot it is named
+/// after, or the UUID recorded when it was received if that is gone. Stream files can't be read
+/// without decrypting them, so only their chains back to a full send are checked.
+pub fn verify_replica(
+    config: &Config,
+    replication: &ReplicationConfig,
+    subvolume: &SubvolumeConfig,
+) -> Result<ReplicaCheck, String> {
+    let local_snapshots = matching_snapshots(config, subvolume).map_err(|e| e.to_string())?;
+    let remote_snapshots = remote_snapshots(config, replication, subvolume)?;
+    let state = State::load(&config.state_path)?;
+    let recorded = state
+        .replication
+        .get(&replication.name)
+        .and_then(|x| x.received.get(&subvolume.name));
+    let mut check = ReplicaCheck {
+        verified: 0,
+        problems: Vec::new(),
+        unsent: Vec::new(),
+    };
+
+    for (name, _) in recorded.into_iter().flatten() {
+        if !remote_snapshots
+            .iter()
+            .any(|x| remote_name(replication, x) == name)
+        {
+            check.problems.push(format!(
+                "{} was received but is missing from the target.",
+                name
+            ));
+        }
+    }
+
+    for (i, snapshot) in remote_snapshots.iter().enumerate() {
+        let name = remote_name(replication, snapshot);
+        if replication.store == ReplicationStore::Files {
+            match stream_chain(replication, &remote_snapshots, i).1 {
+                Some(x) => check.problems.push(format!(
+                    "{} can't be restored as {} in the chain before it is missing from the target.",
+                    file_name(snapshot),
+                    x
+                )),
+                None => check.verified += 1,
+            }
+            continue;
+        }
+
+        let expected = local_snapshots
+            .iter()
+            .find(|x| file_name(x) == name)
+            .map(local_uuid)
+            .filter(|x| !x.is_empty())
+            .or_else(|| recorded.and_then(|x| x.get(name)).cloned());
+        match (received_uuid(replication, name), expected) {
+            (Err(e), _) => check.problems.push(e),
+            (Ok(None), _) => check
+                .problems
+                .push(format!("{} is only partly received.", name)),
+            (Ok(Some(x)), Some(y)) if x != y => check
+                .problems
+                .push(format!("{} was received from {}, not {}.", name, x, y)),
+            (Ok(Some(_)), _) => check.verified += 1,
+        }
+    }
+
+    let newest_remote = remote_snapshots.last().map(|x| &x.time);
+    check.unsent = local_snapshots
+        .iter()
+        .filter(|x| newest_remote.is_none_or(|y| x.time > *y))
+        .map(|x| file_name(x).to_string())
+        .collect();
+
+    Ok(check)
+}
+
+/// Checks whether the copy of `snapshot` on the target was received in full from it, or only
+/// partly, such as by a transfer interrupted by a network drop or reboot. Errors if the copy is
+/// missing or was received from another snapshot.
+fn verify_received(replication: &ReplicationConfig, snapshot: &Snapshot) -> Result<bool, String> {
+    // A stream file is only given its name once it is written in full.
+    if replication.store == ReplicationStore::Files {
+        return Ok(true);
+    }
+    let Some(received_uuid) = received_uuid(replication, file_name(snapshot))? else {
+        return Ok(false);
+    };
+    let uuid = local_uuid(snapshot);
+
+    if !uuid.is_empty() && received_uuid != uuid {
+        return Err(format!(
+            "{} on {} was received from {}, not {}.",
+            replication.path.join(file_name(snapshot)).to_string_lossy(),
+            replication.host,
+            received_uuid,
+            uuid
+        ));
+    }
+
+    Ok(true)
+}
+
+/// The UUID of the snapshot the one named `name` on the target was received from, `None` while
+/// it is only partly received. btrfs receive only sets it once the whole stream is applied.
+fn received_uuid(replication: &ReplicationConfig, name: &str) -> Result<Option<String>, String> {
+    let remote_path = replication.path.join(name);
+    let output = run_ssh(
+        replication,
+        &["btrfs", "subvolume", "show", &remote_path.to_string_lossy()],
+    )?;
+    let received_uuid = subvolume_show_field(&output, &remote_path, "Received UUID")?;
+
+    Ok((!received_uuid.is_empty() && received_uuid != "-").then_some(received_uuid))
+}
+
+/// Deletes the copy of `snapshot` an interrupted transfer left on the target so it can be sent
+/// again. Nothing can be restored from it, so immutable_for doesn't cover it.
+fn delete_partly_received(
+    replication: &ReplicationConfig,
+    snapshot: &Snapshot,
+) -> Result<(), String> {
+    let remote_path = replication.path.join(file_name(snapshot));
+
+    tracing::info!(
+        "Deleting {} from {}, left partly received by an interrupted transfer.",
+        file_name(snapshot),
+        replication.name
+    );
+    run_ssh(
+        replication,
+        &[
+            "btrfs",
+            "subvolume",
+            "delete",
+            "-C",
+            &remote_path.to_string_lossy(),
+        ],
+    )
+    .map(|_| ())
+}
+
+/// The UUID of `snapshot`, empty if btrfs didn't report one.
+fn local_uuid(snapshot: &Snapshot) -> String {
+    snapshot
+        .info
+        .as_ref()
+        .and_then(|x| x.uuid.clone())
+        .unwrap_or_default()
+}
+
+/// Pipes `btrfs send` of `snapshot`, incremental from `parent` if given, through any compression
+/// and encryption into `btrfs receive` or a stream file on the target.
+fn send_snapshot(
+    subvolume: &SubvolumeConfig,
+    replication: &ReplicationConfig,
+    snapshot: &Snapshot,
+    parent: Option<&Snapshot>,
+) -> Result<(), String> {
+    match parent {
+        Some(x) => tracing::info!(
+            "Sending {} incrementally from {}.",
+            file_name(snapshot),
+            file_name(x)
+        ),
+        None => tracing::info!("Sending {} in full.", file_name(snapshot)),
+    }
+
+    let mut filters = Vec::new();
+    if replication.compression == Some(Compression::Zstd) {
+        let mut compress = Command::new("zstd");
+        compress.args(["-q", "-c", "-T0"]);
+        filters.push(compress);
+    }
+    match &replication.encryption {
+        Some(Encryption::Age(x)) => {
+            let mut encrypt = Command::new("age");
+            encrypt.arg("-e");
+            for recipient in x {
+                encrypt.arg("-r").arg(recipient);
+            }
+            filters.push(encrypt);
+        }
+        Some(Encryption::Gpg(x)) => {
+            let mut encrypt = Command::new("gpg");
+            encrypt.args(["--batch", "--quiet", "--encrypt", "--output", "-"]);
+            for recipient in x {
+                encrypt.arg("--recipient").arg(recipient);
+            }
+            filters.push(encrypt);
+        }
+        None => {}
+    }
+
+    let target_dir = shell_quote(&replication.path.to_string_lossy());
+    let stream_file = stream_file_name(replication, file_name(snapshot), parent.map(file_name));
+    let receive = match (replication.store, replication.compression) {
+        (ReplicationStore::Receive, Some(Compression::Zstd)) => {
+            format!("zstd -d -q -c | btrfs receive {}", target_dir)
+        }
+        (ReplicationStore::Receive, _) => format!("btrfs receive {}", target_dir),
+        // Written under a hidden name until it is known to be complete, clearing out any left
+        // by interrupted transfers.
+        (ReplicationStore::Files, _) => format!(
+            "rm -f -- {}/.*.part && cat > {}/{}",
+            target_dir,
+            target_dir,
+            shell_quote(&format!(".{}.part", stream_file))
+        ),
+    };
+
+    pipe_send(
+        &*subvolume.backend,
+        &snapshot.snapshot_path,
+        parent.map(|x| x.snapshot_path.as_path()),
+        replication.compression == Some(Compression::CompressedData),
+        filters,
+        replication.bandwidth_limit_bytes,
+        ssh_command(replication, &["sh", "-c", &receive]),
+    )?;
+    // ssh only succeeds once the remote side has read the whole stream, so the stream file is
+    // complete.
+    if replication.store == ReplicationStore::Files {
+        run_ssh(
+            replication,
+            &[
+                "mv",
+                "--",
+                &replication
+                    .path
+                    .join(format!(".{}.part", stream_file))
+                    .to_string_lossy(),
+                &replication.path.join(&stream_file).to_string_lossy(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The name of the stream file on the target for the snapshot named `snapshot`, sent
+/// incrementally from the one named `parent` if given. Restoring it reverses the extensions,
+/// e.g. `age -d -i key @.2026-01-02.from.@.2026-01-01.btrfs.zst.age | zstd -d | btrfs receive`.
+fn stream_file_name(
+    replication: &ReplicationConfig,
+    snapshot: &str,
+    parent: Option<&str>,
+) -> String {
+    let mut name = match parent {
+        Some(x) => format!("{}.from.{}.btrfs", snapshot, x),
+        None => format!("{}.btrfs", snapshot),
+    };
+    if replication.compression == Some(Compression::Zstd) {
+        name.push_str(".zst");
+    }
+    match replication.encryption {
+        Some(Encryption::Age(_)) => name.push_str(".age"),
+        Some(Encryption::Gpg(_)) => name.push_str(".gpg"),
+        None => {}
+    }
+
+    name
+}
+
+/// Splits a stream file name into the name of the snapshot it was sent from and the one it was
+/// sent incrementally from, if any.
+fn parse_stream_file_name(file: &str) -> Option<(&str, Option<&str>)> {
+    let stem = file
+        .trim_end_matches(".age")
+        .trim_end_matches(".gpg")
+        .trim_end_matches(".zst")
+        .strip_suffix(".btrfs")?;
+
+    Some(match stem.split_once(".from.") {
+        Some((x, y)) => (x, Some(y)),
+        None => (stem, None),
+    })
+}
+
+/// The name of the snapshot `snapshot` on the target was sent from.
+fn remote_name<'a>(replication: &ReplicationConfig, snapshot: &'a Snapshot) -> &'a str {
+    match replication.store {
+        ReplicationStore::Receive => file_name(snapshot),
+        ReplicationStore::Files => {
+            parse_stream_file_name(file_name(snapshot)).map_or(file_name(snapshot), |x| x.0)
+        }
+    }
+}
+
+/// The name of the snapshot the stream file `snapshot` on the target was sent incrementally
+/// from, `None` if it was sent in full or was received as a subvolume.
+fn stream_parent<'a>(replication: &ReplicationConfig, snapshot: &'a Snapshot) -> Option<&'a str> {
+    match replication.store {
+        ReplicationStore::Receive => None,
+        ReplicationStore::Files => parse_stream_file_name(file_name(snapshot))?.1,
+    }
+}
+
+/// The indexes in `remote_snapshots` of the stream files that have to be restored before
+/// `remote_snapshots[i]`, back to a full send, with the name of the first one missing from the
+/// target if the chain is broken. Parents are older, so each is looked for before the last.
+fn stream_chain(
+    replication: &ReplicationConfig,
+    remote_snapshots: &[Snapshot],
+    i: usize,
+) -> (Vec<usize>, Option<String>) {
+    let mut chain = Vec::new();
+    let mut current = i;
+
+    while let Some(x) = stream_parent(replication, &remote_snapshots[current]) {
+        match remote_snapshots[..current]
+            .iter()
+            .position(|y| remote_name(replication, y) == x)
+        {
+            Some(y) => {
+                chain.push(y);
+                current = y;
+            }
+            None => return (chain, Some(x.to_string())),
+        }
+    }
+
+    (chain, None)
+}
+
+/// Sends the read-only snapshot at `snapshot_path` to `destination_dir` on another btrfs
+/// filesystem of this host, deleting the partly received copy if it fails.
+pub fn send_local(
+    backend: &dyn SnapshotBackend,
+    snapshot_path: &Path,
+    destination_dir: &Path,
+) -> Result<(), String> {
+    let (mut reader, mut writer) =
+        io::pipe().map_err(|e| format!("Error creating a pipe to send through. {}", e))?;
+
+    thread::scope(|scope| {
+        // The writer is dropped once the stream is sent, ending it for the receiving side.
+        let send = scope.spawn(move || backend.send(snapshot_path, None, false, &mut writer));
+        let received = backend.receive(destination_dir, &mut reader);
+        // Stops a send the receiving side gave up on.
+        drop(reader);
+        let sent = send
+            .join()
+            .unwrap_or(Err("btrfs send panicked.".to_string()));
+
+        sent.and(received)
+    })
+    .inspect_err(|_| {
+        if let Some(x) = snapshot_path.file_name()
+            && destination_dir.join(x).exists()
+            && let Err(e) = delete_subvolume(backend, &destination_dir.join(x))
+        {
+            tracing::error!("Error deleting partly received snapshot. {}", e);
+        }
+    })
+}
+
+/// Pipes the send stream of `snapshot_path`, incremental from `parent_path` if given, through
+/// each of `filters` in turn into `receive`, at no more than `bandwidth_limit` bytes a second.
+fn pipe_send(
+    backend: &dyn SnapshotBackend,
+    snapshot_path: &Path,
+    parent_path: Option<&Path>,
+    compressed_data: bool,
+    filters: Vec<Command>,
+    bandwidth_limit: Option<u64>,
+    mut receive: Command,
+) -> Result<(), String> {
+    // Limited streams are relayed through this process, throttling what leaves the last filter.
+    let relay = match bandwidth_limit {
+        Some(x) => Some((
+            io::pipe().map_err(|e| format!("Error creating a pipe to send through. {}", e))?,
+            x,
+        )),
+        None => None,
+    };
+    let mut receive = receive
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Error running the receiving side. {}", e))?;
+    let receive_stdin = receive
+        .stdin
+        .take()
+        .expect("btrfs receive stdin should be piped.");
+
+    thread::scope(|scope| {
+        let receive_stderr = drain(scope, receive.stderr.take());
+        let (mut stream, relayed): (OwnedFd, _) = match relay {
+            Some(((mut reader, writer), limit)) => {
+                let mut limited = RateLimited::new(receive_stdin, limit);
+                (
+                    writer.into(),
+                    Some(scope.spawn(move || io::copy(&mut reader, &mut limited))),
+                )
+            }
+            None => (receive_stdin.into(), None),
+        };
+
+        // Started from the receiving end back, each writing into the one after it.
+        let mut running_filters: Vec<(String, Child, ScopedJoinHandle<Vec<u8>>)> =
+            Vec::with_capacity(filters.len());
+        for mut filter in filters.into_iter().rev() {
+            let program = filter.get_program().to_string_lossy().to_string();
+            let spawned = filter
+                .stdin(Stdio::piped())
+                .stdout(stream)
+                .stderr(Stdio::piped())
+                .spawn();
+            let mut child = match spawned {
+                Ok(x) => x,
+                Err(e) => {
+                    // The stream ends for the filters already running and the receiving side,
+                    // which give up.
+                    drop(filter);
+                    for (_, mut child, _) in running_filters {
+                        let _ = child.wait();
+                    }
+                    if let Some(x) = relayed {
+                        let _ = x.join();
+                    }
+                    let _ = receive.wait();
+                    return Err(format!("Error running {}. {}", program, e));
+                }
+            };
+            stream = child
+                .stdin
+                .take()
+                .expect("Filter stdin should be piped.")
+                .into();
+            let stderr = drain(scope, child.stderr.take());
+            running_filters.push((program, child, stderr));
+        }
+
+        let mut stream = File::from(stream);
+        let sent = backend.send(snapshot_path, parent_path, compressed_data, &mut stream);
+        drop(stream);
+        let mut filtered = Ok(());
+        for (program, mut child, stderr) in running_filters.into_iter().rev() {
+            let status = child.wait();
+            let stderr = stderr.join().unwrap_or_default();
+            match status {
+                Ok(x) if x.success() => {}
+                Ok(_) => {
+                    filtered = filtered.and(Err(format!(
+                        "{} failed. Output: {}",
+                        program,
+                        String::from_utf8_lossy(&stderr).trim_end()
+                    )))
+                }
+                Err(e) => filtered = filtered.and(Err(format!("Error running {}. {}", program, e))),
+            }
+        }
+        let relayed = match relayed.map(|x| x.join()) {
+            Some(Ok(Err(e))) => Err(format!(
+                "Error relaying the stream to the receiving side. {}",
+                e
+            )),
+            Some(Err(_)) => Err("Relaying the stream panicked.".to_string()),
+            _ => Ok(()),
+        };
+        let received = receive
+            .wait()
+            .map_err(|e| format!("Error running the receiving side. {}", e))?;
+        let receive_stderr = receive_stderr.join().unwrap_or_default();
+
+        sent?;
+        filtered?;
+        if !received.success() {
+            return Err(format!(
+                "Receiving the stream failed. Output: {}",
+                String::from_utf8_lossy(&receive_stderr).trim_end()
+            ));
+        }
+        relayed
+    })
+}
+
+/// Reads all of `reader` on a thread of `scope`, so a child writing more than a pipe buffer of
+/// stderr never blocks while the stream is still being sent.
+fn drain<'scope, R: Read + Send + 'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    reader: Option<R>,
+) -> ScopedJoinHandle<'scope, Vec<u8>> {
+    scope.spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut x) = reader {
+            let _ = x.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+/// Writes through to `inner` at no more than `bytes_per_second` on average.
+struct RateLimited<W> {
+    inner: W,
+    bytes_per_second: u64,
+    started: Instant,
+    written: u64,
+}
+
+impl<W> RateLimited<W> {
+    fn new(inner: W, bytes_per_second: u64) -> Self {
+        RateLimited {
+            inner,
+            bytes_per_second,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for RateLimited<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // At most a tenth of a second's worth at a time, keeping the rate steady instead of
+        // bursting.
+        let chunk = (self.bytes_per_second / 10).max(1) as usize;
+        let written = self.inner.write(&buf[..buf.len().min(chunk)])?;
+        self.written += written as u64;
+
+        let due = Duration::from_secs_f64(self.written as f64 / self.bytes_per_second as f64);
+        if let Some(x) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(x);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Deletes the snapshots of `subvolume` on the target that the target's policy doesn't keep, or
+/// all but the newest keep_last without one. The newest is always kept, as the next incremental
+/// send is made from it.
+fn prune_target(
+    config: &Config,
+    replication: &ReplicationConfig,
+    subvolume: &SubvolumeConfig,
+) -> Result<(), String> {
+    let policy = replication
+        .policy
+        .as_ref()
+        .and_then(|x| config.policies.get(x));
+    if policy.is_none() && replication.keep_last == 0 {
+        return Ok(());
+    }
+    let mut remote_snapshots = remote_snapshots(config, replication, subvolume)?;
+
+    match policy {
+        Some(x) => {
+            plan_retention(x, &mut remote_snapshots, &Zoned::now());
+            enforce_min_keep(
+                config,
+                &format!("{} on {}", subvolume.name, replication.name),
+                &mut remote_snapshots,
+            );
+        }
+        None => {
+            let kept = remote_snapshots.len().saturating_sub(replication.keep_last);
+            for snapshot in remote_snapshots[kept..].iter_mut() {
+                snapshot.keep = true;
+            }
+        }
+    }
+    if let Some(x) = remote_snapshots.last_mut() {
+        x.keep = true;
+    }
+    // Whichever way the target is pruned, a copy within immutable_for waits out the window.
+    for snapshot in remote_snapshots.iter_mut() {
+        if is_immutable(config, snapshot) {
+            snapshot.keep = true;
+        }
+    }
+    // An incremental stream file can only be restored after every one before it back to a full
+    // one, so those are kept too. Parents are older, so newest first finds every chain.
+    for i in (0..remote_snapshots.len()).rev() {
+        if !remote_snapshots[i].keep {
+            continue;
+        }
+        for x in stream_chain(replication, &remote_snapshots, i).0 {
+            remote_snapshots[x].keep = true;
+        }
+    }
+
+    for snapshot in remote_snapshots.iter().filter(|x| !x.keep) {
+        delete_remote(config, replication, snapshot)?;
+    }
+    // Forgotten as received too, so verify-replica doesn't report them missing.
+    if let Some(received) = State::load(&config.state_path)?
+        .replication
+        .get(&replication.name)
+        .and_then(|x| x.received.get(&subvolume.name))
+    {
+        let mut received = received.clone();
+        received.retain(|x, _| {
+            !remote_snapshots
+                .iter()
+                .any(|y| !y.keep && remote_name(replication, y) == x)
+        });
+        state::record_received(config, &replication.name, &subvolume.name, &received);
+    }
+
+    Ok(())
+}
+
+/// Deletes the copy of `snapshot` on the target, refusing while it is immutable.
+fn delete_remote(
+    config: &Config,
+    replication: &ReplicationConfig,
+    snapshot: &Snapshot,
+) -> Result<(), String> {
+    if is_immutable(config, snapshot) {
+        return Err(format!(
+            "Not deleting {} from {} as it is within immutable_for.",
+            file_name(snapshot),
+            replication.host
+        ));
+    }
+    tracing::info!(
+        "Deleting snapshot {} from {}.",
+        file_name(snapshot),
+        replication.host
+    );
+    let path = snapshot.snapshot_path.to_string_lossy();
+    match replication.store {
+        ReplicationStore::Receive => {
+            run_ssh(replication, &["btrfs", "subvolume", "delete", "-C", &path])?
+        }
+        ReplicationStore::Files => run_ssh(replication, &["rm", "-f", "--", &path])?,
+    };
+
+    Ok(())
+}
+
+/// Returns the snapshots of `subvolume` on the target, sorted oldest first.
+fn remote_snapshots(
+    config: &Config,
+    replication: &ReplicationConfig,
+    subvolume: &SubvolumeConfig,
+) -> Result<Vec<Snapshot>, String> {
+    let time_zone = name_time_zone(config);
+    let listing = run_ssh(
+        replication,
+        &["ls", "-1", "--", &replication.path.to_string_lossy()],
+    )?;
+
+    let mut snapshots: Vec<Snapshot> = listing
+        .lines()
+        .filter_map(|x| {
+            let name = match replication.store {
+                ReplicationStore::Receive => x,
+                ReplicationStore::Files => parse_stream_file_name(x)?.0,
+            };
+            config
+                .name_template
+                .parse(&subvolume.name, name, &time_zone)
+                .map(|time| Snapshot {
+                    snapshot_path: replication.path.join(x),
+                    time: time.with_time_zone(time_zone.clone()),
+                    keep: false,
+                    info: None,
+                })
+        })
+        .collect();
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+/// Runs `remote_command` on the target, returning its stdout.
+fn run_ssh(replication: &ReplicationConfig, remote_command: &[&str]) -> Result<String, String> {
+    let output = match ssh_command(replication, remote_command).output() {
+        Ok(x) => x,
+        Err(e) => return Err(format!("Error running ssh. {}", e)),
+    };
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(format!(
+            "Remote command {} failed. Output: {}",
+            remote_command.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ))
+    }
+}
+
+fn ssh_command(replication: &ReplicationConfig, remote_command: &[&str]) -> Command {
+    let mut command = Command::new("ssh");
+
+    // ssh joins the remote command into a single string for the remote shell, so each argument
+    // is quoted to survive that.
+    command
+        .args(&replication.ssh_options)
+        .arg("--")
+        .arg(&replication.host)
+        .args(remote_command.iter().map(|x| shell_quote(x)));
+
+    command
+}
+
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn file_name(snapshot: &Snapshot) -> &str {
+    snapshot
+        .snapshot_path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockBackend, temp_path};
+    use jiff::ToSpan;
+    use std::path::PathBuf;
+
+    #[test]
+    fn pipes_the_stream_through_filters() {
+        let backend = MockBackend::default();
+        let snapshot = Path::new("/mock/.snapshots/@.2026-01-01_00-00");
+        backend.add_subvolume(snapshot);
+        let received = temp_path("filtered-stream");
+        let mut compress = Command::new("gzip");
+        compress.arg("-c");
+        let mut receive = Command::new("sh");
+        receive.arg("-c").arg(format!(
+            "gzip -d -c > {}",
+            shell_quote(&received.to_string_lossy())
+        ));
+
+        pipe_send(
+            &backend,
+            snapshot,
+            None,
+            false,
+            vec![compress],
+            None,
+            receive,
+        )
+        .expect("Piping through gzip should succeed.");
+
+        assert_eq!(
+            std::fs::read_to_string(&received).expect("The stream should be received."),
+            snapshot.to_string_lossy()
+        );
+        let _ = std::fs::remove_file(received);
+    }
+
+    #[test]
+    fn limits_writes_to_a_tenth_of_a_second_at_a_time() {
+        let sent: Vec<u8> = (0..=u8::MAX).collect();
+        let mut limited = RateLimited::new(Vec::new(), 1000);
+
+        let mut writes = Vec::new();
+        let mut rest = sent.as_slice();
+        while !rest.is_empty() {
+            let written = limited
+                .write(rest)
+                .expect("Writing to a Vec should succeed.");
+            writes.push(written);
+            rest = &rest[written..];
+        }
+
+        assert!(writes.iter().all(|x| *x == 100 || *x == sent.len() % 100));
+        assert_eq!(limited.written, sent.len() as u64);
+        assert_eq!(limited.inner, sent);
+    }
+
+    #[test]
+    fn relays_a_limited_stream() {
+        let backend = MockBackend::default();
+        let snapshot = Path::new("/mock/.snapshots/@.2026-01-01_00-00");
+        backend.add_subvolume(snapshot);
+        let received = temp_path("limited-stream");
+        let mut receive = Command::new("sh");
+        receive.arg("-c").arg(format!(
+            "cat > {}",
+            shell_quote(&received.to_string_lossy())
+        ));
+
+        pipe_send(
+            &backend,
+            snapshot,
+            None,
+            false,
+            vec![],
+            Some(1 << 20),
+            receive,
+        )
+        .expect("Sending with a limit should succeed.");
+
+        assert_eq!(
+            std::fs::read_to_string(&received).expect("The stream should be received."),
+            snapshot.to_string_lossy()
+        );
+        let _ = std::fs::remove_file(received);
+    }
+
+    #[test]
+    fn walks_stream_file_chains_back_to_a_full_send() {
+        let replication = ReplicationConfig {
+            name: "nas".to_string(),
+            host: "nas".to_string(),
+            path: PathBuf::from("/backups"),
+            ssh_options: Vec::new(),
+            interval: None,
+            keep_last: 0,
+            policy: None,
+            compression: None,
+            store: ReplicationStore::Files,
+            encryption: None,
+            full_send_interval: 30.days(),
+            bandwidth_limit_bytes: None,
+        };
+        let stream_files = |files: &[&str]| -> Vec<Snapshot> {
+            files
+                .iter()
+                .enumerate()
+                .map(|(i, x)| Snapshot {
+                    snapshot_path: replication.path.join(x),
+                    time: Zoned::now().saturating_add((i as i64).hours()),
+                    keep: false,
+                    info: None,
+                })
+                .collect()
+        };
+
+        let complete = stream_files(&["@.1.btrfs", "@.2.from.@.1.btrfs", "@.3.from.@.2.btrfs"]);
+        assert_eq!(stream_chain(&replication, &complete, 2), (vec![1, 0], None));
+
+        let broken = stream_files(&["@.2.from.@.1.btrfs", "@.3.from.@.2.btrfs"]);
+        assert_eq!(
+            stream_chain(&replication, &broken, 1),
+            (vec![0], Some("@.1".to_string()))
+        );
+    }
+
+    #[test]
+    fn stream_file_names_record_their_parent() {
+        assert_eq!(
+            parse_stream_file_name("@.2026-01-02.from.@.2026-01-01.btrfs.zst.age"),
+            Some(("@.2026-01-02", Some("@.2026-01-01")))
+        );
+        assert_eq!(
+            parse_stream_file_name("@.2026-01-01.btrfs"),
+            Some(("@.2026-01-01", None))
+        );
+        assert_eq!(parse_stream_file_name("@.2026-01-01"), None);
+    }
+}
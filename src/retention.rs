@@ -0,0 +1,828 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+//! Deciding which snapshots to keep. Everything here works on snapshot times alone and a given
+//! now, never touching the filesystem or the clock, so it can be tested and simulated freely.
+
+use jiff::{
+    Span, ToSpan, Zoned,
+    civil::{self, Weekday},
+};
+
+/// The limits deciding which snapshots are kept.
+pub struct RetentionPolicy {
+    /// Quarter hours to keep a snapshot for, before the hourly tier thins them out.
+    pub frequent_limit: usize,
+    pub hourly_limit: usize,
+    pub daily_limit: usize,
+    pub weekly_limit: usize,
+    /// Monday for ISO weeks, or Sunday.
+    pub week_start: Weekday,
+    pub monthly_limit: usize,
+    pub yearly_limit: usize,
+    pub keep_last: usize,
+    pub keep_within: Option<Span>,
+    pub immutable_for: Option<Span>,
+    /// Snapshots older than this are pruned whatever the limits say, unless immutable_for protects
+    /// them.
+    pub max_age: Option<Span>,
+    /// The oldest snapshots are pruned until the exclusive usage of the rest fits in this.
+    pub space_budget_bytes: Option<u64>,
+    /// Tiers beyond the fixed ones, from tiers.
+    pub tiers: Vec<RetentionTier>,
+}
+
+impl RetentionPolicy {
+    /// Whether no limit keeps any snapshot, leaving only the space budget if one is set.
+    pub fn has_no_limits(&self) -> bool {
+        self.frequent_limit == 0
+            && self.hourly_limit == 0
+            && self.daily_limit == 0
+            && self.weekly_limit == 0
+            && self.monthly_limit == 0
+            && self.yearly_limit == 0
+            && self.keep_last == 0
+            && self.keep_within.is_none()
+            && self.tiers.iter().all(|x| x.limit == 0)
+    }
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            frequent_limit: 0,
+            hourly_limit: 48,
+            daily_limit: 0,
+            weekly_limit: 0,
+            week_start: Weekday::Monday,
+            monthly_limit: 0,
+            yearly_limit: 0,
+            keep_last: 0,
+            keep_within: None,
+            immutable_for: None,
+            max_age: None,
+            space_budget_bytes: None,
+            tiers: Vec::new(),
+        }
+    }
+}
+
+/// A tier from tiers, keeping the newest snapshot in each of the `limit` most recent periods.
+pub struct RetentionTier {
+    /// The period as written, naming the tier in logs, metrics and `prune --explain`.
+    pub name: String,
+    pub period: TierPeriod,
+    pub limit: usize,
+}
+
+/// The length of a tier's periods. Periods are counted on the wall clock from the start of 2000,
+/// or of its first week for days, so they line up with calendar quarters, weeks and hours.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TierPeriod {
+    Months(i64),
+    Days(i64),
+    Seconds(i64),
+}
+
+impl TierPeriod {
+    /// Splits `span` into months, days or seconds. A period mixing them, such as "1mo 2d", has
+    /// no consistent length to count in and is rejected.
+    pub fn from_span(span: Span) -> Result<Self, String> {
+        let months = span.get_years() as i64 * 12 + span.get_months() as i64;
+        let days = span.get_weeks() as i64 * 7 + span.get_days() as i64;
+        let seconds = span.get_hours() as i64 * 3600 + span.get_minutes() * 60 + span.get_seconds();
+        let fractional = span.get_milliseconds() != 0
+            || span.get_microseconds() != 0
+            || span.get_nanoseconds() != 0;
+
+        match (months, days, seconds) {
+            _ if fractional => Err(format!("{:#} is not a whole number of seconds", span)),
+            (x, 0, 0) if x > 0 => Ok(Self::Months(x)),
+            (0, x, 0) if x > 0 => Ok(Self::Days(x)),
+            (0, 0, x) if x > 0 => Ok(Self::Seconds(x)),
+            _ => Err(format!(
+                "{:#} must be in only months and years, weeks and days, or hours, minutes and seconds",
+                span
+            )),
+        }
+    }
+
+    /// The wall clock time the period `time` falls in starts at.
+    fn bucket_start(self, time: &Zoned, week_start: Weekday) -> civil::DateTime {
+        let epoch = match week_start {
+            Weekday::Sunday => civil::date(2000, 1, 2),
+            _ => civil::date(2000, 1, 3),
+        };
+        let days = epoch
+            .until(time.date())
+            .map(|x| x.get_days() as i64)
+            .unwrap_or(0);
+
+        match self {
+            Self::Months(x) => {
+                let month = time.year() as i64 * 12 + time.month() as i64 - 1;
+                let start = month - month.rem_euclid(x);
+                civil::Date::new(
+                    start.div_euclid(12) as i16,
+                    start.rem_euclid(12) as i8 + 1,
+                    1,
+                )
+                .unwrap_or(epoch)
+                .at(0, 0, 0, 0)
+            }
+            Self::Days(x) => epoch
+                .saturating_add((days - days.rem_euclid(x)).days())
+                .at(0, 0, 0, 0),
+            Self::Seconds(x) => {
+                let second = days * 86400
+                    + time.hour() as i64 * 3600
+                    + time.minute() as i64 * 60
+                    + time.second() as i64;
+                epoch
+                    .at(0, 0, 0, 0)
+                    .saturating_add((second - second.rem_euclid(x)).seconds())
+            }
+        }
+    }
+
+    /// Names the period `time` falls in by when it starts, for `prune --explain`.
+    fn bucket_name(self, time: &Zoned, week_start: Weekday) -> String {
+        let start = self.bucket_start(time, week_start);
+        match self {
+            Self::Months(_) => start.strftime("%Y-%m").to_string(),
+            Self::Days(_) => start.strftime("%F").to_string(),
+            Self::Seconds(_) => start.strftime("%F %H:%M:%S").to_string(),
+        }
+    }
+}
+
+/// What `policy` decided for a set of snapshots.
+pub struct Plan {
+    /// Whether each snapshot is kept, in the order their times were given.
+    pub keep: Vec<bool>,
+    /// How many snapshots each rule kept, by the rule's name. A snapshot kept by several rules
+    /// counts towards each of them.
+    pub kept_by: Vec<(String, usize)>,
+}
+
+/// Decides which of the snapshots taken at `times` `policy` keeps as of `now`. `times` must be
+/// sorted oldest first.
+pub fn plan(policy: &RetentionPolicy, times: &[Zoned], now: &Zoned) -> Plan {
+    let mut keep = vec![false; times.len()];
+    let mut kept_by = vec![
+        (
+            "frequent",
+            keep_newest_per_bucket(times, &mut keep, policy.frequent_limit, |time| {
+                (time.date(), time.hour(), time.minute() / 15)
+            }),
+        ),
+        (
+            "hourly",
+            keep_newest_per_bucket(times, &mut keep, policy.hourly_limit, |time| {
+                (time.date(), time.hour())
+            }),
+        ),
+        (
+            "daily",
+            keep_newest_per_bucket(times, &mut keep, policy.daily_limit, |time| time.date()),
+        ),
+        (
+            "weekly",
+            keep_newest_per_bucket(times, &mut keep, policy.weekly_limit, |time| {
+                week_bucket(time, policy.week_start)
+            }),
+        ),
+        (
+            "monthly",
+            keep_newest_per_bucket(times, &mut keep, policy.monthly_limit, |time| {
+                (time.year(), time.month())
+            }),
+        ),
+        (
+            "yearly",
+            keep_newest_per_bucket(times, &mut keep, policy.yearly_limit, |time| time.year()),
+        ),
+    ];
+    for tier in policy.tiers.iter() {
+        kept_by.push((
+            tier.name.as_str(),
+            keep_newest_per_bucket(times, &mut keep, tier.limit, |time| {
+                tier.period.bucket_start(time, policy.week_start)
+            }),
+        ));
+    }
+    kept_by.push(("keep_last", keep_newest(&mut keep, policy.keep_last)));
+    if let Some(x) = policy.keep_within {
+        kept_by.push((
+            "keep_within",
+            keep_newer_than(times, &mut keep, &now.saturating_sub(x)),
+        ));
+    }
+    // Applied before immutable_for, so a snapshot still protected from deletion keeps it.
+    if let Some(x) = policy.max_age {
+        let cutoff = now.saturating_sub(x);
+        for (_, keep) in times
+            .iter()
+            .zip(keep.iter_mut())
+            .filter(|(y, _)| **y < cutoff)
+        {
+            *keep = false;
+        }
+    }
+    if let Some(x) = policy.immutable_for {
+        kept_by.push((
+            "immutable",
+            keep_newer_than(times, &mut keep, &now.saturating_sub(x)),
+        ));
+    }
+    // Pruning by space alone, every snapshot is kept for the space budget to thin out.
+    if policy.space_budget_bytes.is_some() && policy.has_no_limits() {
+        kept_by.push(("space_budget", keep_newest(&mut keep, times.len())));
+    }
+
+    Plan {
+        keep,
+        kept_by: kept_by
+            .into_iter()
+            .map(|(x, y)| (x.to_string(), y))
+            .collect(),
+    }
+}
+
+/// Lists the rules of `policy` keeping each of the snapshots taken at `times` as of `now`, such
+/// as `hourly #3 (2026-03-01 14h)`, for `prune --explain`. Follows `plan` rule for rule, so a
+/// snapshot with none listed is pruned unless min_keep saves it. `times` must be sorted oldest
+/// first.
+pub fn explain(policy: &RetentionPolicy, times: &[Zoned], now: &Zoned) -> Vec<Vec<String>> {
+    let mut reasons = vec![Vec::new(); times.len()];
+    let week = |time: &Zoned| {
+        let (year, week) = week_bucket(time, policy.week_start);
+        format!("{}-W{:02}", year, week)
+    };
+    type BucketName<'a> = Box<dyn Fn(&Zoned) -> String + 'a>;
+    let mut tiers: Vec<(String, usize, BucketName)> = vec![
+        (
+            "frequent".to_string(),
+            policy.frequent_limit,
+            Box::new(|time| format!("{}:{:02}", time.strftime("%F %H"), time.minute() / 15 * 15)),
+        ),
+        (
+            "hourly".to_string(),
+            policy.hourly_limit,
+            Box::new(|time| time.strftime("%F %Hh").to_string()),
+        ),
+        (
+            "daily".to_string(),
+            policy.daily_limit,
+            Box::new(|time| time.strftime("%F").to_string()),
+        ),
+        ("weekly".to_string(), policy.weekly_limit, Box::new(week)),
+        (
+            "monthly".to_string(),
+            policy.monthly_limit,
+            Box::new(|time| time.strftime("%Y-%m").to_string()),
+        ),
+        (
+            "yearly".to_string(),
+            policy.yearly_limit,
+            Box::new(|time| time.strftime("%Y").to_string()),
+        ),
+    ];
+    for tier in policy.tiers.iter() {
+        tiers.push((
+            tier.name.clone(),
+            tier.limit,
+            Box::new(|time| tier.period.bucket_name(time, policy.week_start)),
+        ));
+    }
+
+    // The same walk as keep_newest_per_bucket, with the buckets named.
+    for (tier, limit, bucket) in tiers {
+        let mut last_bucket = None;
+        let mut rank = 0;
+
+        for (time, reasons) in times.iter().zip(reasons.iter_mut()).rev() {
+            if rank >= limit {
+                break;
+            }
+
+            let time_bucket = bucket(time);
+            if last_bucket.as_ref() != Some(&time_bucket) {
+                rank += 1;
+                reasons.push(format!("{} #{} ({})", tier, rank, time_bucket));
+                last_bucket = Some(time_bucket);
+            }
+        }
+    }
+    for (rank, reasons) in reasons.iter_mut().rev().take(policy.keep_last).enumerate() {
+        reasons.push(format!("keep_last #{}", rank + 1));
+    }
+    if let Some(x) = policy.keep_within {
+        let cutoff = now.saturating_sub(x);
+        for (_, reasons) in times
+            .iter()
+            .zip(reasons.iter_mut())
+            .filter(|(y, _)| **y > cutoff)
+        {
+            reasons.push("keep_within".to_string());
+        }
+    }
+    if let Some(x) = policy.max_age {
+        let cutoff = now.saturating_sub(x);
+        for (_, reasons) in times
+            .iter()
+            .zip(reasons.iter_mut())
+            .filter(|(y, _)| **y < cutoff)
+        {
+            reasons.clear();
+        }
+    }
+    if let Some(x) = policy.immutable_for {
+        let cutoff = now.saturating_sub(x);
+        for (_, reasons) in times
+            .iter()
+            .zip(reasons.iter_mut())
+            .filter(|(y, _)| **y > cutoff)
+        {
+            reasons.push("immutable_for".to_string());
+        }
+    }
+    if policy.space_budget_bytes.is_some() && policy.has_no_limits() {
+        for reasons in reasons.iter_mut() {
+            reasons.push("space_budget".to_string());
+        }
+    }
+
+    reasons
+}
+
+/// Keeps the newest `min_keep` snapshots whatever `keep` says, returning how many weren't kept
+/// already. `keep` must be in the order of snapshots sorted oldest first.
+pub fn enforce_min_keep(keep: &mut [bool], min_keep: usize) -> usize {
+    let mut saved = 0;
+
+    for keep in keep.iter_mut().rev().take(min_keep) {
+        if !*keep {
+            *keep = true;
+            saved += 1;
+        }
+    }
+
+    saved
+}
+
+/// Returns the ISO-8601 week year and number of `time`, with weeks starting on `week_start`
+/// instead of Monday if set to Sunday.
+fn week_bucket(time: &Zoned, week_start: Weekday) -> (i16, i8) {
+    let date = match week_start {
+        // A week starting on Sunday is the ISO week starting the following day, shifted back.
+        Weekday::Sunday => time.date().tomorrow().unwrap_or(time.date()),
+        _ => time.date(),
+    };
+    let week_date = date.iso_week_date();
+
+    (week_date.year(), week_date.week())
+}
+
+/// Marks the newest snapshot in each of the `limit` most recent buckets to be kept, returning how
+/// many were marked. `times` must be sorted oldest first.
+fn keep_newest_per_bucket<K: PartialEq>(
+    times: &[Zoned],
+    keep: &mut [bool],
+    limit: usize,
+    bucket: impl Fn(&Zoned) -> K,
+) -> usize {
+    let mut last_bucket = None;
+    let mut kept = 0;
+
+    for (time, keep) in times.iter().zip(keep.iter_mut()).rev() {
+        if kept >= limit {
+            break;
+        }
+
+        let time_bucket = bucket(time);
+        if last_bucket.as_ref() != Some(&time_bucket) {
+            *keep = true;
+            kept += 1;
+            last_bucket = Some(time_bucket);
+        }
+    }
+
+    kept
+}
+
+/// Marks the `count` newest snapshots to be kept, returning how many were marked. `keep` must be
+/// in the order of snapshots sorted oldest first.
+fn keep_newest(keep: &mut [bool], count: usize) -> usize {
+    let mut kept = 0;
+
+    for keep in keep.iter_mut().rev().take(count) {
+        *keep = true;
+        kept += 1;
+    }
+
+    kept
+}
+
+/// Marks every snapshot taken after `time` to be kept, returning how many were marked.
+fn keep_newer_than(times: &[Zoned], keep: &mut [bool], time: &Zoned) -> usize {
+    let mut kept = 0;
+
+    for (_, keep) in times.iter().zip(keep.iter_mut()).filter(|(x, _)| *x > time) {
+        *keep = true;
+        kept += 1;
+    }
+
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jiff::tz::TimeZone;
+
+    /// A snapshot time every `step` from `start` for `count` snapshots, in UTC.
+    fn times(start: civil::DateTime, step: Span, count: usize) -> Vec<Zoned> {
+        let mut time = start
+            .to_zoned(TimeZone::UTC)
+            .expect("Test start time should be valid.");
+        let mut times = Vec::new();
+
+        for _ in 0..count {
+            times.push(time.clone());
+            time = time.saturating_add(step);
+        }
+
+        times
+    }
+
+    /// The times of the snapshots `policy` keeps as of the newest, newest first.
+    fn kept(policy: &RetentionPolicy, times: &[Zoned]) -> Vec<String> {
+        let now = times.last().expect("Test should have snapshots.");
+        let plan = plan(policy, times, now);
+
+        times
+            .iter()
+            .zip(plan.keep)
+            .rev()
+            .filter(|(_, keep)| *keep)
+            .map(|(x, _)| x.strftime("%F %H:%M").to_string())
+            .collect()
+    }
+
+    fn policy() -> RetentionPolicy {
+        RetentionPolicy {
+            hourly_limit: 0,
+            ..RetentionPolicy::default()
+        }
+    }
+
+    #[test]
+    fn keeps_newest_per_day() {
+        let times = times(civil::date(2026, 3, 1).at(0, 0, 0, 0), 1.hour(), 24 * 10);
+        let policy = RetentionPolicy {
+            daily_limit: 3,
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(&policy, &times),
+            ["2026-03-10 23:00", "2026-03-09 23:00", "2026-03-08 23:00"]
+        );
+    }
+
+    #[test]
+    fn tiers_combine() {
+        let times = times(civil::date(2026, 3, 1).at(0, 0, 0, 0), 1.hour(), 24 * 10);
+        let policy = RetentionPolicy {
+            hourly_limit: 3,
+            daily_limit: 2,
+            ..policy()
+        };
+
+        // The newest hour is also the newest of its day, so it is only kept once.
+        assert_eq!(
+            kept(&policy, &times),
+            [
+                "2026-03-10 23:00",
+                "2026-03-10 22:00",
+                "2026-03-10 21:00",
+                "2026-03-09 23:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn weeks_are_iso_weeks() {
+        // Thursday 2026-01-01 is in ISO week 1 of 2026, which started on Monday 2025-12-29.
+        let times = times(civil::date(2025, 12, 20).at(12, 0, 0, 0), 1.day(), 20);
+        let mut policy = RetentionPolicy {
+            weekly_limit: 3,
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(&policy, &times),
+            ["2026-01-08 12:00", "2026-01-04 12:00", "2025-12-28 12:00"]
+        );
+
+        policy.week_start = Weekday::Sunday;
+        assert_eq!(
+            kept(&policy, &times),
+            ["2026-01-08 12:00", "2026-01-03 12:00", "2025-12-27 12:00"]
+        );
+    }
+
+    #[test]
+    fn months_are_calendar_months() {
+        let times = times(
+            civil::date(2026, 1, 1).at(6, 0, 0, 0),
+            1.day(),
+            31 + 28 + 10,
+        );
+        let policy = RetentionPolicy {
+            monthly_limit: 3,
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(&policy, &times),
+            ["2026-03-10 06:00", "2026-02-28 06:00", "2026-01-31 06:00"]
+        );
+    }
+
+    #[test]
+    fn empty_buckets_dont_count() {
+        let mut times = times(civil::date(2026, 3, 1).at(12, 0, 0, 0), 1.day(), 3);
+        times.extend(self::times(
+            civil::date(2026, 3, 20).at(12, 0, 0, 0),
+            1.day(),
+            2,
+        ));
+        let policy = RetentionPolicy {
+            daily_limit: 4,
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(&policy, &times),
+            [
+                "2026-03-21 12:00",
+                "2026-03-20 12:00",
+                "2026-03-03 12:00",
+                "2026-03-02 12:00"
+            ]
+        );
+    }
+
+    #[test]
+    fn space_budget_alone_keeps_everything() {
+        let times = times(civil::date(2026, 3, 1).at(0, 0, 0, 0), 1.day(), 5);
+        let policy = RetentionPolicy {
+            space_budget_bytes: Some(1),
+            ..policy()
+        };
+
+        assert_eq!(kept(&policy, &times).len(), 5);
+    }
+
+    #[test]
+    fn min_keep_overrides_retention() {
+        let times = times(civil::date(2026, 3, 1).at(0, 0, 0, 0), 1.day(), 5);
+        let policy = RetentionPolicy {
+            keep_within: Some(1.second()),
+            ..policy()
+        };
+        let mut plan = plan(&policy, &times, &times[4]);
+
+        assert_eq!(plan.keep, [false, false, false, false, true]);
+        assert_eq!(enforce_min_keep(&mut plan.keep, 2), 1);
+        assert_eq!(plan.keep, [false, false, false, true, true]);
+    }
+
+    #[test]
+    fn explanations_match_the_plan() {
+        let times = times(civil::date(2026, 1, 1).at(0, 0, 0, 0), 7.hours(), 400);
+        let policy = RetentionPolicy {
+            hourly_limit: 5,
+            daily_limit: 7,
+            weekly_limit: 4,
+            monthly_limit: 3,
+            keep_last: 2,
+            keep_within: Some(2.days()),
+            max_age: Some(70.days()),
+            ..policy()
+        };
+        let now = &times[times.len() - 1];
+
+        let reasons = explain(&policy, &times, now);
+        let plan = plan(&policy, &times, now);
+
+        for ((time, keep), reasons) in times.iter().zip(plan.keep).zip(reasons.iter()) {
+            assert_eq!(keep, !reasons.is_empty(), "{}", time);
+        }
+        assert_eq!(
+            reasons.last().map(Vec::as_slice),
+            Some(
+                [
+                    "hourly #1 (2026-04-27 09h)",
+                    "daily #1 (2026-04-27)",
+                    "weekly #1 (2026-W18)",
+                    "monthly #1 (2026-04)",
+                    "keep_last #1",
+                    "keep_within"
+                ]
+                .map(String::from)
+                .as_slice()
+            )
+        );
+    }
+
+    #[test]
+    fn custom_tiers_bucket_by_calendar_periods() {
+        let quarterly = RetentionPolicy {
+            tiers: vec![RetentionTier {
+                name: "3mo".to_string(),
+                period: TierPeriod::Months(3),
+                limit: 3,
+            }],
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(
+                &quarterly,
+                &times(civil::date(2025, 1, 1).at(0, 0, 0, 0), 1.day(), 500)
+            ),
+            ["2026-05-15 00:00", "2026-03-31 00:00", "2025-12-31 00:00"]
+        );
+
+        let six_hourly = RetentionPolicy {
+            tiers: vec![RetentionTier {
+                name: "6h".to_string(),
+                period: TierPeriod::Seconds(6 * 3600),
+                limit: 4,
+            }],
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(
+                &six_hourly,
+                &times(civil::date(2026, 3, 1).at(0, 30, 0, 0), 1.hour(), 20)
+            ),
+            [
+                "2026-03-01 19:30",
+                "2026-03-01 17:30",
+                "2026-03-01 11:30",
+                "2026-03-01 05:30"
+            ]
+        );
+    }
+
+    #[test]
+    fn tier_periods_must_not_mix_units() {
+        assert_eq!(TierPeriod::from_span(1.week()), Ok(TierPeriod::Days(7)));
+        assert_eq!(
+            TierPeriod::from_span(1.hour().minutes(30)),
+            Ok(TierPeriod::Seconds(5400))
+        );
+        assert!(TierPeriod::from_span(1.month().days(2)).is_err());
+        assert!(TierPeriod::from_span(1.day().hours(12)).is_err());
+    }
+
+    #[test]
+    fn max_age_overrides_limits() {
+        let times = times(civil::date(2026, 3, 1).at(0, 0, 0, 0), 1.day(), 10);
+        let mut policy = RetentionPolicy {
+            daily_limit: 10,
+            max_age: Some(3.days()),
+            ..policy()
+        };
+
+        assert_eq!(
+            kept(&policy, &times),
+            [
+                "2026-03-10 00:00",
+                "2026-03-09 00:00",
+                "2026-03-08 00:00",
+                "2026-03-07 00:00"
+            ]
+        );
+
+        policy.immutable_for = Some(5.days());
+        assert_eq!(kept(&policy, &times).len(), 5);
+    }
+
+    /// A xorshift generator, so the randomised tests below fail the same way every run.
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n.max(1)
+        }
+    }
+
+    /// Snapshot times at irregular intervals of up to a few days, with gaps of up to a month.
+    fn random_times(random: &mut Random) -> Vec<Zoned> {
+        let mut time = civil::date(2025, 1, 1)
+            .at(0, 0, 0, 0)
+            .to_zoned(TimeZone::UTC)
+            .expect("Test start time should be valid.");
+        let mut times = Vec::new();
+
+        for _ in 0..random.below(300) + 1 {
+            let step = match random.below(10) {
+                0 => random.below(30 * 86400),
+                _ => random.below(3 * 86400),
+            };
+            time = time.saturating_add((step as i64 + 1).seconds());
+            times.push(time.clone());
+        }
+
+        times
+    }
+
+    fn random_policy(random: &mut Random) -> RetentionPolicy {
+        let mut limit = || random.below(3).saturating_sub(1) as usize * random.below(12) as usize;
+
+        RetentionPolicy {
+            frequent_limit: limit(),
+            hourly_limit: limit(),
+            daily_limit: limit(),
+            weekly_limit: limit(),
+            monthly_limit: limit(),
+            yearly_limit: limit(),
+            keep_last: limit(),
+            tiers: vec![RetentionTier {
+                name: "6h".to_string(),
+                period: TierPeriod::Seconds(6 * 3600),
+                limit: limit(),
+            }],
+            ..RetentionPolicy::default()
+        }
+    }
+
+    #[test]
+    fn never_deletes_the_newest_snapshot() {
+        let mut random = Random(0x5eed);
+
+        for _ in 0..500 {
+            let times = random_times(&mut random);
+            let policy = random_policy(&mut random);
+            let now = &times[times.len() - 1];
+
+            if !policy.has_no_limits() {
+                assert_eq!(plan(&policy, &times, now).keep.last(), Some(&true));
+            }
+        }
+    }
+
+    #[test]
+    fn never_keeps_more_than_the_limits() {
+        let mut random = Random(0xace);
+
+        for _ in 0..500 {
+            let times = random_times(&mut random);
+            let policy = random_policy(&mut random);
+            let plan = plan(&policy, &times, &times[times.len() - 1]);
+            let limits = [
+                policy.frequent_limit,
+                policy.hourly_limit,
+                policy.daily_limit,
+                policy.weekly_limit,
+                policy.monthly_limit,
+                policy.yearly_limit,
+                policy.tiers[0].limit,
+                policy.keep_last,
+            ];
+
+            for ((rule, kept), limit) in plan.kept_by.iter().zip(limits) {
+                assert!(*kept <= limit, "{} kept {} over {}", rule, kept, limit);
+            }
+            assert!(plan.keep.iter().filter(|x| **x).count() <= limits.iter().sum());
+        }
+    }
+
+    #[test]
+    fn pruning_again_deletes_nothing_more() {
+        let mut random = Random(0xb7f5);
+
+        for _ in 0..500 {
+            let times = random_times(&mut random);
+            let policy = random_policy(&mut random);
+            let now = &times[times.len() - 1];
+            let kept: Vec<Zoned> = times
+                .iter()
+                .zip(plan(&policy, &times, now).keep)
+                .filter(|(_, keep)| *keep)
+                .map(|(x, _)| x.clone())
+                .collect();
+
+            assert!(plan(&policy, &kept, now).keep.iter().all(|x| *x));
+        }
+    }
+}
@@ -0,0 +1,549 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use jiff::{
+    SignedDuration, Span, SpanRelativeTo, ToSpan, Zoned, civil,
+    tz::{AmbiguousOffset, TimeZone},
+};
+use std::str::FromStr;
+
+/// Days to search forward for a match. Eight years covers leap days across skipped leap years.
+const SEARCH_DAYS: i32 = 366 * 8;
+
+/// When snapshots are due, set globally and optionally overridden per subvolume.
+#[derive(Clone)]
+pub struct Timing {
+    pub minutes: i8,
+    pub interval: Span,
+    /// Replaces minutes and interval when set.
+    pub schedule: Option<CalendarSchedule>,
+    pub dst_policy: DstPolicy,
+}
+
+impl Timing {
+    /// Returns the first snapshot time at or after `start_time`. A schedule that has stopped
+    /// matching, such as one for a year now past, falls back to minutes and interval.
+    pub fn first_at_or_after(&self, start_time: &Zoned) -> Zoned {
+        if let Some(schedule) = &self.schedule {
+            match schedule.next_at_or_after(start_time, self.dst_policy) {
+                Some(x) => return x,
+                None => tracing::error!(
+                    "Schedule has no time after {}, falling back to interval.",
+                    start_time
+                ),
+            }
+        }
+
+        // Stepping the wall clock rather than elapsed time keeps snapshots on the same nominal
+        // times either side of a DST change.
+        let time_zone = start_time.time_zone();
+        let mut nominal_time = start_time.date().at(0, self.minutes, 0, 0);
+        loop {
+            if let Some(x) = self.dst_policy.resolve(nominal_time, time_zone)
+                && &x >= start_time
+            {
+                return x;
+            }
+            nominal_time = nominal_time
+                .checked_add(self.interval)
+                .expect("Time should never overflow.");
+        }
+    }
+
+    /// Returns the snapshot time following `snapshot_time`.
+    pub fn next_after(&self, snapshot_time: &Zoned) -> Zoned {
+        let after = snapshot_time
+            .checked_add(1.second())
+            .expect("Time should never be near Zoned limit.");
+
+        if self.schedule.is_some() || divides_days(self.interval) {
+            self.first_at_or_after(&after)
+        } else {
+            // Realigning to midnight would shorten the last interval of each day.
+            snapshot_time
+                .checked_add(self.interval)
+                .expect("Time should never be near Zoned limit.")
+        }
+    }
+}
+
+/// Whether `interval` fits a whole number of times into a day, or is a whole number of days.
+fn divides_days(interval: Span) -> bool {
+    let day = SignedDuration::from_hours(24).as_secs();
+
+    match interval.to_duration(SpanRelativeTo::days_are_24_hours()) {
+        Ok(x) if x.subsec_nanos() == 0 && x.as_secs() > 0 => {
+            day % x.as_secs() == 0 || x.as_secs() % day == 0
+        }
+        _ => false,
+    }
+}
+
+/// What to do with snapshot times the clocks skip or repeat as daylight saving time starts or
+/// ends. A repeated time is only ever snapshotted once.
+#[derive(Clone, Copy, Default)]
+pub enum DstPolicy {
+    /// Skipped times aren't snapshotted, repeated times are snapshotted the first time round.
+    #[default]
+    Skip,
+    /// Skipped times are snapshotted the second before the clocks go forward, repeated times the
+    /// first time round.
+    Early,
+    /// Skipped times are snapshotted as the clocks go forward, repeated times the second time
+    /// round.
+    Late,
+}
+
+impl DstPolicy {
+    /// Returns when the wall clock time `time` is snapshotted in `time_zone`, if at all.
+    fn resolve(self, time: civil::DateTime, time_zone: &TimeZone) -> Option<Zoned> {
+        let ambiguous = time_zone.to_ambiguous_zoned(time);
+
+        match (ambiguous.offset(), self) {
+            (AmbiguousOffset::Unambiguous { .. }, _) => ambiguous.compatible().ok(),
+            (AmbiguousOffset::Gap { .. }, DstPolicy::Skip) => None,
+            (AmbiguousOffset::Gap { after, .. }, _) => {
+                // Read with the offset after the gap, the time falls just before the clocks jump.
+                let before_gap = after.to_timestamp(time).ok()?;
+                let jump = time_zone.following(before_gap).next()?.timestamp();
+                let jump = match self {
+                    DstPolicy::Early => jump.checked_sub(1.second()).ok()?,
+                    _ => jump,
+                };
+
+                Some(jump.to_zoned(time_zone.clone()))
+            }
+            (AmbiguousOffset::Fold { .. }, DstPolicy::Late) => ambiguous.later().ok(),
+            (AmbiguousOffset::Fold { .. }, _) => ambiguous.earlier().ok(),
+        }
+    }
+}
+
+impl FromStr for DstPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "early" => Ok(Self::Early),
+            "late" => Ok(Self::Late),
+            _ => Err(format!(
+                "Invalid DST policy: {} (expected skip, early or late)",
+                s
+            )),
+        }
+    }
+}
+
+/// A systemd OnCalendar style schedule, e.g. `Mon..Fri *-*-* 09..17:00,30:00`.
+#[derive(Clone)]
+pub struct CalendarSchedule {
+    weekdays: Vec<Matcher>,
+    years: Vec<Matcher>,
+    months: Vec<Matcher>,
+    days: Vec<Matcher>,
+    hours: Vec<Matcher>,
+    minutes: Vec<Matcher>,
+    seconds: Vec<Matcher>,
+}
+
+#[derive(Clone)]
+enum Matcher {
+    Any,
+    Range { start: i16, end: i16, step: i16 },
+}
+
+impl Matcher {
+    fn matches(&self, value: i16) -> bool {
+        match self {
+            Matcher::Any => true,
+            Matcher::Range { start, end, step } => {
+                value >= *start && value <= *end && (value - start) % step == 0
+            }
+        }
+    }
+}
+
+fn any_matches(matchers: &[Matcher], value: i16) -> bool {
+    matchers.iter().any(|x| x.matches(value))
+}
+
+impl CalendarSchedule {
+    /// Returns the first time matching the schedule that is at or after `time`.
+    pub fn next_at_or_after(&self, time: &Zoned, dst_policy: DstPolicy) -> Option<Zoned> {
+        let mut date = time.date();
+
+        for _ in 0..SEARCH_DAYS {
+            if any_matches(&self.years, date.year())
+                && any_matches(&self.months, date.month().into())
+                && any_matches(&self.days, date.day().into())
+                && any_matches(&self.weekdays, date.weekday().to_monday_one_offset().into())
+                && let Some(x) = self.next_on_date(date, time, dst_policy)
+            {
+                return Some(x);
+            }
+
+            date = date.checked_add(1.day()).ok()?;
+        }
+
+        None
+    }
+
+    fn next_on_date(
+        &self,
+        date: civil::Date,
+        time: &Zoned,
+        dst_policy: DstPolicy,
+    ) -> Option<Zoned> {
+        for hour in (0..24).filter(|x| any_matches(&self.hours, *x)) {
+            for minute in (0..60).filter(|x| any_matches(&self.minutes, *x)) {
+                for second in (0..60).filter(|x| any_matches(&self.seconds, *x)) {
+                    if let Some(x) = dst_policy.resolve(
+                        date.at(hour as i8, minute as i8, second as i8, 0),
+                        time.time_zone(),
+                    ) && &x >= time
+                    {
+                        return Some(x);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl FromStr for CalendarSchedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expanded = match s.trim() {
+            "minutely" => "*-*-* *:*:00",
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            "monthly" => "*-*-01 00:00:00",
+            "yearly" | "annually" => "*-01-01 00:00:00",
+            x => x,
+        };
+        let mut tokens: Vec<&str> = expanded.split_whitespace().collect();
+        if tokens.is_empty() || tokens.len() > 3 {
+            return Err(format!("Invalid calendar schedule: {}", s));
+        }
+
+        let weekdays = if tokens[0].starts_with(|x: char| x.is_ascii_alphabetic()) {
+            parse_weekdays(tokens.remove(0))?
+        } else {
+            vec![Matcher::Any]
+        };
+
+        let mut date = "*-*-*";
+        let mut time = "00:00:00";
+        for token in tokens {
+            if token.contains(':') {
+                time = token;
+            } else if token.contains('-') {
+                date = token;
+            } else {
+                return Err(format!("Invalid calendar schedule component: {}", token));
+            }
+        }
+
+        let mut date_parts: Vec<&str> = date.split('-').collect();
+        if date_parts.len() == 2 {
+            date_parts.insert(0, "*");
+        }
+        if date_parts.len() != 3 {
+            return Err(format!("Invalid calendar schedule date: {}", date));
+        }
+
+        let mut time_parts: Vec<&str> = time.split(':').collect();
+        if time_parts.len() == 2 {
+            time_parts.push("00");
+        }
+        if time_parts.len() != 3 {
+            return Err(format!("Invalid calendar schedule time: {}", time));
+        }
+
+        Ok(Self {
+            weekdays,
+            years: parse_field(date_parts[0], 1970, 9999)?,
+            months: parse_field(date_parts[1], 1, 12)?,
+            days: parse_field(date_parts[2], 1, 31)?,
+            hours: parse_field(time_parts[0], 0, 23)?,
+            minutes: parse_field(time_parts[1], 0, 59)?,
+            seconds: parse_field(time_parts[2], 0, 59)?,
+        })
+    }
+}
+
+/// Parses a comma separated list of values, `a..b` ranges and `/step` repetitions.
+fn parse_field(field: &str, min: i16, max: i16) -> Result<Vec<Matcher>, String> {
+    let parse_value = |x: &str| match x.parse::<i16>() {
+        Ok(x) if x >= min && x <= max => Ok(x),
+        _ => Err(format!(
+            "Invalid calendar schedule value: {} (expected {}..{})",
+            x, min, max
+        )),
+    };
+    let mut matchers = Vec::new();
+
+    for item in field.split(',') {
+        let (base, step) = match item.split_once('/') {
+            Some((base, step)) => match step.parse::<i16>() {
+                Ok(x) if x > 0 => (base, Some(x)),
+                _ => return Err(format!("Invalid calendar schedule repetition: {}", item)),
+            },
+            None => (item, None),
+        };
+
+        let (start, end) = if base == "*" {
+            if step.is_none() {
+                matchers.push(Matcher::Any);
+                continue;
+            }
+            (min, max)
+        } else if let Some((start, end)) = base.split_once("..") {
+            (parse_value(start)?, parse_value(end)?)
+        } else {
+            let start = parse_value(base)?;
+            (start, if step.is_some() { max } else { start })
+        };
+
+        if start > end {
+            return Err(format!("Invalid calendar schedule range: {}", item));
+        }
+        matchers.push(Matcher::Range {
+            start,
+            end,
+            step: step.unwrap_or(1),
+        });
+    }
+
+    Ok(matchers)
+}
+
+fn parse_weekdays(field: &str) -> Result<Vec<Matcher>, String> {
+    let parse_weekday = |x: &str| {
+        let x = x.to_ascii_lowercase();
+        ["mon", "tue", "wed", "thu", "fri", "sat", "sun"]
+            .iter()
+            .position(|day| x.starts_with(day))
+            .map(|x| x as i16 + 1)
+            .ok_or(format!("Invalid calendar schedule weekday: {}", x))
+    };
+    let mut matchers = Vec::new();
+
+    for item in field.split(',') {
+        let (start, end) = match item.split_once("..") {
+            Some((start, end)) => (parse_weekday(start)?, parse_weekday(end)?),
+            None => {
+                let day = parse_weekday(item)?;
+                (day, day)
+            }
+        };
+
+        if start > end {
+            return Err(format!("Invalid calendar schedule weekday range: {}", item));
+        }
+        matchers.push(Matcher::Range {
+            start,
+            end,
+            step: 1,
+        });
+    }
+
+    Ok(matchers)
+}
+
+/// A daily window such as `02:00-04:00` in which nothing is snapshotted or pruned. Windows
+/// ending before they start run past midnight.
+pub struct BlackoutWindow {
+    start: civil::Time,
+    end: civil::Time,
+}
+
+impl BlackoutWindow {
+    /// Returns whether `time` falls in the window, including its start but not its end.
+    pub fn contains(&self, time: &Zoned) -> bool {
+        let time = time.time();
+
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for BlackoutWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or(format!("Blackout window must look like 02:00-04:00: {}", s))?;
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<civil::Time>()
+                .map_err(|e| format!("Invalid blackout window time: {} | Error: {}", x, e))
+        };
+        let window = Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+
+        if window.start == window.end {
+            return Err(format!("Blackout window is empty: {}", s));
+        }
+
+        Ok(window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-03-08 skips 02:00-03:00 and 2026-11-01 repeats 01:00-02:00 in New York.
+    fn day_times(timing: &Timing, date: civil::Date) -> Vec<Zoned> {
+        let time_zone = TimeZone::get("America/New_York").expect("Test time zone should exist.");
+        let start = date
+            .to_zoned(time_zone.clone())
+            .expect("Test start time should be valid.");
+        let end = date
+            .tomorrow()
+            .and_then(|x| x.to_zoned(time_zone))
+            .expect("Test end time should be valid.");
+        let mut times = Vec::new();
+
+        let mut time = timing.first_at_or_after(&start);
+        while time < end {
+            times.push(time.clone());
+            time = timing.next_after(&time);
+        }
+
+        times
+    }
+
+    fn hourly(minutes: i8, dst_policy: DstPolicy) -> Timing {
+        Timing {
+            minutes,
+            interval: 1.hour(),
+            schedule: None,
+            dst_policy,
+        }
+    }
+
+    fn clock(times: &[Zoned]) -> Vec<String> {
+        times
+            .iter()
+            .map(|x| x.strftime("%H:%M:%S%:z").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn spring_forward() {
+        let date = civil::date(2026, 3, 8);
+
+        for (dst_policy, expected) in [
+            (DstPolicy::Skip, None),
+            (DstPolicy::Early, Some("01:59:59-05:00")),
+            (DstPolicy::Late, Some("03:00:00-04:00")),
+        ] {
+            let times = clock(&day_times(&hourly(30, dst_policy), date));
+
+            assert_eq!(times.len(), 23 + expected.is_some() as usize);
+            assert_eq!(times[1], "01:30:00-05:00");
+            assert_eq!(times.last().map(|x| x.as_str()), Some("23:30:00-04:00"));
+            match expected {
+                Some(x) => {
+                    assert_eq!(times[2], x);
+                    assert_eq!(times[3], "03:30:00-04:00");
+                }
+                None => assert_eq!(times[2], "03:30:00-04:00"),
+            }
+        }
+    }
+
+    #[test]
+    fn fall_back() {
+        let date = civil::date(2026, 11, 1);
+
+        for (dst_policy, expected) in [
+            (DstPolicy::Skip, "01:30:00-04:00"),
+            (DstPolicy::Early, "01:30:00-04:00"),
+            (DstPolicy::Late, "01:30:00-05:00"),
+        ] {
+            let times = clock(&day_times(&hourly(30, dst_policy), date));
+
+            assert_eq!(times.len(), 24);
+            assert_eq!(times[0], "00:30:00-04:00");
+            assert_eq!(times[1], expected);
+            assert_eq!(times[2], "02:30:00-05:00");
+        }
+    }
+
+    #[test]
+    fn schedule_follows_policy() {
+        let schedule = |dst_policy| Timing {
+            schedule: Some("hourly".parse().expect("Test schedule should parse.")),
+            ..hourly(0, dst_policy)
+        };
+
+        // 02:00 falls in the gap, so snapshotting it as the clocks jump coincides with 03:00.
+        assert_eq!(
+            day_times(&schedule(DstPolicy::Skip), civil::date(2026, 3, 8)).len(),
+            23
+        );
+        assert_eq!(
+            day_times(&schedule(DstPolicy::Early), civil::date(2026, 3, 8)).len(),
+            24
+        );
+        assert_eq!(
+            day_times(&schedule(DstPolicy::Late), civil::date(2026, 3, 8)).len(),
+            23
+        );
+        for dst_policy in [DstPolicy::Skip, DstPolicy::Early, DstPolicy::Late] {
+            assert_eq!(
+                day_times(&schedule(dst_policy), civil::date(2026, 11, 1)).len(),
+                24
+            );
+        }
+    }
+
+    #[test]
+    fn schedules_that_never_match_fall_back_to_the_interval() {
+        let schedule: CalendarSchedule = "*-02-30".parse().expect("Test schedule should parse.");
+        let timing = Timing {
+            schedule: Some(schedule.clone()),
+            ..hourly(0, DstPolicy::Skip)
+        };
+
+        assert!(
+            schedule
+                .next_at_or_after(&Zoned::now(), DstPolicy::Early)
+                .is_none()
+        );
+        assert_eq!(day_times(&timing, civil::date(2026, 6, 1)).len(), 24);
+    }
+
+    #[test]
+    fn intervals_stay_on_the_wall_clock() {
+        let timing = Timing {
+            interval: 2.hours(),
+            ..hourly(0, DstPolicy::Skip)
+        };
+
+        // 02:00 is skipped in spring.
+        for (date, count) in [
+            (civil::date(2026, 3, 8), 11),
+            (civil::date(2026, 11, 1), 12),
+        ] {
+            let times = day_times(&timing, date);
+
+            assert!(times.iter().all(|x| x.hour() % 2 == 0 && x.minute() == 0));
+            assert_eq!(times.len(), count);
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use serde::de::{
+    self, DeserializeOwned, Deserializer, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use std::{cell::Cell, fmt};
+
+/// The type of value a config key takes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyType {
+    Bool,
+    Integer,
+    Float,
+    String,
+    /// Arrays, tables and anything else written as TOML.
+    Other,
+}
+
+/// Returns the type of value `T` takes at `key_path`, such as `["subvolume", "0", "path"]`, or
+/// `None` if it names no key. Read from `T`'s own deserializer, so it never drifts from it.
+pub fn key_type<T: DeserializeOwned>(key_path: &[&str]) -> Option<KeyType> {
+    let found = Cell::new(None);
+    // Always fails, as the probe ends deserializing as soon as it reaches the key.
+    let _ = T::deserialize(Probe {
+        key_path,
+        found: &found,
+    });
+
+    found.get()
+}
+
+/// Deserializes only the keys along `key_path`, noting what type is asked for at its end.
+struct Probe<'a> {
+    key_path: &'a [&'a str],
+    found: &'a Cell<Option<KeyType>>,
+}
+
+impl<'a> Probe<'a> {
+    fn next(&self) -> Probe<'a> {
+        Probe {
+            key_path: &self.key_path[1..],
+            found: self.found,
+        }
+    }
+
+    fn leaf<T>(self, key_type: KeyType) -> Result<T, ProbeEnd> {
+        if self.key_path.is_empty() {
+            self.found.set(Some(key_type));
+        }
+
+        Err(ProbeEnd)
+    }
+}
+
+impl<'de> Deserializer<'de> for Probe<'_> {
+    type Error = ProbeEnd;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeEnd> {
+        match self.key_path.first() {
+            None => self.leaf(KeyType::Other),
+            Some(x) if x.parse::<usize>().is_ok() => visitor.visit_seq(ProbeSeq(self.next())),
+            Some(x) => visitor.visit_map(ProbeMap {
+                key: Some(x),
+                value: self.next(),
+            }),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Bool)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Integer)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Float)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::Float)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::String)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::String)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, ProbeEnd> {
+        self.leaf(KeyType::String)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ProbeEnd> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ProbeEnd> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i128 u128 bytes byte_buf unit unit_struct seq tuple tuple_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+/// A table holding only `key`.
+struct ProbeMap<'a> {
+    key: Option<&'a str>,
+    value: Probe<'a>,
+}
+
+impl<'de> MapAccess<'de> for ProbeMap<'_> {
+    type Error = ProbeEnd;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ProbeEnd> {
+        match self.key.take() {
+            Some(x) => seed.deserialize(x.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ProbeEnd> {
+        seed.deserialize(Probe {
+            key_path: self.value.key_path,
+            found: self.value.found,
+        })
+    }
+}
+
+/// An array whose first entry stands in for the indexed one, as every entry has the same type.
+struct ProbeSeq<'a>(Probe<'a>);
+
+impl<'de> SeqAccess<'de> for ProbeSeq<'_> {
+    type Error = ProbeEnd;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, ProbeEnd> {
+        seed.deserialize(Probe {
+            key_path: self.0.key_path,
+            found: self.0.found,
+        })
+        .map(Some)
+    }
+}
+
+/// Ends deserializing once the probe has found its key, or found there is no such key.
+#[derive(Debug)]
+pub struct ProbeEnd;
+
+impl fmt::Display for ProbeEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Probe ended.")
+    }
+}
+
+impl std::error::Error for ProbeEnd {}
+
+impl de::Error for ProbeEnd {
+    fn custom<T: fmt::Display>(_message: T) -> Self {
+        ProbeEnd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Entry {
+        path: PathBuf,
+        readonly: Option<bool>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Root {
+        interval: Option<String>,
+        hourly_limit: Option<usize>,
+        defer_io_pressure: Option<f64>,
+        ssh_options: Option<Vec<String>>,
+        subvolume: Option<Vec<Entry>>,
+        policy: Option<BTreeMap<String, Entry>>,
+    }
+
+    #[test]
+    fn finds_the_type_of_nested_keys() {
+        assert_eq!(key_type::<Root>(&["interval"]), Some(KeyType::String));
+        assert_eq!(key_type::<Root>(&["hourly_limit"]), Some(KeyType::Integer));
+        assert_eq!(
+            key_type::<Root>(&["defer_io_pressure"]),
+            Some(KeyType::Float)
+        );
+        assert_eq!(key_type::<Root>(&["ssh_options"]), Some(KeyType::Other));
+        assert_eq!(
+            key_type::<Root>(&["subvolume", "3", "path"]),
+            Some(KeyType::String)
+        );
+        assert_eq!(
+            key_type::<Root>(&["policy", "archive", "readonly"]),
+            Some(KeyType::Bool)
+        );
+    }
+
+    #[test]
+    fn finds_nothing_for_unknown_keys() {
+        assert_eq!(key_type::<Root>(&["hourly_limt"]), None);
+        assert_eq!(key_type::<Root>(&["subvolume", "0", "name"]), None);
+        assert_eq!(key_type::<Root>(&["interval", "hours"]), None);
+    }
+}
@@ -0,0 +1,506 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{Config, FailurePolicy, Snapshot, read_only};
+use jiff::{Span, ToSpan, Zoned};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// What the daemon remembers between cycles and restarts, shared with the status and resume
+/// commands through the state file. Times are stored as RFC 9557 strings.
+#[derive(Default, Deserialize, Serialize)]
+pub struct State {
+    #[serde(default)]
+    pub subvolumes: BTreeMap<String, SubvolumeState>,
+    /// When each snapshot in the trash was moved there, by its path in the trash.
+    #[serde(default)]
+    pub trashed: BTreeMap<String, String>,
+    /// The snapshots each group's members took together, by group name, oldest first.
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<GroupSnapshot>>,
+    /// What each replication target has received, by target name.
+    #[serde(default)]
+    pub replication: BTreeMap<String, TargetState>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+pub struct SubvolumeState {
+    #[serde(default)]
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_failure: Option<String>,
+    pub last_success: Option<String>,
+    /// Snapshots aren't retried before this time under the backoff policy.
+    pub retry_after: Option<String>,
+    /// Set under the stop policy until cleared with the resume command.
+    #[serde(default)]
+    pub stopped: bool,
+    /// The generation of the newest snapshot's subvolume when it was taken.
+    pub last_generation: Option<u64>,
+    /// The generation each existing snapshot was taken at, by snapshot path, so change
+    /// detection doesn't have to ask btrfs about every snapshot.
+    #[serde(default)]
+    pub generations: BTreeMap<String, u64>,
+    /// Pruned snapshots waiting to be deleted under max_deletions_per_cycle, oldest first.
+    #[serde(default)]
+    pub deletion_queue: Vec<String>,
+    /// The free space of the subvolume's filesystem over the last fill_horizon, oldest first.
+    #[serde(default)]
+    pub usage_samples: Vec<UsageSample>,
+    /// When each writable subvolume a rollback left of this one was made, by its path.
+    #[serde(default)]
+    pub clones: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct UsageSample {
+    pub time: String,
+    pub free_bytes: u64,
+}
+
+/// The snapshots a replication target was checked to have received in full.
+#[derive(Default, Deserialize, Serialize)]
+pub struct TargetState {
+    /// The UUID of the local snapshot each was received from, by subvolume name and then snapshot
+    /// name.
+    #[serde(default)]
+    pub received: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// One cycle's snapshots of a group, all named after the same time.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct GroupSnapshot {
+    pub time: String,
+    /// The path of each member's snapshot, by subvolume name. Members whose snapshot failed or
+    /// was skipped are missing.
+    pub snapshots: BTreeMap<String, String>,
+}
+
+impl State {
+    /// Reads the state file, or an empty state if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        match fs::read_to_string(path) {
+            Ok(x) => toml::from_str(&x).map_err(|e| {
+                format!(
+                    "Error parsing state file: {} | Error: {}",
+                    path.to_string_lossy(),
+                    e
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!(
+                "Error reading state file: {} | Error: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+
+    /// Writes the state file, replacing it in one step so readers never see half of it.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let contents = toml::to_string(self).map_err(|e| e.to_string())?;
+        let temp_path = path.with_extension("toml.tmp");
+
+        if let Some(x) = path.parent() {
+            fs::create_dir_all(x).map_err(|e| e.to_string())?;
+        }
+        fs::write(&temp_path, contents)
+            .and_then(|()| fs::rename(&temp_path, path))
+            .map_err(|e| {
+                format!(
+                    "Error writing state file: {} | Error: {}",
+                    path.to_string_lossy(),
+                    e
+                )
+            })
+    }
+}
+
+/// Returns why snapshots of `subvolume` are held back at `time` by the failure policy, if they
+/// are.
+pub fn held_back(config: &Config, subvolume: &str, time: &Zoned) -> Option<String> {
+    let state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return None;
+        }
+    };
+    let subvolume_state = state.subvolumes.get(subvolume)?;
+
+    if subvolume_state.stopped {
+        return Some(format!(
+            "stopped after {} consecutive failures, run snapshotter resume {} once fixed",
+            subvolume_state.consecutive_failures, subvolume
+        ));
+    }
+    if let Some(x) = &subvolume_state.retry_after
+        && let Ok(retry_after) = x.parse::<Zoned>()
+        && *time < retry_after
+    {
+        return Some(format!("backing off until {}", retry_after));
+    }
+
+    None
+}
+
+/// Drops the state of subvolumes, groups and replication targets no longer in the config, the
+/// generations of deleted snapshots and group sets whose snapshots are all deleted, keeping the
+/// state file from growing over years of operation.
+pub fn compact(config: &Config) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let before = toml::to_string(&state).ok();
+
+    state
+        .subvolumes
+        .retain(|x, _| config.subvolumes.iter().any(|y| y.name == *x));
+    for subvolume_state in state.subvolumes.values_mut() {
+        subvolume_state
+            .generations
+            .retain(|x, _| Path::new(x).exists());
+        subvolume_state.clones.retain(|x, _| Path::new(x).exists());
+    }
+    state.trashed.retain(|x, _| Path::new(x).exists());
+    state
+        .groups
+        .retain(|x, _| config.groups.iter().any(|y| y.name == *x));
+    for sets in state.groups.values_mut() {
+        sets.retain(|x| x.snapshots.values().any(|y| Path::new(y).exists()));
+    }
+    state
+        .replication
+        .retain(|x, _| config.replication.iter().any(|y| y.name == *x));
+
+    if toml::to_string(&state).ok() != before
+        && let Err(e) = state.save(&config.state_path)
+    {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Records that the snapshot now at `trash_path` was moved to the trash at `time`, forgetting
+/// any trashed snapshots since deleted or rescued.
+pub fn record_trashed(config: &Config, trash_path: &Path, time: &Zoned) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+
+    state.trashed.retain(|x, _| Path::new(x).exists());
+    state
+        .trashed
+        .insert(trash_path.to_string_lossy().to_string(), time.to_string());
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Records that a rollback left the writable subvolume `clone` of `subvolume` at `time`, or
+/// forgets it if `time` is `None`.
+pub fn record_clone(config: &Config, subvolume: &str, clone: &Path, time: Option<&Zoned>) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let clones = &mut state
+        .subvolumes
+        .entry(subvolume.to_string())
+        .or_default()
+        .clones;
+
+    let clone = clone.to_string_lossy().to_string();
+    match time {
+        Some(x) => clones.insert(clone, x.to_string()),
+        None => clones.remove(&clone),
+    };
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Records the snapshots of `group` taken together at `time`, by subvolume name, forgetting the
+/// group's earlier sets whose snapshots have all been pruned since.
+pub fn record_group(
+    config: &Config,
+    group: &str,
+    time: &Zoned,
+    snapshots: BTreeMap<String, String>,
+) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let sets = state.groups.entry(group.to_string()).or_default();
+
+    sets.retain(|x| x.snapshots.values().any(|y| Path::new(y).exists()));
+    sets.push(GroupSnapshot {
+        time: time.to_string(),
+        snapshots,
+    });
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Replaces the snapshots of `subvolume` that replication target `target` was checked to have
+/// received, with the UUID of the local snapshot each was received from by snapshot name.
+pub fn record_received(
+    config: &Config,
+    target: &str,
+    subvolume: &str,
+    received: &BTreeMap<String, String>,
+) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let target_state = state.replication.entry(target.to_string()).or_default();
+
+    if target_state.received.get(subvolume) == Some(received) {
+        return;
+    }
+    target_state
+        .received
+        .insert(subvolume.to_string(), received.clone());
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Adds the snapshots of `subvolume` that retention no longer keeps to the back of its deletion
+/// queue, returning the `limit` at the front to delete this cycle. They stay queued until they
+/// are gone, so deletions interrupted by a restart are picked up again. Queued snapshots since
+/// deleted, or kept again after a change to the config, leave the queue.
+pub fn take_deletion_batch<'a>(
+    config: &Config,
+    subvolume: &str,
+    snapshots: &'a [Snapshot],
+    limit: usize,
+) -> Vec<&'a Snapshot> {
+    let doomed: Vec<&Snapshot> = snapshots.iter().filter(|x| !x.keep).collect();
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return doomed.into_iter().take(limit).collect();
+        }
+    };
+    let subvolume_state = state.subvolumes.entry(subvolume.to_string()).or_default();
+
+    let mut queue: Vec<&Snapshot> = subvolume_state
+        .deletion_queue
+        .iter()
+        .filter_map(|x| {
+            doomed
+                .iter()
+                .find(|y| y.snapshot_path == Path::new(x))
+                .copied()
+        })
+        .collect();
+    for snapshot in doomed {
+        if !queue
+            .iter()
+            .any(|x| x.snapshot_path == snapshot.snapshot_path)
+        {
+            queue.push(snapshot);
+        }
+    }
+    if queue.len() > limit {
+        tracing::info!(
+            "Deleting {} of the {} snapshots of {} queued for deletion, as max_deletions_per_cycle is {}.",
+            limit,
+            queue.len(),
+            subvolume,
+            limit
+        );
+    }
+
+    let queued: Vec<String> = queue
+        .iter()
+        .map(|x| x.snapshot_path.to_string_lossy().to_string())
+        .collect();
+    if !read_only() && subvolume_state.deletion_queue != queued {
+        subvolume_state.deletion_queue = queued;
+        if let Err(e) = state.save(&config.state_path) {
+            tracing::error!("{}", e);
+        }
+    }
+    queue.truncate(limit);
+
+    queue
+}
+
+/// Records `free_bytes` free on the filesystem of `subvolume` at `time`, unless the last sample is
+/// less than `spacing_seconds` old, and forgets samples older than `window`.
+pub fn record_usage_sample(
+    config: &Config,
+    subvolume: &str,
+    time: &Zoned,
+    free_bytes: u64,
+    window: Span,
+    spacing_seconds: i64,
+) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let samples = &mut state
+        .subvolumes
+        .entry(subvolume.to_string())
+        .or_default()
+        .usage_samples;
+    let sampled_at = |x: &UsageSample| x.time.parse::<Zoned>().ok();
+
+    if samples
+        .last()
+        .and_then(sampled_at)
+        .is_some_and(|x| time.timestamp().as_second() - x.timestamp().as_second() < spacing_seconds)
+    {
+        return;
+    }
+    let oldest = time.saturating_sub(window);
+    samples.retain(|x| sampled_at(x).is_some_and(|y| y >= oldest));
+    samples.push(UsageSample {
+        time: time.to_string(),
+        free_bytes,
+    });
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
+
+/// Returns the generation recorded for `snapshot_path` of `subvolume`, if any.
+pub fn snapshot_generation(config: &Config, subvolume: &str, snapshot_path: &Path) -> Option<u64> {
+    let state = State::load(&config.state_path).ok()?;
+
+    state
+        .subvolumes
+        .get(subvolume)?
+        .generations
+        .get(snapshot_path.to_string_lossy().as_ref())
+        .copied()
+}
+
+/// Records the outcome of snapshotting `subvolume` at `time`, applying the failure policy to
+/// decide when it is next tried, along with the path and generation of the snapshot taken if
+/// known. Nothing is recorded in read-only mode, where every snapshot is refused.
+pub fn record_snapshot(
+    config: &Config,
+    subvolume: &str,
+    time: &Zoned,
+    result: &Result<(), String>,
+    generation: Option<(&Path, u64)>,
+) {
+    if read_only() {
+        return;
+    }
+    let mut state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return;
+        }
+    };
+    let subvolume_state = state.subvolumes.entry(subvolume.to_string()).or_default();
+
+    match result {
+        Ok(()) => {
+            *subvolume_state = SubvolumeState {
+                last_success: Some(time.to_string()),
+                last_generation: subvolume_state.last_generation,
+                generations: std::mem::take(&mut subvolume_state.generations),
+                deletion_queue: std::mem::take(&mut subvolume_state.deletion_queue),
+                usage_samples: std::mem::take(&mut subvolume_state.usage_samples),
+                ..SubvolumeState::default()
+            };
+            if let Some((path, x)) = generation {
+                subvolume_state.last_generation = Some(x);
+                subvolume_state
+                    .generations
+                    .insert(path.to_string_lossy().to_string(), x);
+            }
+            // Pruned snapshots are forgotten here rather than on every deletion.
+            subvolume_state
+                .generations
+                .retain(|x, _| Path::new(x).exists());
+        }
+        Err(e) => {
+            subvolume_state.consecutive_failures += 1;
+            subvolume_state.last_error = Some(e.clone());
+            subvolume_state.last_failure = Some(time.to_string());
+
+            match config.failure_policy {
+                FailurePolicy::Retry => {}
+                FailurePolicy::Backoff => {
+                    // Doubling from a minute passes any sensible cap well before the shift
+                    // could overflow.
+                    let minutes = 1i64 << (subvolume_state.consecutive_failures - 1).min(30);
+                    let retry_after = time
+                        .saturating_add(minutes.minutes())
+                        .min(time.saturating_add(config.failure_backoff_max));
+
+                    tracing::warn!(
+                        "Snapshots of {} failed {} times in a row, retrying after {}.",
+                        subvolume,
+                        subvolume_state.consecutive_failures,
+                        retry_after
+                    );
+                    subvolume_state.retry_after = Some(retry_after.to_string());
+                }
+                FailurePolicy::Stop => {
+                    if subvolume_state.consecutive_failures >= config.failure_limit {
+                        tracing::error!(
+                            "Snapshots of {} failed {} times in a row, stopping until resumed.",
+                            subvolume,
+                            subvolume_state.consecutive_failures
+                        );
+                        subvolume_state.stopped = true;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Err(e) = state.save(&config.state_path) {
+        tracing::error!("{}", e);
+    }
+}
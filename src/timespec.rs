@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use jiff::{
+    Span, Timestamp, Zoned,
+    civil::{self, Weekday},
+};
+
+/// Resolves a time given on the command line relative to `now`. Accepts
+/// - "now", "today" and "yesterday", the last two optionally followed by a time of day
+/// - a weekday such as "friday 17:00", the most recent one not after now, or "last friday" for
+///   the one before today
+/// - a span followed by "ago", such as "2 weeks ago" or "1d 6h ago"
+/// - a date, date and time, timestamp or zoned time, such as "2026-03-01 12:00"
+///
+/// Times without a zone are read in `now`'s zone.
+pub fn parse_time(expression: &str, now: &Zoned) -> Result<Zoned, String> {
+    let expression = expression.trim();
+    let time_zone = now.time_zone().clone();
+
+    if let Ok(x) = expression.parse::<Zoned>() {
+        return Ok(x);
+    }
+    if let Ok(x) = expression.parse::<Timestamp>() {
+        return Ok(x.to_zoned(time_zone));
+    }
+    if let Ok(x) = expression.parse::<civil::DateTime>() {
+        return x.to_zoned(time_zone).map_err(|e| e.to_string());
+    }
+
+    let lowercase = expression.to_lowercase();
+    if let Some(x) = lowercase.strip_suffix(" ago") {
+        let span: Span = x
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid span in time {}: {}", expression, e))?;
+        return now.checked_sub(span).map_err(|e| e.to_string());
+    }
+
+    let words: Vec<&str> = lowercase.split_whitespace().collect();
+    let (day, time) = match words.as_slice() {
+        ["now"] => return Ok(now.clone()),
+        ["last", day, rest @ ..] => ((true, *day), rest),
+        [day, rest @ ..] => ((false, *day), rest),
+        [] => return Err("Empty time.".to_string()),
+    };
+    let time = match time {
+        [] => civil::Time::midnight(),
+        [x] => x
+            .parse()
+            .map_err(|_| format!("Invalid time of day in time {}.", expression))?,
+        _ => return Err(format!("Unrecognised time: {}", expression)),
+    };
+    let at = |date: civil::Date| {
+        date.to_datetime(time)
+            .to_zoned(now.time_zone().clone())
+            .map_err(|e| e.to_string())
+    };
+
+    match day {
+        (false, "today") => at(now.date()),
+        (false, "yesterday") => at(now.date().yesterday().map_err(|e| e.to_string())?),
+        (last, x) => {
+            let weekday = parse_weekday(x).ok_or(format!("Unrecognised time: {}", expression))?;
+            let on_or_before = |date: civil::Date| {
+                if date.weekday() == weekday {
+                    Ok(date)
+                } else {
+                    date.nth_weekday(-1, weekday).map_err(|e| e.to_string())
+                }
+            };
+
+            if last {
+                return at(on_or_before(
+                    now.date().yesterday().map_err(|e| e.to_string())?,
+                )?);
+            }
+            // Today's weekday only counts once its time has passed.
+            let date = on_or_before(now.date())?;
+            match at(date)? {
+                x if &x > now => at(date.nth_weekday(-1, weekday).map_err(|e| e.to_string())?),
+                x => Ok(x),
+            }
+        }
+    }
+}
+
+fn parse_weekday(name: &str) -> Option<Weekday> {
+    Some(match name {
+        "monday" | "mon" => Weekday::Monday,
+        "tuesday" | "tue" => Weekday::Tuesday,
+        "wednesday" | "wed" => Weekday::Wednesday,
+        "thursday" | "thu" => Weekday::Thursday,
+        "friday" | "fri" => Weekday::Friday,
+        "saturday" | "sat" => Weekday::Saturday,
+        "sunday" | "sun" => Weekday::Sunday,
+        _ => return None,
+    })
+}
@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, Snapshot, SubvolumeConfig, delete_snapshots, is_immutable, missing_delete_result,
+    read_only,
+    state::{self, State},
+    verify_snapshot,
+};
+use jiff::{Zoned, tz::TimeZone};
+use std::{fs, path::Path};
+
+/// The directory in each snapshot path pruned snapshots are moved to under trash_grace.
+const TRASH_DIR: &str = ".trash";
+
+/// Prunes each of `snapshots` once it is confirmed to be a snapshot, moving it into the trash
+/// beside it if trash_grace is set or otherwise deleting them all in one go. Returns whether each
+/// was deleted outright. Snapshots within immutable_for are neither trashed nor deleted.
+pub fn discard_all(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    snapshots: &[&Snapshot],
+) -> Vec<Result<bool, String>> {
+    let verified: Vec<Result<(), String>> = snapshots
+        .iter()
+        .map(|x| verify_snapshot(&*subvolume.backend, &x.snapshot_path))
+        .collect();
+
+    if config.trash_grace.is_some() && !read_only() {
+        return snapshots
+            .iter()
+            .zip(verified)
+            .map(|(snapshot, x)| {
+                x.and_then(|()| {
+                    if is_immutable(config, snapshot) {
+                        Err(format!(
+                            "Not moving {} to the trash as it is within immutable_for.",
+                            snapshot.snapshot_path.to_string_lossy()
+                        ))
+                    } else {
+                        move_to_trash(config, &snapshot.snapshot_path)
+                    }
+                })
+                .map(|()| false)
+            })
+            .collect();
+    }
+    let deletable: Vec<&Snapshot> = snapshots
+        .iter()
+        .zip(verified.iter())
+        .filter(|(_, x)| x.is_ok())
+        .map(|(snapshot, _)| *snapshot)
+        .collect();
+    let mut deleted = delete_snapshots(config, &*subvolume.backend, &deletable).into_iter();
+
+    snapshots
+        .iter()
+        .zip(verified)
+        .map(|(snapshot, x)| {
+            x.and_then(|()| {
+                deleted
+                    .next()
+                    .unwrap_or_else(|| missing_delete_result(&snapshot.snapshot_path))
+            })
+            .map(|()| true)
+        })
+        .collect()
+}
+
+fn move_to_trash(config: &Config, snapshot_path: &Path) -> Result<(), String> {
+    let (Some(parent), Some(name)) = (snapshot_path.parent(), snapshot_path.file_name()) else {
+        return Err(format!(
+            "Snapshot path has no parent: {}",
+            snapshot_path.to_string_lossy()
+        ));
+    };
+    let trash_dir = parent.join(TRASH_DIR);
+    let trash_path = trash_dir.join(name);
+
+    if trash_path.exists() {
+        return Err(format!(
+            "Not moving {} to the trash as {} already exists.",
+            snapshot_path.to_string_lossy(),
+            trash_path.to_string_lossy()
+        ));
+    }
+    fs::create_dir_all(&trash_dir)
+        .and_then(|()| fs::rename(snapshot_path, &trash_path))
+        .map_err(|e| {
+            format!(
+                "Error moving {} to the trash. {}",
+                snapshot_path.to_string_lossy(),
+                e
+            )
+        })?;
+    tracing::info!(
+        "Moved snapshot {} to the trash.",
+        snapshot_path.to_string_lossy()
+    );
+    state::record_trashed(config, &trash_path, &Zoned::now());
+
+    Ok(())
+}
+
+/// Deletes the snapshots in `subvolume`'s trash that have been there for trash_grace, or all of
+/// them if `everything` is set or trash_grace is no longer set, leaving any still within
+/// immutable_for. Returns how many were deleted.
+pub fn empty(config: &Config, subvolume: &SubvolumeConfig, everything: bool) -> usize {
+    let trash_dir = subvolume.snapshot_path.join(TRASH_DIR);
+    let Ok(entries) = fs::read_dir(&trash_dir) else {
+        return 0;
+    };
+    // The names may be of any series, so when each was taken is read from its creation time.
+    let subvolumes = subvolume.backend.list(&trash_dir).unwrap_or_default();
+    let state = match State::load(&config.state_path) {
+        Ok(x) => x,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return 0;
+        }
+    };
+    let now = Zoned::now();
+    let mut doomed = Vec::new();
+
+    for path in entries.flatten().map(|x| x.path()) {
+        let trashed_at = state
+            .trashed
+            .get(path.to_string_lossy().as_ref())
+            .and_then(|x| x.parse::<Zoned>().ok());
+        let expired = match (config.trash_grace, &trashed_at) {
+            (Some(grace), Some(x)) => x.saturating_add(grace) <= now,
+            (Some(_), None) => {
+                // The state file was lost, so the grace period starts over.
+                state::record_trashed(config, &path, &now);
+                false
+            }
+            (None, _) => true,
+        };
+        if !everything && !expired {
+            continue;
+        }
+        let info = subvolumes.iter().find(|x| x.path == path).cloned();
+        // Unknown creation times fall back to when it was trashed, which can only be later.
+        let time = info
+            .as_ref()
+            .and_then(|x| x.created)
+            .map(|x| x.to_zoned(TimeZone::system()))
+            .or(trashed_at)
+            .unwrap_or_else(|| now.clone());
+        let snapshot = Snapshot {
+            snapshot_path: path,
+            time,
+            keep: false,
+            info,
+        };
+        if !is_immutable(config, &snapshot) {
+            doomed.push(snapshot);
+        }
+    }
+
+    let snapshots: Vec<&Snapshot> = doomed.iter().collect();
+    let mut deleted = 0;
+    for (snapshot, result) in
+        doomed
+            .iter()
+            .zip(delete_snapshots(config, &*subvolume.backend, &snapshots))
+    {
+        match result {
+            Ok(()) => deleted += 1,
+            Err(e) => tracing::error!(
+                "Error deleting {} from the trash. {}",
+                snapshot.snapshot_path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+
+    deleted
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, SubvolumeConfig,
+    state::{self, State},
+};
+use jiff::{Unit, Zoned};
+
+/// How many samples of free space are kept across fill_horizon, at most.
+const MAX_SAMPLES: i64 = 100;
+
+/// Records the free space of `subvolume`'s filesystem at `now`, then returns how many bytes must
+/// be freed for its trend to no longer project it filling within fill_horizon. `None` while
+/// there is too little history for a trend or the filesystem isn't filling.
+pub fn bytes_short(
+    config: &Config,
+    subvolume: &SubvolumeConfig,
+    free_bytes: u64,
+    now: &Zoned,
+) -> Option<u64> {
+    let horizon = config.fill_horizon?;
+    let horizon_seconds = horizon.total((Unit::Second, now)).ok()?;
+    state::record_usage_sample(
+        config,
+        &subvolume.name,
+        now,
+        free_bytes,
+        horizon,
+        (horizon_seconds as i64 / MAX_SAMPLES).max(1),
+    );
+
+    let state = State::load(&config.state_path).ok()?;
+    let samples: Vec<(i64, u64)> = state
+        .subvolumes
+        .get(&subvolume.name)?
+        .usage_samples
+        .iter()
+        .filter_map(|x| {
+            Some((
+                x.time.parse::<Zoned>().ok()?.timestamp().as_second(),
+                x.free_bytes,
+            ))
+        })
+        .collect();
+    let rate = fill_rate(&samples)?;
+    let seconds_to_full = free_bytes as f64 / rate;
+
+    if seconds_to_full >= horizon_seconds {
+        return None;
+    }
+    tracing::warn!(
+        "{} is filling by {:.0} bytes an hour and projected to be full in {:.1} days, within fill_horizon {:#}.",
+        subvolume.snapshot_path.to_string_lossy(),
+        rate * 3600.0,
+        seconds_to_full / 86400.0,
+        horizon
+    );
+
+    Some(((rate * horizon_seconds) as u64).saturating_sub(free_bytes))
+}
+
+/// The rate free space shrinks at in bytes a second, by a least squares fit of `samples` of Unix
+/// seconds and free bytes. `None` if it isn't shrinking or the samples cover less than an hour.
+fn fill_rate(samples: &[(i64, u64)]) -> Option<f64> {
+    let first = samples.first()?.0;
+    let last = samples.last()?.0;
+    if samples.len() < 3 || last - first < 3600 {
+        return None;
+    }
+
+    let n = samples.len() as f64;
+    // Relative to the first sample, keeping the sums small enough to stay precise.
+    let mean_time = samples.iter().map(|x| (x.0 - first) as f64).sum::<f64>() / n;
+    let mean_free = samples.iter().map(|x| x.1 as f64).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (time, free) in samples {
+        let time = (time - first) as f64 - mean_time;
+        covariance += time * (*free as f64 - mean_free);
+        variance += time * time;
+    }
+    let rate = -covariance / variance;
+
+    (rate > 0.0).then_some(rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_the_rate_free_space_shrinks_at() {
+        // A GiB an hour, with noise either side.
+        let samples: Vec<(i64, u64)> = (0..24)
+            .map(|x| {
+                let noise = if x % 2 == 0 { 1 << 20 } else { 0 };
+                (x * 3600, (100 << 30) - x as u64 * (1 << 30) + noise)
+            })
+            .collect();
+        let rate = fill_rate(&samples).expect("Free space should be shrinking.");
+
+        assert!((rate * 3600.0 - (1u64 << 30) as f64).abs() < (1u64 << 20) as f64);
+    }
+
+    #[test]
+    fn needs_history_that_is_filling() {
+        assert_eq!(fill_rate(&[(0, 100), (60, 90), (120, 80)]), None);
+
+        let growing: Vec<(i64, u64)> = (0..10).map(|x| (x * 3600, 100 + x as u64)).collect();
+        assert_eq!(fill_rate(&growing), None);
+    }
+}
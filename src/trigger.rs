@@ -0,0 +1,291 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: Copyright 2026 Edward Scroop <edward.scroop@gmail.com>
+
+use crate::{
+    Config, Event, Snapshot, SubvolumeConfig, btrfs_filesystem_uuid, create_snapshot,
+    delete_snapshots, delete_subvolume, events::EventSender, hooks, in_blackout, is_immutable,
+    name_time_zone, read_only, replication, series_snapshots, truncated_now,
+};
+use inotify::{Inotify, WatchMask};
+use jiff::{ToSpan, Zoned};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
+};
+
+/// Appended to a subvolume's name to name its trigger snapshots, keeping them out of the
+/// timeline's retention.
+const TRIGGER_TAG: &str = "trigger";
+
+/// The recent trigger snapshot times, for limiting them to trigger_limit per hour.
+#[derive(Default)]
+pub struct TriggerLimiter {
+    times: Vec<Zoned>,
+}
+
+impl TriggerLimiter {
+    /// Returns whether another trigger snapshot may be taken at `now`, recording it if so.
+    fn allow(&mut self, limit: usize, now: &Zoned) -> bool {
+        let hour_ago = now.saturating_sub(1.hour());
+        self.times.retain(|x| *x > hour_ago);
+
+        if self.times.len() < limit {
+            self.times.push(now.clone());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Watches each of `trigger_paths`, sending a trigger event with the changed path. Directories
+/// are watched for changes to their entries, files for being opened or modified so lock files
+/// count when a package manager takes them.
+pub fn watch_trigger_paths(trigger_paths: &[PathBuf], sender: EventSender) -> io::Result<()> {
+    let mut inotify = Inotify::init()?;
+    let mut watches = Vec::new();
+
+    for path in trigger_paths {
+        let mask = if path.is_dir() {
+            WatchMask::CREATE
+                | WatchMask::DELETE
+                | WatchMask::MODIFY
+                | WatchMask::MOVED_FROM
+                | WatchMask::MOVED_TO
+                | WatchMask::ATTRIB
+        } else {
+            WatchMask::OPEN | WatchMask::MODIFY | WatchMask::ATTRIB
+        };
+
+        match inotify.watches().add(path, mask) {
+            Ok(x) => watches.push((x, path.clone())),
+            Err(e) => tracing::warn!(
+                "Not watching trigger path {}. {}",
+                path.to_string_lossy(),
+                e
+            ),
+        }
+    }
+    if watches.is_empty() {
+        return Ok(());
+    }
+
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(x) => x,
+                Err(e) => {
+                    tracing::error!("Error reading trigger path changes. {}", e);
+                    break;
+                }
+            };
+
+            // Only the first change in each read matters, the rest are part of the same burst.
+            if let Some(event) = events.into_iter().next()
+                && let Some((_, path)) = watches.iter().find(|(x, _)| *x == event.wd)
+            {
+                let path = match event.name {
+                    Some(x) => path.join(x),
+                    None => path.clone(),
+                };
+                if sender.send(Event::Trigger(path)).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Takes a trigger snapshot of the subvolume containing `changed_path`, unless trigger_limit
+/// snapshots were already taken in the past hour.
+pub fn on_trigger(config: &Config, limiter: &mut TriggerLimiter, changed_path: &Path) {
+    let Some(subvolume) = containing_subvolume(config, changed_path) else {
+        tracing::warn!(
+            "Change to {} isn't in a configured subvolume, not taking a trigger snapshot.",
+            changed_path.to_string_lossy()
+        );
+        return;
+    };
+    let now = truncated_now();
+
+    if in_blackout(config, &now) {
+        tracing::info!(
+            "In a blackout window, ignoring change to {}.",
+            changed_path.to_string_lossy()
+        );
+        return;
+    }
+    if !limiter.allow(config.trigger_limit, &now) {
+        tracing::debug!(
+            "Trigger snapshot limit reached, ignoring change to {}.",
+            changed_path.to_string_lossy()
+        );
+        return;
+    }
+    tracing::info!(
+        "Change to {} detected, taking a trigger snapshot of {}.",
+        changed_path.to_string_lossy(),
+        subvolume.name
+    );
+
+    let name = config.name_template.render(
+        &series_name(subvolume),
+        &now.with_time_zone(name_time_zone(config)),
+    );
+    let mut hook_env = hooks::subvolume_env(subvolume);
+    hook_env.push((
+        "SNAPSHOTTER_TRIGGER_PATH",
+        changed_path.to_string_lossy().to_string(),
+    ));
+
+    // btrfs can only snapshot within a filesystem, so snapshots for another one are taken
+    // next to the timeline and then sent over.
+    let separate_filesystem = match on_separate_filesystem(subvolume) {
+        Ok(x) => x,
+        Err(e) => {
+            hooks::report_error(
+                &config.hooks,
+                &hook_env,
+                &format!(
+                    "Error finding the filesystem of {}. {}",
+                    subvolume.trigger_snapshot_path.to_string_lossy(),
+                    e
+                ),
+            );
+            return;
+        }
+    };
+    if !separate_filesystem {
+        // Already reported, and trigger snapshots don't count towards the failure policy.
+        let _ = create_snapshot(
+            config,
+            subvolume,
+            &subvolume.trigger_snapshot_path.join(&name),
+            &now,
+            hook_env,
+        );
+        return;
+    }
+    if !subvolume.readonly {
+        hooks::report_error(
+            &config.hooks,
+            &hook_env,
+            &format!(
+                "Not taking a trigger snapshot of {} as only read-only snapshots can be sent to {}.",
+                subvolume.name,
+                subvolume.trigger_snapshot_path.to_string_lossy()
+            ),
+        );
+        return;
+    }
+
+    let local_path = subvolume.snapshot_path.join(&name);
+    if create_snapshot(config, subvolume, &local_path, &now, hook_env.clone()).is_ok()
+        && let Err(e) = replication::send_local(
+            &*subvolume.backend,
+            &local_path,
+            &subvolume.trigger_snapshot_path,
+        )
+        // Moved rather than deleted, as the copy sent beside the triggers lives on.
+        .and_then(|()| delete_subvolume(&*subvolume.backend, &local_path))
+    {
+        hooks::report_error(
+            &config.hooks,
+            &hook_env,
+            &format!(
+                "Error moving trigger snapshot {} to {}, leaving it in place. {}",
+                local_path.to_string_lossy(),
+                subvolume.trigger_snapshot_path.to_string_lossy(),
+                e
+            ),
+        );
+    }
+}
+
+/// Deletes all but the newest trigger_keep_last trigger snapshots of `subvolume`.
+pub fn prune_trigger_snapshots(config: &Config, subvolume: &SubvolumeConfig) {
+    // Any left next to the timeline after failing to move are counted and pruned with the rest.
+    let mut snapshot_dirs = vec![&subvolume.trigger_snapshot_path];
+    if subvolume.snapshot_path != subvolume.trigger_snapshot_path {
+        snapshot_dirs.push(&subvolume.snapshot_path);
+    }
+    let mut snapshots = Vec::new();
+    for snapshot_dir in snapshot_dirs {
+        match series_snapshots(config, subvolume, snapshot_dir, &series_name(subvolume)) {
+            Ok(x) => snapshots.extend(x),
+            Err(e) => {
+                tracing::error!(
+                    "Error reading trigger snapshots of {} in {}. {}",
+                    subvolume.name,
+                    snapshot_dir.to_string_lossy(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+    snapshots.sort();
+
+    // Those within immutable_for wait out the window whatever trigger_keep_last says.
+    let doomed: Vec<&Snapshot> = snapshots
+        [..snapshots.len().saturating_sub(config.trigger_keep_last)]
+        .iter()
+        .filter(|x| !is_immutable(config, x))
+        .collect();
+    if read_only() {
+        for snapshot in doomed {
+            tracing::info!(
+                "Read-only mode, not deleting snapshot {}.",
+                snapshot.snapshot_path.to_string_lossy()
+            );
+        }
+        return;
+    }
+
+    for (snapshot, result) in
+        doomed
+            .iter()
+            .zip(delete_snapshots(config, &*subvolume.backend, &doomed))
+    {
+        if let Err(e) = result {
+            hooks::report_error(
+                &config.hooks,
+                &hooks::subvolume_env(subvolume),
+                &format!(
+                    "Error deleting trigger snapshot {}. {}",
+                    snapshot.snapshot_path.to_string_lossy(),
+                    e
+                ),
+            );
+        }
+    }
+}
+
+fn series_name(subvolume: &SubvolumeConfig) -> String {
+    format!("{}.{}", subvolume.name, TRIGGER_TAG)
+}
+
+/// Returns whether the trigger snapshot path is on a different btrfs filesystem than the
+/// subvolume.
+fn on_separate_filesystem(subvolume: &SubvolumeConfig) -> Result<bool, String> {
+    if subvolume.trigger_snapshot_path == subvolume.snapshot_path {
+        return Ok(false);
+    }
+
+    Ok(btrfs_filesystem_uuid(&subvolume.btrfs, &subvolume.path)?
+        != btrfs_filesystem_uuid(&subvolume.btrfs, &subvolume.trigger_snapshot_path)?)
+}
+
+/// Returns the subvolume with the longest path containing `path`.
+fn containing_subvolume<'a>(config: &'a Config, path: &Path) -> Option<&'a SubvolumeConfig> {
+    config
+        .subvolumes
+        .iter()
+        .filter(|x| path.starts_with(&x.path))
+        .max_by_key(|x| x.path.components().count())
+}